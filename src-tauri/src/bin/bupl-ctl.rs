@@ -0,0 +1,160 @@
+// Controls a running bupl over its Unix socket, meant for hotkeys and status bars
+use std::process::ExitCode;
+
+const USAGE: &str = "Usage: bupl-ctl <command>
+
+Commands:
+  play                 Resume or start the queue
+  pause                Pause playback
+  toggle               Play or pause
+  next                 Skip to the next song
+  prev                 Go back to the previous song
+  seek <+s|-s|s>       Seek by or to a number of seconds
+  volume <+v|-v|v>     Change or set the volume (0.0 - 1.0)
+  queue [--json]       List the songs in the queue
+  queue add <path>...  Add songs to the end of the queue
+  play-album <name>    Play an album by title or id
+  status [--json]      Print what is playing";
+
+#[cfg(unix)]
+fn main() -> ExitCode {
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    let Some((request, json)) = ctl::parse_args(&args) else {
+        eprintln!("{}", USAGE);
+        return ExitCode::from(2);
+    };
+    match ctl::send(request) {
+        Ok(response) => ctl::print(response, json),
+        Err(e) => {
+            eprintln!("bupl-ctl: {}", e);
+            ExitCode::FAILURE
+        }
+    }
+}
+
+#[cfg(not(unix))]
+fn main() -> ExitCode {
+    eprintln!("bupl-ctl needs Unix domain sockets");
+    ExitCode::FAILURE
+}
+
+#[cfg(unix)]
+mod ctl {
+    use bupl_lib::control::{control_socket_path, ControlRequest, ControlResponse, PlayerStatus};
+    use std::fs;
+    use std::io::{self, BufRead, BufReader, Write};
+    use std::os::unix::net::UnixStream;
+    use std::path::PathBuf;
+    use std::process::ExitCode;
+
+    // Returns the request and whether the output should be JSON
+    pub fn parse_args(args: &[String]) -> Option<(ControlRequest, bool)> {
+        let args: Vec<&str> = args.iter().map(String::as_str).collect();
+        let request = match args.as_slice() {
+            ["play"] => ControlRequest::Play,
+            ["pause"] => ControlRequest::Pause,
+            ["toggle" | "play-pause"] => ControlRequest::PlayPause,
+            ["next"] => ControlRequest::Next,
+            ["prev" | "previous"] => ControlRequest::Prev,
+            ["seek", by] if by.starts_with(['+', '-']) => ControlRequest::Seek {
+                by: by.trim_start_matches('+').parse().ok()?,
+            },
+            ["seek", position] => ControlRequest::SeekTo {
+                position: position.parse().ok()?,
+            },
+            ["volume", by] if by.starts_with(['+', '-']) => ControlRequest::AdjustVolume {
+                by: by.trim_start_matches('+').parse().ok()?,
+            },
+            ["volume", volume] => ControlRequest::Volume {
+                volume: volume.parse().ok()?,
+            },
+            // The daemon runs elsewhere, so paths are made absolute here
+            ["queue", "add", paths @ ..] if !paths.is_empty() => ControlRequest::QueueAdd {
+                paths: paths
+                    .iter()
+                    .map(|path| fs::canonicalize(path).unwrap_or_else(|_| PathBuf::from(path)))
+                    .collect(),
+            },
+            ["queue"] => ControlRequest::Queue,
+            ["queue", "--json"] => return Some((ControlRequest::Queue, true)),
+            ["play-album", name @ ..] if !name.is_empty() => ControlRequest::PlayAlbum {
+                name: name.join(" "),
+            },
+            ["status"] => ControlRequest::Status,
+            ["status", "--json"] => return Some((ControlRequest::Status, true)),
+            _ => return None,
+        };
+        Some((request, false))
+    }
+
+    pub fn send(request: ControlRequest) -> io::Result<ControlResponse> {
+        let path = control_socket_path();
+        let mut stream = UnixStream::connect(&path).map_err(|e| {
+            io::Error::new(
+                e.kind(),
+                format!("bupl is not running ({}: {})", path.display(), e),
+            )
+        })?;
+
+        let mut line = serde_json::to_string(&request)?;
+        line.push('\n');
+        stream.write_all(line.as_bytes())?;
+
+        let mut response = String::new();
+        BufReader::new(stream).read_line(&mut response)?;
+        Ok(serde_json::from_str(&response)?)
+    }
+
+    pub fn print(response: ControlResponse, json: bool) -> ExitCode {
+        if let Some(error) = response.error {
+            eprintln!("bupl-ctl: {}", error.message);
+            return ExitCode::FAILURE;
+        }
+        match (response.status, response.queue) {
+            (Some(status), _) if json => {
+                println!("{}", serde_json::to_string(&status).unwrap_or_default())
+            }
+            (Some(status), _) => println!("{}", status_line(&status)),
+            (_, Some(queue)) if json => {
+                println!("{}", serde_json::to_string(&queue).unwrap_or_default())
+            }
+            (_, Some(queue)) => {
+                for (i, entry) in queue.iter().enumerate() {
+                    let title = entry
+                        .title
+                        .clone()
+                        .unwrap_or(entry.path.display().to_string());
+                    match &entry.artist {
+                        Some(artist) => println!("{:>3}. {} - {}", i + 1, artist, title),
+                        None => println!("{:>3}. {}", i + 1, title),
+                    }
+                }
+            }
+            (None, None) => {}
+        }
+        ExitCode::SUCCESS
+    }
+
+    // `Artist - Title [1:02/3:45]`, or the state when nothing is playing
+    fn status_line(status: &PlayerStatus) -> String {
+        let Some(title) = &status.title else {
+            return status.state.clone();
+        };
+        let time = |secs: u64| format!("{}:{:02}", secs / 60, secs % 60);
+        let song = match &status.artist {
+            Some(artist) => format!("{} - {}", artist, title),
+            None => title.clone(),
+        };
+        let state = match status.state.as_str() {
+            "playing" => String::new(),
+            state => format!(" ({})", state),
+        };
+        format!(
+            "{} [{}/{}]{}",
+            song,
+            time(status.position),
+            time(status.duration),
+            state
+        )
+    }
+}
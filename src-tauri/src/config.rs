@@ -0,0 +1,132 @@
+use dirs::config_dir;
+use lazy_static::lazy_static;
+use serde::{Deserialize, Serialize};
+use std::fs::{self, read_to_string};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use tokio::sync::Mutex;
+
+use crate::error::AppError;
+use crate::mpd::MpdSettings;
+use crate::notifications::NotificationSettings;
+use crate::pattern::PathPattern;
+use crate::remote::RemoteSettings;
+use crate::scrobbler::ScrobbleSettings;
+use crate::shortcuts::ShortcutSettings;
+use crate::subsonic::SubsonicSettings;
+use crate::tray::TraySettings;
+use crate::APP_NAME;
+
+lazy_static! {
+    pub static ref CONFIG: Arc<Mutex<Config>> = Arc::new(Mutex::new(Config::load()));
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Config {
+    pub library_roots: Vec<PathBuf>,
+    pub path_patterns: Vec<String>,
+    // Also store ratings in POPM/FMPS_RATING tags of the files
+    pub write_rating_tags: bool,
+    pub scrobbling: ScrobbleSettings,
+    pub mpd: MpdSettings,
+    pub subsonic: SubsonicSettings,
+    pub remote: RemoteSettings,
+    pub shortcuts: ShortcutSettings,
+    pub notifications: NotificationSettings,
+    pub tray: TraySettings,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            library_roots: dirs::audio_dir().into_iter().collect(),
+            path_patterns: vec![
+                String::from("{artist}/{year} - {album}/{track} - {title}"),
+                String::from("{artist}/{album}/{track} - {title}"),
+                String::from("{artist} - {album}/{track} - {title}"),
+                String::from("{artist} - {title}"),
+            ],
+            write_rating_tags: false,
+            scrobbling: ScrobbleSettings::default(),
+            mpd: MpdSettings::default(),
+            subsonic: SubsonicSettings::default(),
+            remote: RemoteSettings::default(),
+            shortcuts: ShortcutSettings::default(),
+            notifications: NotificationSettings::default(),
+            tray: TraySettings::default(),
+        }
+    }
+}
+
+impl Config {
+    pub fn load() -> Self {
+        let Ok(config_path) = app_config_path() else {
+            return Self::default();
+        };
+        let config_file = format!("{}/config.json", config_path);
+        if !Path::new(&config_file).exists() {
+            return Self::default();
+        }
+        let config = read_to_string(&config_file)
+            .map_err(AppError::from)
+            .and_then(|config| Ok(serde_json::from_str::<Self>(&config)?));
+        match config {
+            Ok(config) => config,
+            // Kept aside so the next save does not overwrite the tokens and passwords in it
+            Err(e) => {
+                let backup = format!("{}.bak", config_file);
+                println!(
+                    "Could not read {}, moving it to {}: {}",
+                    config_file, backup, e
+                );
+                if let Err(e) = fs::rename(&config_file, &backup) {
+                    println!("Could not move {}: {}", config_file, e);
+                }
+                Self::default()
+            }
+        }
+    }
+
+    pub fn save(&self) -> Result<(), AppError> {
+        let config_path = app_config_path()?;
+        fs::create_dir_all(&config_path)?;
+        let config = serde_json::to_string_pretty(self)?;
+        fs::write(format!("{}/config.json", config_path), config)?;
+        Ok(())
+    }
+
+    // Canonical form of `path` if it lies inside one of the library roots
+    pub fn library_path(&self, path: &Path) -> Result<PathBuf, AppError> {
+        let path = path
+            .canonicalize()
+            .map_err(|_| AppError::FileMissing(path.to_owned()))?;
+        let inside = self
+            .library_roots
+            .iter()
+            .filter_map(|root| root.canonicalize().ok())
+            .any(|root| path.starts_with(root));
+
+        if inside {
+            Ok(path)
+        } else {
+            Err(AppError::OutsideLibrary(path))
+        }
+    }
+
+    pub fn path_patterns(&self) -> Vec<PathPattern> {
+        self.path_patterns
+            .iter()
+            .filter_map(|p| PathPattern::parse(p).ok())
+            .collect()
+    }
+}
+
+pub fn app_config_path() -> Result<String, AppError> {
+    let config_path = config_dir().ok_or(AppError::DirectoryMissing("config"))?;
+    Ok(format!(
+        "{}/{}",
+        config_path.display(),
+        Arc::clone(&APP_NAME)
+    ))
+}
@@ -0,0 +1,461 @@
+use serde::{Deserialize, Serialize};
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::Duration;
+
+use crate::config::{Config, CONFIG};
+use crate::error::AppError;
+use crate::get_library_songs;
+use crate::library::{LibraryStore, LIBRARY_SONGS, LIBRARY_STORE};
+use crate::player::Player;
+use crate::search::SEARCH_INDEX;
+use crate::song::{Song, SongError};
+use crate::{get_album_playlist, PLAYER};
+
+// Set when the GUI found a daemon at startup, player requests are then sent to it
+static ATTACHED: AtomicBool = AtomicBool::new(false);
+
+// One JSON object per line in both directions, e.g. `{"command":"seek","by":10}`
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "command", rename_all = "snake_case")]
+pub enum ControlRequest {
+    Play,
+    Pause,
+    PlayPause,
+    Next,
+    Prev,
+    Skip { to: i32 },
+    Seek { by: i32 },
+    SeekTo { position: u64 },
+    Volume { volume: f32 },
+    AdjustVolume { by: f32 },
+    ToggleRepeat,
+    Shuffle,
+    ToggleShuffle,
+    QueueAdd { paths: Vec<PathBuf> },
+    // Replaces the queue and starts from the first song
+    PlayPaths { paths: Vec<PathBuf> },
+    PlayAlbum { name: String },
+    Queue,
+    Status,
+    // Sent by an attached GUI after it saved config.json or library.json, `songs` after a rescan
+    Reload { songs: bool },
+}
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct ControlResponse {
+    pub ok: bool,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub status: Option<PlayerStatus>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub queue: Option<Vec<QueueEntry>>,
+    // Paths left out of the queue because they couldn't be read
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub failed: Option<Vec<PathBuf>>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub error: Option<ControlError>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ControlError {
+    pub code: String,
+    pub message: String,
+    // What `AppError::from_code` needs besides the code
+    #[serde(default)]
+    pub detail: String,
+}
+
+// Positions and durations are in seconds
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+pub struct PlayerStatus {
+    pub state: String,
+    pub title: Option<String>,
+    pub artist: Option<String>,
+    pub album: Option<String>,
+    pub album_artist: Option<String>,
+    pub composer: Option<String>,
+    pub genre: Option<String>,
+    pub year: Option<u32>,
+    pub track: Option<u32>,
+    pub disc: Option<u32>,
+    pub path: Option<PathBuf>,
+    pub cover_path: Option<PathBuf>,
+    pub position: u64,
+    pub duration: u64,
+    pub volume: f32,
+    pub repeat: bool,
+    pub shuffle: bool,
+    pub queue_index: usize,
+    pub queue_length: usize,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct QueueEntry {
+    pub path: PathBuf,
+    pub title: Option<String>,
+    pub artist: Option<String>,
+    pub album: Option<String>,
+    pub duration: u64,
+    pub cover_path: PathBuf,
+}
+
+impl ControlResponse {
+    fn done() -> Self {
+        Self {
+            ok: true,
+            ..Self::default()
+        }
+    }
+}
+
+impl From<AppError> for ControlError {
+    fn from(e: AppError) -> Self {
+        Self {
+            code: e.code().to_string(),
+            detail: e.detail(),
+            message: e.to_string(),
+        }
+    }
+}
+
+// The daemon's error as it was raised there, for codes this side knows
+impl From<ControlError> for AppError {
+    fn from(e: ControlError) -> Self {
+        AppError::from_code(&e.code, e.detail).unwrap_or(AppError::Daemon(e.message))
+    }
+}
+
+pub fn is_attached() -> bool {
+    ATTACHED.load(Ordering::Relaxed)
+}
+
+// Runs the request on the daemon when attached to one, otherwise on this process' player
+pub async fn dispatch(request: ControlRequest) -> Result<ControlResponse, AppError> {
+    #[cfg(unix)]
+    if is_attached() {
+        return send_to_daemon(&request).await;
+    }
+    handle_request(request).await
+}
+
+// Falls back to an idle status if the daemon went away
+pub async fn player_status() -> PlayerStatus {
+    dispatch(ControlRequest::Status)
+        .await
+        .ok()
+        .and_then(|response| response.status)
+        .unwrap_or_default()
+}
+
+pub async fn handle_request(request: ControlRequest) -> Result<ControlResponse, AppError> {
+    match request {
+        ControlRequest::Play => {
+            let player = PLAYER.lock().await;
+            let stopped = player.song_paused() || player.song_finished();
+            drop(player);
+            if stopped {
+                play_or_pause().await?;
+            }
+        }
+        ControlRequest::Pause => {
+            let mut player = PLAYER.lock().await;
+            if !player.song_paused() && !player.song_finished() {
+                player.pause_resume();
+            }
+        }
+        ControlRequest::PlayPause => play_or_pause().await?,
+        ControlRequest::Next => PLAYER.lock().await.skip(1)?,
+        ControlRequest::Prev => PLAYER.lock().await.skip(-1)?,
+        ControlRequest::Skip { to } => PLAYER.lock().await.skip(to)?,
+        ControlRequest::Seek { by } => PLAYER.lock().await.seek_position(by)?,
+        ControlRequest::SeekTo { position } => {
+            PLAYER.lock().await.seek_to(Duration::from_secs(position))?
+        }
+        ControlRequest::Volume { volume } => PLAYER.lock().await.set_volume(volume),
+        ControlRequest::AdjustVolume { by } => PLAYER.lock().await.adjust_volume(by),
+        ControlRequest::ToggleRepeat => {
+            let mut player = PLAYER.lock().await;
+            let repeat = !player.repeat;
+            player.set_repeat(repeat);
+        }
+        ControlRequest::Shuffle => PLAYER.lock().await.shuffle_queue(),
+        ControlRequest::ToggleShuffle => {
+            let mut player = PLAYER.lock().await;
+            let shuffle = !player.is_shuffled;
+            player.set_shuffle(shuffle);
+        }
+        ControlRequest::QueueAdd { paths } => {
+            let (songs, failed) = Player::get_songs_info(paths).await;
+            let response = loaded(!songs.is_empty(), failed)?;
+            PLAYER.lock().await.push(songs);
+            return Ok(response);
+        }
+        ControlRequest::PlayPaths { paths } => {
+            let (songs, failed) = Player::get_songs_info(paths).await;
+            let response = loaded(!songs.is_empty(), failed)?;
+            play_songs(songs).await?;
+            return Ok(response);
+        }
+        ControlRequest::PlayAlbum { name } => {
+            let playlist = get_album_playlist(find_album(&name).await?).await?;
+            play_songs(playlist.song_list).await?;
+        }
+        ControlRequest::Queue => {
+            return Ok(ControlResponse {
+                queue: Some(queue_entries().await),
+                ..ControlResponse::done()
+            })
+        }
+        ControlRequest::Status => {
+            return Ok(ControlResponse {
+                status: Some(local_status().await),
+                ..ControlResponse::done()
+            })
+        }
+        ControlRequest::Reload { songs } => reload(songs).await?,
+    }
+    Ok(ControlResponse::done())
+}
+
+// Cached songs keep their metadata but follow the ratings in the store
+async fn reload(songs: bool) -> Result<(), AppError> {
+    *CONFIG.lock().await = Config::load();
+    let mut store = LIBRARY_STORE.lock().await;
+    *store = LibraryStore::load()?;
+
+    let mut cached = LIBRARY_SONGS.lock().await;
+    if songs {
+        *cached = None;
+    } else if let Some(song_list) = cached.as_mut() {
+        for song in song_list {
+            if let Some(stats) = store.songs.get(&song.get_path()) {
+                song.rating = stats.rating;
+            }
+        }
+    }
+    Ok(())
+}
+
+// Starts the queue over once it has finished
+async fn play_or_pause() -> Result<(), AppError> {
+    let mut player = PLAYER.lock().await;
+    let first_song = player.queue.first().ok_or(AppError::QueueEmpty)?.get_path();
+    if player.song_finished() {
+        player.play(first_song)
+    } else {
+        player.pause_resume();
+        Ok(())
+    }
+}
+
+async fn play_songs(songs: Vec<Song>) -> Result<(), AppError> {
+    let mut player = PLAYER.lock().await;
+    player.empty_queue();
+    player.push(songs);
+    let first_song = player.queue.first().ok_or(AppError::QueueEmpty)?.get_path();
+    player.play(first_song)
+}
+
+// Errors only when nothing could be added, otherwise the unreadable paths are listed
+fn loaded(added: bool, failed: Vec<SongError>) -> Result<ControlResponse, AppError> {
+    for e in &failed {
+        println!("Left out of the queue: {}", e);
+    }
+    if failed.is_empty() {
+        return Ok(ControlResponse::done());
+    }
+    if !added {
+        return Err(failed.into_iter().next().unwrap().into());
+    }
+    Ok(ControlResponse {
+        failed: Some(failed.iter().map(|e| e.path().to_path_buf()).collect()),
+        ..ControlResponse::done()
+    })
+}
+
+async fn local_status() -> PlayerStatus {
+    let player = PLAYER.lock().await;
+    let state = match &player.current_song {
+        None => "stopped",
+        Some(_) if player.song_finished() => "stopped",
+        Some(_) if player.song_paused() => "paused",
+        Some(_) => "playing",
+    };
+    let song = player.current_song.clone().unwrap_or_default();
+    let has_song = player.current_song.is_some();
+
+    PlayerStatus {
+        state: state.to_string(),
+        path: has_song.then(|| song.get_path()),
+        cover_path: has_song.then(|| song.get_cover_path()),
+        position: player.song_position(),
+        duration: song.duration.as_secs(),
+        title: song.title,
+        artist: song.artist,
+        album: song.album,
+        album_artist: song.album_artist,
+        composer: song.composer,
+        genre: song.genre,
+        year: song.year,
+        track: song.track,
+        disc: song.disc,
+        volume: player.volume(),
+        repeat: player.repeat,
+        shuffle: player.is_shuffled,
+        queue_index: player.queue_index(),
+        queue_length: player.queue.len(),
+    }
+}
+
+async fn queue_entries() -> Vec<QueueEntry> {
+    let player = PLAYER.lock().await;
+    player
+        .queue
+        .iter()
+        .map(|song| QueueEntry {
+            path: song.get_path(),
+            title: song.title.clone(),
+            artist: song.artist.clone(),
+            album: song.album.clone(),
+            duration: song.duration.as_secs(),
+            cover_path: song.get_cover_path(),
+        })
+        .collect()
+}
+
+// Takes an album id (`Artist - Album`), an album title, or the best search match
+async fn find_album(name: &str) -> Result<String, AppError> {
+    if LIBRARY_STORE.lock().await.albums.contains(name) {
+        return Ok(name.to_string());
+    }
+
+    let songs = get_library_songs().await?;
+    let by_title = songs.iter().find(|song| {
+        song.album
+            .as_ref()
+            .is_some_and(|album| album.eq_ignore_ascii_case(name))
+    });
+    if let Some(song) = by_title {
+        return Ok(song.album_key());
+    }
+
+    SEARCH_INDEX
+        .lock()
+        .await
+        .search(name, 1)
+        .albums
+        .into_iter()
+        .next()
+        .map(|album| album.id)
+        .ok_or(AppError::PlaylistNotFound(name.to_string()))
+}
+
+// `$XDG_RUNTIME_DIR/bupl.sock`, or a per-user socket in the temp directory
+#[cfg(unix)]
+pub fn control_socket_path() -> PathBuf {
+    match dirs::runtime_dir() {
+        Some(dir) => dir.join("bupl.sock"),
+        None => {
+            let user = std::env::var("USER").unwrap_or_default();
+            std::env::temp_dir().join(format!("bupl-{}.sock", user))
+        }
+    }
+}
+
+#[cfg(unix)]
+pub use self::unix::{attach_to_daemon, remove_control_socket, run_control_server};
+
+#[cfg(unix)]
+mod unix {
+    use std::fs;
+    use std::os::unix::fs::PermissionsExt;
+    use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+    use tokio::net::{UnixListener, UnixStream};
+
+    use super::*;
+
+    pub async fn run_control_server() {
+        if let Err(e) = serve().await {
+            println!("Remote control unavailable: {}", e);
+        }
+    }
+
+    async fn serve() -> Result<(), AppError> {
+        let path = control_socket_path();
+        if path.exists() {
+            // A socket nobody answers on was left behind by a crash
+            if UnixStream::connect(&path).await.is_ok() {
+                return Err(AppError::Io(format!(
+                    "{} is used by another instance",
+                    path.display()
+                )));
+            }
+            fs::remove_file(&path)?;
+        }
+
+        let listener = UnixListener::bind(&path)?;
+        fs::set_permissions(&path, fs::Permissions::from_mode(0o600))?;
+        loop {
+            let (stream, _) = listener.accept().await?;
+            tokio::spawn(handle_connection(stream));
+        }
+    }
+
+    async fn handle_connection(stream: UnixStream) -> Result<(), AppError> {
+        let (reader, mut writer) = stream.into_split();
+        let mut lines = BufReader::new(reader).lines();
+
+        while let Some(line) = lines.next_line().await? {
+            let result = match serde_json::from_str::<ControlRequest>(&line) {
+                Ok(request) => handle_request(request).await,
+                Err(e) => Err(AppError::from(e)),
+            };
+            let response = result.unwrap_or_else(|e| ControlResponse {
+                error: Some(ControlError::from(e)),
+                ..ControlResponse::default()
+            });
+            let mut json = serde_json::to_string(&response)?;
+            json.push('\n');
+            writer.write_all(json.as_bytes()).await?;
+        }
+        Ok(())
+    }
+
+    pub(super) async fn send_to_daemon(
+        request: &ControlRequest,
+    ) -> Result<ControlResponse, AppError> {
+        let stream = UnixStream::connect(control_socket_path())
+            .await
+            .map_err(|e| AppError::Daemon(e.to_string()))?;
+        let (reader, mut writer) = stream.into_split();
+
+        let mut json = serde_json::to_string(request)?;
+        json.push('\n');
+        writer.write_all(json.as_bytes()).await?;
+
+        let line = BufReader::new(reader)
+            .lines()
+            .next_line()
+            .await?
+            .ok_or(AppError::Daemon(String::from("Connection closed")))?;
+        let response: ControlResponse = serde_json::from_str(&line)?;
+        match response.error {
+            Some(error) => Err(AppError::from(error)),
+            None => Ok(response),
+        }
+    }
+
+    // Checks for a running daemon once, the GUI then leaves playback to it
+    pub async fn attach_to_daemon() -> bool {
+        let attached = send_to_daemon(&ControlRequest::Status).await.is_ok();
+        ATTACHED.store(attached, Ordering::Relaxed);
+        attached
+    }
+
+    pub fn remove_control_socket() {
+        let _ = fs::remove_file(control_socket_path());
+    }
+}
+
+#[cfg(unix)]
+use self::unix::send_to_daemon;
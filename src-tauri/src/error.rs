@@ -0,0 +1,176 @@
+use image::ImageError;
+use serde::ser::SerializeStruct;
+use serde::{Serialize, Serializer};
+use std::path::PathBuf;
+use std::{fmt, io};
+
+use crate::song::SongError;
+
+#[derive(Debug)]
+pub enum AppError {
+    QueueEmpty,
+    OutOfRange(usize),
+    PlaylistNotFound(String),
+    FileMissing(PathBuf),
+    OutsideLibrary(PathBuf),
+    DirectoryMissing(&'static str),
+    Metadata(String),
+    Decode(String),
+    Seek(String),
+    Cover(String),
+    Network(String),
+    InvalidPattern(String),
+    InvalidQuery(String),
+    UnsupportedFormat(String),
+    Daemon(String),
+    Io(String),
+}
+
+impl AppError {
+    pub fn code(&self) -> &'static str {
+        match self {
+            AppError::QueueEmpty => "queue_empty",
+            AppError::OutOfRange(_) => "out_of_range",
+            AppError::PlaylistNotFound(_) => "playlist_not_found",
+            AppError::FileMissing(_) => "file_missing",
+            AppError::OutsideLibrary(_) => "outside_library",
+            AppError::DirectoryMissing(_) => "directory_missing",
+            AppError::Metadata(_) => "metadata",
+            AppError::Decode(_) => "decode",
+            AppError::Seek(_) => "seek",
+            AppError::Cover(_) => "cover",
+            AppError::Network(_) => "network",
+            AppError::InvalidPattern(_) => "invalid_pattern",
+            AppError::InvalidQuery(_) => "invalid_query",
+            AppError::UnsupportedFormat(_) => "unsupported_format",
+            AppError::Daemon(_) => "daemon",
+            AppError::Io(_) => "io",
+        }
+    }
+
+    // The value the variant holds, sent along with the code so another process can rebuild it
+    pub fn detail(&self) -> String {
+        match self {
+            AppError::QueueEmpty => String::new(),
+            AppError::OutOfRange(index) => index.to_string(),
+            AppError::FileMissing(path) | AppError::OutsideLibrary(path) => {
+                path.display().to_string()
+            }
+            AppError::DirectoryMissing(dir) => dir.to_string(),
+            AppError::PlaylistNotFound(detail)
+            | AppError::Metadata(detail)
+            | AppError::Decode(detail)
+            | AppError::Seek(detail)
+            | AppError::Cover(detail)
+            | AppError::Network(detail)
+            | AppError::InvalidPattern(detail)
+            | AppError::InvalidQuery(detail)
+            | AppError::UnsupportedFormat(detail)
+            | AppError::Daemon(detail)
+            | AppError::Io(detail) => detail.clone(),
+        }
+    }
+
+    // The reverse of `code` and `detail`, None for codes this version does not know
+    pub fn from_code(code: &str, detail: String) -> Option<Self> {
+        Some(match code {
+            "queue_empty" => AppError::QueueEmpty,
+            "out_of_range" => AppError::OutOfRange(detail.parse().ok()?),
+            "playlist_not_found" => AppError::PlaylistNotFound(detail),
+            "file_missing" => AppError::FileMissing(PathBuf::from(detail)),
+            "outside_library" => AppError::OutsideLibrary(PathBuf::from(detail)),
+            "directory_missing" => AppError::DirectoryMissing(match detail.as_str() {
+                "audio" => "audio",
+                "cache" => "cache",
+                "config" => "config",
+                _ => return None,
+            }),
+            "metadata" => AppError::Metadata(detail),
+            "decode" => AppError::Decode(detail),
+            "seek" => AppError::Seek(detail),
+            "cover" => AppError::Cover(detail),
+            "network" => AppError::Network(detail),
+            "invalid_pattern" => AppError::InvalidPattern(detail),
+            "invalid_query" => AppError::InvalidQuery(detail),
+            "unsupported_format" => AppError::UnsupportedFormat(detail),
+            "daemon" => AppError::Daemon(detail),
+            "io" => AppError::Io(detail),
+            _ => return None,
+        })
+    }
+}
+
+impl fmt::Display for AppError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            AppError::QueueEmpty => write!(f, "Queue is empty"),
+            AppError::OutOfRange(index) => write!(f, "Position {} is out of range", index),
+            AppError::PlaylistNotFound(id) => write!(f, "Playlist not found: {}", id),
+            AppError::FileMissing(path) => write!(f, "File missing: {}", path.display()),
+            AppError::OutsideLibrary(path) => {
+                write!(f, "Not inside a library folder: {}", path.display())
+            }
+            AppError::DirectoryMissing(dir) => write!(f, "Could not find the {} directory", dir),
+            AppError::Metadata(e) => write!(f, "Could not read metadata: {}", e),
+            AppError::Decode(e) => write!(f, "Could not decode audio: {}", e),
+            AppError::Seek(e) => write!(f, "Could not seek: {}", e),
+            AppError::Cover(e) => write!(f, "Could not get cover art: {}", e),
+            AppError::Network(e) => write!(f, "Network error: {}", e),
+            AppError::InvalidPattern(e) => write!(f, "Invalid pattern {}", e),
+            AppError::InvalidQuery(e) => write!(f, "Invalid query: {}", e),
+            AppError::UnsupportedFormat(format) => write!(f, "Unsupported format: {}", format),
+            AppError::Daemon(e) => write!(f, "Daemon error: {}", e),
+            AppError::Io(e) => write!(f, "I/O error: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for AppError {}
+
+impl Serialize for AppError {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let mut state = serializer.serialize_struct("AppError", 2)?;
+        state.serialize_field("code", self.code())?;
+        state.serialize_field("message", &self.to_string())?;
+        state.end()
+    }
+}
+
+impl From<io::Error> for AppError {
+    fn from(e: io::Error) -> Self {
+        AppError::Io(e.to_string())
+    }
+}
+
+impl From<serde_json::Error> for AppError {
+    fn from(e: serde_json::Error) -> Self {
+        AppError::Io(e.to_string())
+    }
+}
+
+impl From<SongError> for AppError {
+    fn from(e: SongError) -> Self {
+        match e {
+            SongError::BadPath(path, _) => AppError::FileMissing(path),
+            e => AppError::Metadata(e.to_string()),
+        }
+    }
+}
+
+impl From<ImageError> for AppError {
+    fn from(e: ImageError) -> Self {
+        AppError::Cover(e.to_string())
+    }
+}
+
+impl From<reqwest::Error> for AppError {
+    fn from(e: reqwest::Error) -> Self {
+        AppError::Network(e.to_string())
+    }
+}
+
+impl From<youtube_dl::Error> for AppError {
+    fn from(e: youtube_dl::Error) -> Self {
+        AppError::Network(e.to_string())
+    }
+}
@@ -0,0 +1,142 @@
+use serde::Serialize;
+use std::cmp::Ordering;
+use std::fs;
+use std::path::{Path, PathBuf};
+use walkdir::WalkDir;
+
+use crate::error::AppError;
+use crate::library::is_audio_file;
+
+const COVER_NAMES: [&str; 4] = ["cover", "folder", "front", "album"];
+const COVER_EXTENSIONS: [&str; 4] = ["jpg", "jpeg", "png", "webp"];
+
+#[derive(Debug, Serialize)]
+pub struct FolderNode {
+    pub name: String,
+    pub path: PathBuf,
+    pub cover_path: Option<PathBuf>,
+    pub folders: Vec<FolderNode>,
+    pub songs: Vec<PathBuf>,
+}
+
+impl FolderNode {
+    // Lists subfolders and songs, expanding subfolders `depth` levels deep
+    pub fn read(path: &Path, depth: usize) -> Result<Self, AppError> {
+        let mut node = Self {
+            name: folder_name(path),
+            path: path.to_owned(),
+            cover_path: folder_cover(path),
+            folders: vec![],
+            songs: vec![],
+        };
+        if depth == 0 {
+            return Ok(node);
+        }
+
+        let mut entries: Vec<PathBuf> = fs::read_dir(path)?
+            .filter_map(|e| e.ok())
+            .map(|e| e.path())
+            .collect();
+        entries.sort_by(|a, b| natural_cmp(&folder_name(a), &folder_name(b)));
+
+        for entry in entries {
+            if entry.is_dir() {
+                // One unreadable subfolder is left out instead of failing the whole tree
+                match Self::read(&entry, depth - 1) {
+                    Ok(folder) => node.folders.push(folder),
+                    Err(e) => println!("Could not read {}: {}", entry.display(), e),
+                }
+            } else if is_audio_file(&entry) {
+                node.songs.push(entry);
+            }
+        }
+        Ok(node)
+    }
+}
+
+// Songs of a folder in natural order, with subfolders after the folder's own songs
+pub fn folder_songs(path: &Path, recursive: bool) -> Vec<PathBuf> {
+    let max_depth = if recursive { usize::MAX } else { 1 };
+
+    WalkDir::new(path)
+        .max_depth(max_depth)
+        .sort_by(|a, b| {
+            let a_dir = a.file_type().is_dir();
+            let b_dir = b.file_type().is_dir();
+            a_dir.cmp(&b_dir).then_with(|| {
+                natural_cmp(
+                    &a.file_name().to_string_lossy(),
+                    &b.file_name().to_string_lossy(),
+                )
+            })
+        })
+        .into_iter()
+        .filter_map(|e| e.ok())
+        .filter(|e| e.file_type().is_file() && is_audio_file(e.path()))
+        .map(|e| e.path().to_owned())
+        .collect()
+}
+
+pub fn folder_cover(path: &Path) -> Option<PathBuf> {
+    let entries = fs::read_dir(path).ok()?;
+    entries
+        .filter_map(|e| e.ok())
+        .map(|e| e.path())
+        .find(|entry| {
+            let stem = entry.file_stem().unwrap_or_default().to_string_lossy();
+            let extension = entry.extension().unwrap_or_default().to_string_lossy();
+            COVER_NAMES.contains(&stem.to_lowercase().as_str())
+                && COVER_EXTENSIONS.contains(&extension.to_lowercase().as_str())
+        })
+}
+
+pub fn folder_name(path: &Path) -> String {
+    path.file_name()
+        .map(|name| name.to_string_lossy().to_string())
+        .unwrap_or_else(|| path.display().to_string())
+}
+
+// Compares numbers by value so "Track 2" sorts before "Track 10"
+pub fn natural_cmp(a: &str, b: &str) -> Ordering {
+    let mut a = a.chars().peekable();
+    let mut b = b.chars().peekable();
+
+    loop {
+        match (a.peek().copied(), b.peek().copied()) {
+            (None, None) => return Ordering::Equal,
+            (None, Some(_)) => return Ordering::Less,
+            (Some(_), None) => return Ordering::Greater,
+            (Some(x), Some(y)) if x.is_ascii_digit() && y.is_ascii_digit() => {
+                let x = take_number(&mut a);
+                let y = take_number(&mut b);
+                let x_trimmed = x.trim_start_matches('0');
+                let y_trimmed = y.trim_start_matches('0');
+                let ordering = x_trimmed
+                    .len()
+                    .cmp(&y_trimmed.len())
+                    .then_with(|| x_trimmed.cmp(y_trimmed))
+                    .then_with(|| x.len().cmp(&y.len()));
+                if ordering != Ordering::Equal {
+                    return ordering;
+                }
+            }
+            (Some(x), Some(y)) => {
+                let ordering = x.to_lowercase().cmp(y.to_lowercase());
+                if ordering != Ordering::Equal {
+                    return ordering;
+                }
+                a.next();
+                b.next();
+            }
+        }
+    }
+}
+
+fn take_number(chars: &mut std::iter::Peekable<std::str::Chars>) -> String {
+    let mut number = String::new();
+    while let Some(c) = chars.peek().copied().filter(|c| c.is_ascii_digit()) {
+        number.push(c);
+        chars.next();
+    }
+    number
+}
@@ -0,0 +1,106 @@
+use std::time::Duration;
+use tokio::io::{AsyncBufReadExt, AsyncReadExt, BufReader};
+use tokio::net::TcpStream;
+
+use crate::error::AppError;
+
+// The request line and headers together, a longer head is refused
+const MAX_HEAD: u64 = 16 * 1024;
+
+// A client that stops sending part way through is dropped instead of holding the connection
+const READ_TIMEOUT: Duration = Duration::from_secs(10);
+
+// What the Subsonic and remote servers need from a request before its body
+pub struct Head {
+    pub method: String,
+    pub target: String,
+    // Names are lowercase
+    pub headers: Vec<(String, String)>,
+}
+
+impl Head {
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(key, _)| key == name)
+            .map(|(_, value)| value.as_str())
+    }
+}
+
+// None for a malformed head or one over MAX_HEAD
+pub async fn read_head(stream: &mut BufReader<TcpStream>) -> Result<Option<Head>, AppError> {
+    tokio::time::timeout(READ_TIMEOUT, read_head_lines(stream))
+        .await
+        .map_err(|_| timed_out())?
+}
+
+pub async fn read_body(
+    stream: &mut BufReader<TcpStream>,
+    length: usize,
+) -> Result<Vec<u8>, AppError> {
+    let mut body = vec![0; length];
+    tokio::time::timeout(READ_TIMEOUT, stream.read_exact(&mut body))
+        .await
+        .map_err(|_| timed_out())??;
+    Ok(body)
+}
+
+// Looks at every byte whatever the first difference, so the time taken gives nothing away
+pub fn same_secret(given: &[u8], expected: &[u8]) -> bool {
+    given.len() == expected.len()
+        && given
+            .iter()
+            .zip(expected)
+            .fold(0, |diff, (a, b)| diff | (a ^ b))
+            == 0
+}
+
+async fn read_head_lines(stream: &mut BufReader<TcpStream>) -> Result<Option<Head>, AppError> {
+    let mut head = (&mut *stream).take(MAX_HEAD);
+    let mut line = String::new();
+    head.read_line(&mut line).await?;
+    let mut parts = line.split_whitespace();
+    let (Some(method), Some(target)) = (parts.next(), parts.next()) else {
+        return Ok(None);
+    };
+    let (method, target) = (method.to_string(), target.to_string());
+
+    let mut headers = vec![];
+    loop {
+        line.clear();
+        // Running out, whether the client closed or MAX_HEAD was reached, before the empty line
+        if head.read_line(&mut line).await? == 0 {
+            return Ok(None);
+        }
+        if line.trim().is_empty() {
+            break;
+        }
+        if let Some((name, value)) = line.split_once(':') {
+            headers.push((name.trim().to_ascii_lowercase(), value.trim().to_string()));
+        }
+    }
+    Ok(Some(Head {
+        method,
+        target,
+        headers,
+    }))
+}
+
+fn timed_out() -> AppError {
+    AppError::Network(String::from("Timed out reading the request"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn compares_secrets_by_every_byte() {
+        assert!(same_secret(b"sesame", b"sesame"));
+        assert!(same_secret(b"", b""));
+        assert!(!same_secret(b"sesame", b"sesamE"));
+        assert!(!same_secret(b"Sesame", b"sesame"));
+        assert!(!same_secret(b"sesam", b"sesame"));
+        assert!(!same_secret(b"sesame ", b"sesame"));
+    }
+}
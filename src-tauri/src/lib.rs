@@ -1,113 +1,137 @@
 // Learn more about Tauri commands at https://tauri.app/v1/guides/features/command
+mod config;
+pub mod control;
+mod error;
+mod folder;
+mod http;
+mod library;
+mod mpd;
+#[cfg(target_os = "linux")]
+mod mpris;
+mod notifications;
+mod pattern;
 mod player;
 mod playlist;
+mod playlist_format;
+mod remote;
+mod scrobble_log;
+mod scrobbler;
+mod search;
+mod shortcuts;
+mod smart_playlist;
 mod song;
+mod stats;
+mod subsonic;
+mod tray;
+mod user_playlist;
 
-use dirs::config_dir;
 use lazy_static::lazy_static;
-use reqwest;
 use std::{
-    fs::{self, read_to_string, OpenOptions},
-    io::Write,
-    path::PathBuf,
+    collections::{BTreeMap, HashSet},
+    fs,
+    path::{Path, PathBuf},
     sync::Arc,
+    time::Duration,
 };
-use tokio::sync::Mutex;
+use tauri::Manager;
+use tokio::sync::broadcast::error::RecvError;
+use tokio::sync::{Mutex, MutexGuard};
 use walkdir::WalkDir;
-use youtube_dl::{SearchOptions, SingleVideo, YoutubeDl, YoutubeDlOutput};
+use youtube_dl::YoutubeDl;
 
-use crate::player::Player;
-use crate::playlist::Playlist;
-use crate::song::Song;
+use crate::config::CONFIG;
+use crate::control::{dispatch, is_attached, player_status, ControlRequest};
+use crate::error::AppError;
+use crate::folder::{folder_cover, folder_name, folder_songs, FolderNode};
+use crate::library::{
+    is_audio_file, LibraryStore, ScanReport, SongStats, LIBRARY_SONGS, LIBRARY_STORE,
+};
+use crate::mpd::{run_mpd_server, MpdSettings};
+use crate::notifications::{run_notifications, NotificationSettings};
+use crate::pattern::{PathFields, PathPattern};
+use crate::player::{PlayRecord, Player, PlayerEvent, PLAYER_EVENTS};
+use crate::playlist::{ArtistSummary, GenreSummary, Playlist, PlaylistSource, TrackIssues};
+use crate::playlist_format::{ImportReport, PlaylistEntry, PlaylistFormat};
+use crate::remote::{new_pairing_token, run_remote_server, RemoteSettings};
+use crate::scrobble_log::{scrobbler_log_path, ScrobbleImport, ScrobbleLogEntry};
+use crate::scrobbler::{
+    flush_scrobbles, run_scrobbler, LastFmAuth, QueuedScrobble, ScrobbleClient, ScrobbleSettings,
+    SCROBBLE_QUEUE,
+};
+use crate::search::{SearchResults, SEARCH_INDEX};
+use crate::shortcuts::{
+    register_shortcuts, shortcut_conflicts, ShortcutConflict, ShortcutSettings,
+};
+use crate::smart_playlist::SmartPlaylist;
+use crate::song::{fetch_video_info, write_rating, Song};
+use crate::stats::{history_csv, year_range, HistoryEntry, ListeningReport};
+use crate::subsonic::{cache_library_ids, run_subsonic_server, SubsonicSettings};
+use crate::tray::{apply_tray_settings, create_tray, handle_window_event, TraySettings};
+use crate::user_playlist::{SongRef, UserPlaylist};
 
 lazy_static! {
-    static ref PLAYER: Arc<Mutex<Player>> = Arc::new(Mutex::new(Player::new()));
+    pub(crate) static ref PLAYER: Arc<Mutex<Player>> = Arc::new(Mutex::new(Player::new()));
     static ref APP_NAME: Arc<String> = Arc::new(String::from("bupl"));
+    static ref SCAN_REPORT: Arc<Mutex<ScanReport>> = Arc::new(Mutex::new(ScanReport::new()));
 }
 
 #[tauri::command]
 async fn player_song_position() -> u64 {
-    let player = PLAYER.lock().await;
-    player.song_position()
+    player_status().await.position
 }
 
 #[tauri::command]
-async fn player_play_or_pause() {
-    let song_finished = player_song_finished().await;
-    let mut player = PLAYER.lock().await;
-
-    let first_song = player.queue.get(0).unwrap().get_path();
-    if song_finished {
-        player.play(first_song);
-    } else {
-        player.pause_resume();
-    }
+async fn player_play_or_pause() -> Result<(), AppError> {
+    dispatch(ControlRequest::PlayPause).await?;
+    Ok(())
 }
 
 #[tauri::command]
-async fn player_skip(to: i32) {
-    let mut player = PLAYER.lock().await;
-    player.skip(to);
+async fn player_skip(to: i32) -> Result<(), AppError> {
+    dispatch(ControlRequest::Skip { to }).await?;
+    Ok(())
 }
 
 #[tauri::command]
-async fn player_seek_position(by: i32) {
-    let player = PLAYER.lock().await;
-    player.seek_position(by);
+async fn player_seek_position(by: i32) -> Result<(), AppError> {
+    dispatch(ControlRequest::Seek { by }).await?;
+    Ok(())
 }
 
 #[tauri::command]
 async fn player_adjust_volume(by: f32) {
-    let mut player = PLAYER.lock().await;
-    player.adjust_volume(by);
+    let _ = dispatch(ControlRequest::AdjustVolume { by }).await;
 }
 
 #[tauri::command]
 async fn player_shuffle_queue() {
-    let mut player = PLAYER.lock().await;
-    player.shuffle_queue();
+    let _ = dispatch(ControlRequest::Shuffle).await;
 }
 
 #[tauri::command]
 async fn player_song_finished() -> bool {
-    let player = PLAYER.lock().await;
-    player.song_finished()
+    player_status().await.state == "stopped"
 }
 
 #[tauri::command]
 async fn player_song_paused() -> bool {
-    let player = PLAYER.lock().await;
-    player.song_paused()
-}
-
-async fn fetch_video_info(title: String, album: String) -> SingleVideo {
-    let search_query = format!("{} {}", title, album);
-    let search = SearchOptions::youtube(&search_query);
-    println!("Searching for: {}", search_query);
-
-    let yt_search = YoutubeDl::search_for(&search).run().unwrap();
-    let video = match yt_search {
-        YoutubeDlOutput::Playlist(playlist) => {
-            if let Some(video) = playlist.entries.unwrap().first() {
-                video.clone()
-            } else {
-                return SingleVideo::default();
-            }
-        }
-        YoutubeDlOutput::SingleVideo(video) => *video,
-    };
-    video
+    player_status().await.state == "paused"
+}
+
+#[tauri::command]
+async fn player_queue_index() -> usize {
+    player_status().await.queue_index
 }
 
-async fn _fetch_audio(title: String, album: String) {
-    let audio_path = dirs::audio_dir()
-        .ok_or("Failed to get audio directory")
-        .unwrap();
-    let audio_download_path = format!("{}/{}", audio_path.display().to_string(), album);
+async fn _fetch_audio(title: String, album: String) -> Result<(), AppError> {
+    let audio_path = dirs::audio_dir().ok_or(AppError::DirectoryMissing("audio"))?;
+    let audio_download_path = format!("{}/{}", audio_path.display(), album);
 
-    let video = fetch_video_info(title, album).await;
+    let video = fetch_video_info(title, album).await?;
 
-    let video_url = video.webpage_url.ok_or("No video URL found").unwrap();
+    let video_url = video
+        .webpage_url
+        .ok_or(AppError::Network(String::from("No video URL found")))?;
 
     let _ = fs::create_dir_all(&audio_download_path);
 
@@ -116,235 +140,1226 @@ async fn _fetch_audio(title: String, album: String) {
         .extract_audio(true)
         .format("m4a")
         .output_directory(&audio_download_path)
-        .run()
-        .unwrap();
+        .run()?;
     println!("Downloaded audio to: {}", audio_download_path);
+    Ok(())
 }
-#[tauri::command]
-async fn fetch_album_cover(title: String, album: String) {
-    println!("Searching for cover art for: {}", album);
-    let cache_path = dirs::cache_dir()
-        .ok_or("Failed to get cache directory")
-        .unwrap();
 
-    let video = fetch_video_info(title, album.clone()).await;
-
-    let thumbnail_url = video.thumbnail.ok_or("No thumbnail found").unwrap();
-    println!("Thumbnail URL: {}", thumbnail_url);
-
-    let extension = if let Some(query_start) = thumbnail_url.find('?') {
-        thumbnail_url[..query_start]
-            .split('.')
-            .last()
-            .unwrap_or("jpg")
-    } else {
-        thumbnail_url.split('.').last().unwrap_or("jpg")
-    };
-
-    let thumbnail_path = format!(
-        "{}/{}/{}.{}",
-        cache_path.display(),
-        Arc::clone(&APP_NAME),
-        &album,
-        extension
-    );
-    let response = reqwest::get(&thumbnail_url).await.unwrap();
-    let mut file = tokio::fs::File::create(&thumbnail_path).await.unwrap();
-    let content = response.bytes().await.unwrap();
-    tokio::io::copy(&mut content.as_ref(), &mut file)
-        .await
-        .unwrap();
+#[tauri::command]
+async fn fetch_album_cover(
+    title: String,
+    album: String,
+    artist: Option<String>,
+) -> Result<(), AppError> {
+    let mut song = Song::default();
+    song.album = Some(album.clone());
+    song.artist = artist;
+    song::fetch_album_cover(title, album, song.cover_key()).await
 }
 
 #[tauri::command]
 async fn player_current_song_info(key: String) -> String {
-    let player = PLAYER.lock().await;
-    let current_song = player.current_song.clone().unwrap_or_default();
+    let status = player_status().await;
+    let number = |n: Option<u32>| n.unwrap_or_default().to_string();
 
     match key.as_str() {
-        "title" => current_song.title.unwrap_or_default(),
-        "album" => current_song.album.unwrap_or_default(),
-        "artist" => current_song.artist.unwrap_or_default(),
-        "genre" => current_song.genre.unwrap_or_default(),
-        "year" => current_song.year.unwrap_or_default().to_string(),
-        "track" => current_song.track.unwrap_or_default().to_string(),
-        "duration" => current_song.duration.as_secs().to_string(),
-        "cover_path" => current_song.get_cover_path().display().to_string(),
+        "title" => status.title.unwrap_or_default(),
+        "album" => status.album.unwrap_or_default(),
+        "album_artist" => status.album_artist.unwrap_or_default(),
+        "artist" => status.artist.unwrap_or_default(),
+        "composer" => status.composer.unwrap_or_default(),
+        "genre" => status.genre.unwrap_or_default(),
+        "year" => number(status.year),
+        "track" => number(status.track),
+        "disc" => number(status.disc),
+        "duration" => status.duration.to_string(),
+        "cover_path" => status.cover_path.unwrap_or_default().display().to_string(),
         _ => String::default(),
     }
 }
 
 #[tauri::command]
-async fn player_cover_path_queue() -> Vec<PathBuf> {
-    let player = PLAYER.lock().await;
-    player
-        .queue
-        .iter()
-        .map(|song| song.get_cover_path())
-        .collect()
+async fn player_cover_path_queue() -> Result<Vec<PathBuf>, AppError> {
+    let queue = dispatch(ControlRequest::Queue).await?.queue;
+    Ok(queue
+        .unwrap_or_default()
+        .into_iter()
+        .map(|entry| entry.cover_path)
+        .collect())
 }
 
 #[tauri::command]
 async fn player_repeat() -> bool {
-    let player = PLAYER.lock().await;
-    player.repeat
+    player_status().await.repeat
 }
 
 #[tauri::command]
 async fn player_toggle_repeat() {
-    let mut player = PLAYER.lock().await;
-    player.repeat = !player.repeat;
+    let _ = dispatch(ControlRequest::ToggleRepeat).await;
 }
 
 #[tauri::command]
-async fn create_playlist_types() {
-    let player = PLAYER.clone();
-    let player = player.lock().await;
-    let songs = get_audio_from_path("dir");
+async fn create_playlist_types() -> Result<ScanReport, AppError> {
+    let songs = get_library_audio().await?;
+    let mut report = ScanReport::new();
+    let mut categories = LibraryStore::default();
+    let mut song_list = vec![];
 
     for song in songs {
-        let info = player.get_song_info(song).await;
-        process_playlist_type("Album ".to_owned() + &info.album.unwrap_or_default());
-        process_playlist_type("Artist ".to_owned() + &info.artist.unwrap_or_default());
-        process_playlist_type("Genre ".to_owned() + &info.genre.unwrap_or_default());
+        report.scanned += 1;
+        let mut info = match Player::get_song_info(song.clone()).await {
+            Ok(info) => info,
+            Err(e) => {
+                report.add_failure(song, &e);
+                continue;
+            }
+        };
+        categories.add_song(&info);
+        info.cover_data = None;
+        song_list.push(info);
+    }
+
+    let mut store = library_store_for_update().await?;
+    store.albums = categories.albums;
+    store.artists = categories.artists;
+    store.genres = categories.genres;
+    store.version = categories.version;
+    for song in &song_list {
+        store.add_stats(song);
+    }
+    store.save()?;
+    drop(store);
+    cache_library_songs(song_list).await;
+    reload_daemon(true).await?;
+
+    *SCAN_REPORT.lock().await = report.clone();
+    Ok(report)
+}
+
+#[tauri::command]
+async fn get_song_stats(path: PathBuf) -> SongStats {
+    let store = LIBRARY_STORE.lock().await;
+    store.songs.get(&path).cloned().unwrap_or_default()
+}
+
+#[tauri::command]
+async fn set_song_rating(path: PathBuf, rating: Option<u8>) -> Result<SongStats, AppError> {
+    if let Some(rating) = rating.filter(|rating| *rating > 5) {
+        return Err(AppError::OutOfRange(rating as usize));
+    }
+    if CONFIG.lock().await.write_rating_tags {
+        write_rating(&path, rating)?;
+    }
+
+    let mut store = library_store_for_update().await?;
+    let stats = store
+        .songs
+        .entry(path.clone())
+        .or_insert_with(|| SongStats::added(&path));
+    stats.rating = rating;
+    let stats = stats.clone();
+    store.save()?;
+    drop(store);
+    reload_daemon(false).await?;
+
+    if let Some(songs) = LIBRARY_SONGS.lock().await.as_mut() {
+        if let Some(song) = songs.iter_mut().find(|song| song.get_path() == path) {
+            song.rating = rating;
+        }
+    }
+    Ok(stats)
+}
+
+// Counts plays and skips as songs end and keeps the history, for as long as the app runs
+async fn record_playback() {
+    let mut events = PLAYER_EVENTS.subscribe();
+    loop {
+        let record = match events.recv().await {
+            Ok(PlayerEvent::TrackEnded(record)) => record,
+            Ok(_) | Err(RecvError::Lagged(_)) => continue,
+            Err(RecvError::Closed) => break,
+        };
+        save_play(&record).await;
+    }
+}
+
+// Plays reported by Subsonic clients are saved the same way as local ones
+async fn save_play(record: &PlayRecord) {
+    let mut store = LIBRARY_STORE.lock().await;
+    store.record_play(record);
+    if let Err(e) = store.save() {
+        println!("Could not save song stats: {}", e);
+    }
+    if record.counts_as_play() {
+        if let Err(e) = HistoryEntry::from_record(record).append() {
+            println!("Could not save play history: {}", e);
+        }
+    }
+    if record.counts_as_scrobble() {
+        let entry = ScrobbleLogEntry::from_record(record);
+        if let Some(Err(e)) = entry.map(|entry| entry.append()) {
+            println!("Could not write scrobbler log: {}", e);
+        }
+    }
+}
+
+// For uploading with existing tools, the file is written as songs finish
+#[tauri::command]
+async fn get_scrobbler_log_path() -> Result<PathBuf, AppError> {
+    scrobbler_log_path()
+}
+
+#[tauri::command]
+async fn get_scrobble_settings() -> ScrobbleSettings {
+    CONFIG.lock().await.scrobbling.clone()
+}
+
+#[tauri::command]
+async fn set_scrobble_settings(settings: ScrobbleSettings) -> Result<(), AppError> {
+    let mut config = CONFIG.lock().await;
+    config.scrobbling = settings;
+    config.save()?;
+    drop(config);
+    reload_daemon(false).await
+}
+
+#[tauri::command]
+async fn get_mpd_settings() -> MpdSettings {
+    CONFIG.lock().await.mpd.clone()
+}
+
+// Takes effect the next time the player starts
+#[tauri::command]
+async fn set_mpd_settings(settings: MpdSettings) -> Result<(), AppError> {
+    let mut config = CONFIG.lock().await;
+    config.mpd = settings;
+    config.save()?;
+    drop(config);
+    reload_daemon(false).await
+}
+
+#[tauri::command]
+async fn get_subsonic_settings() -> SubsonicSettings {
+    CONFIG.lock().await.subsonic.clone()
+}
+
+// Takes effect the next time the player starts
+#[tauri::command]
+async fn set_subsonic_settings(settings: SubsonicSettings) -> Result<(), AppError> {
+    let mut config = CONFIG.lock().await;
+    config.subsonic = settings;
+    config.save()?;
+    drop(config);
+    reload_daemon(false).await
+}
+
+#[tauri::command]
+async fn get_notification_settings() -> NotificationSettings {
+    CONFIG.lock().await.notifications.clone()
+}
+
+#[tauri::command]
+async fn set_notification_settings(settings: NotificationSettings) -> Result<(), AppError> {
+    let mut config = CONFIG.lock().await;
+    config.notifications = settings;
+    config.save()?;
+    drop(config);
+    reload_daemon(false).await
+}
+
+#[tauri::command]
+async fn get_tray_settings() -> TraySettings {
+    CONFIG.lock().await.tray.clone()
+}
+
+#[tauri::command]
+async fn set_tray_settings(settings: TraySettings) -> Result<(), AppError> {
+    let mut config = CONFIG.lock().await;
+    config.tray = settings;
+    config.save()?;
+    apply_tray_settings(&config.tray);
+    drop(config);
+    reload_daemon(false).await
+}
+
+#[tauri::command]
+async fn get_shortcut_settings() -> ShortcutSettings {
+    CONFIG.lock().await.shortcuts.clone()
+}
+
+// Registers the new bindings right away and returns the ones that could not be
+#[tauri::command]
+async fn set_shortcut_settings(
+    app: tauri::AppHandle,
+    settings: ShortcutSettings,
+) -> Result<Vec<ShortcutConflict>, AppError> {
+    let mut config = CONFIG.lock().await;
+    config.shortcuts = settings;
+    config.save()?;
+    drop(config);
+    reload_daemon(false).await?;
+    Ok(register_shortcuts(&app).await)
+}
+
+#[tauri::command]
+async fn get_shortcut_conflicts() -> Vec<ShortcutConflict> {
+    shortcut_conflicts().await
+}
+
+#[tauri::command]
+async fn get_remote_settings() -> RemoteSettings {
+    CONFIG.lock().await.remote.clone()
+}
+
+// Takes effect the next time the player starts, except for the token
+#[tauri::command]
+async fn set_remote_settings(settings: RemoteSettings) -> Result<(), AppError> {
+    let mut config = CONFIG.lock().await;
+    config.remote = settings;
+    config.save()?;
+    drop(config);
+    reload_daemon(false).await
+}
+
+// Unpairs every remote, they need the returned token to connect again
+#[tauri::command]
+async fn reset_remote_token() -> Result<String, AppError> {
+    let mut config = CONFIG.lock().await;
+    config.remote.token = new_pairing_token();
+    config.save()?;
+    let token = config.remote.token.clone();
+    drop(config);
+    // Only the daemon serves remotes when attached to one
+    reload_daemon(false).await?;
+    Ok(token)
+}
+
+#[tauri::command]
+async fn get_scrobble_queue() -> Vec<QueuedScrobble> {
+    SCROBBLE_QUEUE.lock().await.pending.clone()
+}
+
+// Retries everything queued right away, returns how many were sent
+#[tauri::command]
+async fn flush_scrobble_queue() -> Result<usize, AppError> {
+    let client = ScrobbleClient::new(CONFIG.lock().await.scrobbling.clone());
+    flush_scrobbles(&client, true).await
+}
+
+#[tauri::command]
+async fn lastfm_begin_auth() -> Result<LastFmAuth, AppError> {
+    let client = ScrobbleClient::new(CONFIG.lock().await.scrobbling.clone());
+    client.lastfm_begin_auth().await
+}
+
+#[tauri::command]
+async fn lastfm_finish_auth(token: String) -> Result<String, AppError> {
+    let client = ScrobbleClient::new(CONFIG.lock().await.scrobbling.clone());
+    let (session_key, username) = client.lastfm_finish_auth(token).await?;
+
+    let mut config = CONFIG.lock().await;
+    config.scrobbling.lastfm.session_key = Some(session_key);
+    config.scrobbling.lastfm.username = Some(username.clone());
+    config.save()?;
+    drop(config);
+    reload_daemon(false).await?;
+    Ok(username)
+}
+
+// Adds the listened entries of a portable player's .scrobbler.log to the history
+#[tauri::command]
+async fn import_scrobbler_log(path: PathBuf) -> Result<ScrobbleImport, AppError> {
+    let log = fs::read(&path)?;
+    let entries = ScrobbleLogEntry::parse_log(&String::from_utf8_lossy(&log));
+    let songs = get_library_songs().await?;
+    let known: HashSet<(u64, String)> = HistoryEntry::load(0, u64::MAX)?
+        .into_iter()
+        .map(|entry| (entry.started, entry.title.unwrap_or_default()))
+        .collect();
+
+    let mut report = ScrobbleImport::default();
+    let mut store = library_store_for_update().await?;
+    for entry in entries {
+        if !entry.listened || known.contains(&(entry.timestamp, entry.title.clone())) {
+            report.skipped += 1;
+            continue;
+        }
+        let song = songs.iter().find(|song| entry.matches(song));
+        let record = PlayRecord {
+            song: song.cloned().unwrap_or_else(|| entry.to_song()),
+            started: entry.timestamp,
+            played: Duration::from_secs(entry.duration),
+        };
+        if song.is_some() {
+            store.record_play(&record);
+            report.matched += 1;
+        }
+        HistoryEntry::from_record(&record).append()?;
+        report.imported += 1;
     }
+    store.save()?;
+    drop(store);
+    reload_daemon(false).await?;
+    Ok(report)
+}
+
+#[tauri::command]
+async fn get_listening_report(
+    from: Option<u64>,
+    to: Option<u64>,
+    limit: Option<usize>,
+) -> Result<ListeningReport, AppError> {
+    let (from, to) = (from.unwrap_or_default(), to.unwrap_or(u64::MAX));
+    let history = HistoryEntry::load(from, to)?;
+    Ok(ListeningReport::new(
+        &history,
+        from,
+        to,
+        limit.unwrap_or(10),
+    ))
+}
+
+#[tauri::command]
+async fn get_year_in_review(year: i32, limit: Option<usize>) -> Result<ListeningReport, AppError> {
+    let (from, to) = year_range(year)?;
+    get_listening_report(Some(from), Some(to), limit).await
+}
+
+// A .json file gets the report, a .csv file every play in the range
+#[tauri::command]
+async fn export_listening_report(
+    path: PathBuf,
+    from: Option<u64>,
+    to: Option<u64>,
+) -> Result<(), AppError> {
+    let (from, to) = (from.unwrap_or_default(), to.unwrap_or(u64::MAX));
+    let history = HistoryEntry::load(from, to)?;
+    let extension = path.extension().unwrap_or_default().to_string_lossy();
+
+    let contents = match extension.to_lowercase().as_str() {
+        "json" => serde_json::to_string_pretty(&ListeningReport::new(&history, from, to, 100))?,
+        "csv" => history_csv(&history),
+        _ => return Err(AppError::UnsupportedFormat(extension.to_string())),
+    };
+    fs::write(&path, contents)?;
+    Ok(())
 }
 
 #[tauri::command]
-async fn play_album_playlist(album: String) {
-    let playlist = get_album_playlist(album).await;
-    let player = PLAYER.clone();
-    let mut player = player.lock().await;
-    player.empty_queue();
-    for song in playlist.song_list {
-        player.add_to_queue(song.get_path()).await;
+async fn get_scan_report() -> ScanReport {
+    SCAN_REPORT.lock().await.clone()
+}
+
+#[tauri::command]
+async fn get_path_patterns() -> Vec<String> {
+    CONFIG.lock().await.path_patterns.clone()
+}
+
+#[tauri::command]
+async fn set_path_patterns(patterns: Vec<String>) -> Result<(), AppError> {
+    for pattern in &patterns {
+        PathPattern::parse(pattern)?;
+    }
+    let mut config = CONFIG.lock().await;
+    config.path_patterns = patterns;
+    config.save()?;
+    drop(config);
+    reload_daemon(false).await
+}
+
+#[tauri::command]
+async fn preview_path_pattern(
+    pattern: String,
+    limit: Option<usize>,
+) -> Result<Vec<(PathBuf, Option<PathFields>)>, AppError> {
+    let pattern = PathPattern::parse(&pattern)?;
+    let songs = get_library_audio().await?;
+
+    Ok(songs
+        .into_iter()
+        .take(limit.unwrap_or(20))
+        .map(|song| {
+            let fields = pattern.parse_path(&song);
+            (song, fields)
+        })
+        .collect())
+}
+
+#[tauri::command]
+async fn play_album_playlist(album: String) -> Result<(), AppError> {
+    let playlist = get_album_playlist(album).await?;
+    play_playlist(playlist).await
+}
+
+#[tauri::command]
+async fn play_artist_playlist(artist: String) -> Result<(), AppError> {
+    let playlist = get_artist_playlist(artist).await?;
+    play_playlist(playlist).await
+}
+
+#[tauri::command]
+async fn play_genre_playlist(genre: String) -> Result<(), AppError> {
+    let playlist = get_genre_playlist(genre).await?;
+    play_playlist(playlist).await
+}
+
+async fn play_playlist(playlist: Playlist) -> Result<(), AppError> {
+    let paths = playlist
+        .song_list
+        .iter()
+        .map(|song| song.get_path())
+        .collect();
+    dispatch(ControlRequest::PlayPaths { paths }).await?;
+    Ok(())
+}
+
+#[tauri::command]
+async fn get_artists() -> Result<Vec<ArtistSummary>, AppError> {
+    let artists = LIBRARY_STORE.lock().await.artists.clone();
+    let songs = get_library_songs().await?;
+    let mut artist_list = vec![];
+
+    for artist in artists {
+        let artist_songs: Vec<Song> = songs
+            .iter()
+            .filter(|song| song_has_artist(song, &artist))
+            .cloned()
+            .collect();
+        let song_count = artist_songs.len();
+        let albums = Playlist::albums_from(artist_songs);
+
+        artist_list.push(ArtistSummary {
+            name: artist,
+            album_count: albums.len(),
+            song_count,
+            cover_path: albums
+                .last()
+                .map(|album| album.cover_path.clone())
+                .unwrap_or_default(),
+        });
     }
-    let first_song = player.queue.get(0).cloned().unwrap_or_default().get_path();
-    player.play(first_song)
+    Ok(artist_list)
+}
+
+#[tauri::command]
+async fn get_artist_albums(artist: String) -> Result<Vec<Playlist>, AppError> {
+    let songs = get_library_songs().await?;
+    Ok(Playlist::albums_from(
+        songs
+            .into_iter()
+            .filter(|song| song_has_artist(song, &artist))
+            .collect(),
+    ))
+}
+
+#[tauri::command]
+async fn get_artist_playlist(artist: String) -> Result<Playlist, AppError> {
+    let albums = get_artist_albums(artist.clone()).await?;
+    // Most recent album art represents the artist
+    let cover_path = albums
+        .last()
+        .map(|album| album.cover_path.clone())
+        .unwrap_or_default();
+    let song_list = albums
+        .into_iter()
+        .flat_map(|album| album.song_list)
+        .collect();
+
+    let mut playlist = Playlist::new_playlist_from(&artist, song_list);
+    playlist.cover_path = cover_path;
+    Ok(playlist)
+}
+
+#[tauri::command]
+async fn get_genres() -> Result<Vec<GenreSummary>, AppError> {
+    let genres = LIBRARY_STORE.lock().await.genres.clone();
+    let songs = get_library_songs().await?;
+
+    Ok(genres
+        .into_iter()
+        .map(|genre| {
+            let genre_songs: Vec<&Song> = songs
+                .iter()
+                .filter(|song| song.genres.contains(&genre))
+                .collect();
+            GenreSummary {
+                song_count: genre_songs.len(),
+                cover_path: genre_songs
+                    .first()
+                    .map(|song| song.get_cover_path())
+                    .unwrap_or_default(),
+                name: genre,
+            }
+        })
+        .collect())
 }
 
 #[tauri::command]
-async fn get_album_playlists() -> Vec<Playlist> {
-    let app_cache_path = format!(
-        "{}/{}/cache.bu",
-        config_dir().unwrap().display().to_string(),
-        Arc::clone(&APP_NAME)
+async fn get_genre_playlist(genre: String) -> Result<Playlist, AppError> {
+    let songs = get_library_songs().await?;
+    let albums = Playlist::albums_from(
+        songs
+            .into_iter()
+            .filter(|song| song.genres.contains(&genre))
+            .collect(),
     );
-    let cache = read_to_string(&app_cache_path).unwrap_or_default();
-    let mut playlist_list = vec![];
+    let song_list = albums
+        .into_iter()
+        .flat_map(|album| album.song_list)
+        .collect();
+    Ok(Playlist::new_playlist_from(&genre, song_list))
+}
 
-    for l in cache.lines() {
-        let mut l = l.split_whitespace();
-        let type_of = l.next().unwrap_or_default();
-        let album_of = l.clone().collect::<Vec<&str>>().join(&String::from(" "));
-        if type_of == "Album" {
-            let playlist = get_album_playlist(album_of).await;
-            if playlist.cover_path.exists() {
-                playlist_list.push(playlist)
+// Field scoped fuzzy search, e.g. `artist:radiohead year:1997`
+#[tauri::command]
+async fn library_search(query: String, limit: Option<usize>) -> Result<SearchResults, AppError> {
+    if LIBRARY_SONGS.lock().await.is_none() {
+        get_library_songs().await?;
+    }
+    Ok(SEARCH_INDEX
+        .lock()
+        .await
+        .search(&query, limit.unwrap_or(50)))
+}
+
+#[tauri::command]
+async fn get_library_roots() -> Vec<PathBuf> {
+    CONFIG.lock().await.library_roots.clone()
+}
+
+#[tauri::command]
+async fn set_library_roots(roots: Vec<PathBuf>) -> Result<(), AppError> {
+    if let Some(root) = roots.iter().find(|root| !root.is_dir()) {
+        return Err(AppError::FileMissing(root.clone()));
+    }
+    let mut config = CONFIG.lock().await;
+    config.library_roots = roots;
+    config.save()?;
+    drop(config);
+    reload_daemon(false).await
+}
+
+#[tauri::command]
+async fn get_folder(path: Option<PathBuf>, depth: Option<usize>) -> Result<FolderNode, AppError> {
+    let depth = depth.unwrap_or(1);
+    let mut folder = match path {
+        Some(path) => {
+            let path = CONFIG.lock().await.library_path(&path)?;
+            FolderNode::read(&path, depth)?
+        }
+        None => {
+            let roots = CONFIG.lock().await.library_roots.clone();
+            let mut folders = vec![];
+            for root in roots {
+                match FolderNode::read(&root, depth.saturating_sub(1)) {
+                    Ok(folder) => folders.push(folder),
+                    Err(e) => println!("Could not read {}: {}", root.display(), e),
+                }
+            }
+            FolderNode {
+                name: String::from("Library"),
+                path: PathBuf::default(),
+                cover_path: None,
+                folders,
+                songs: vec![],
             }
         }
+    };
+
+    // Folders without a cover image use the art of their first song
+    let mut stack = vec![&mut folder];
+    while let Some(node) = stack.pop() {
+        if node.cover_path.is_none() {
+            if let Some(first_song) = node.songs.first() {
+                node.cover_path = Player::get_song_info(first_song.clone())
+                    .await
+                    .ok()
+                    .map(|song| song.get_cover_path())
+                    .filter(|cover| cover.exists());
+            }
+        }
+        stack.extend(node.folders.iter_mut());
     }
-    playlist_list
+    Ok(folder)
 }
 
-async fn get_songs_of_album(album: &String) -> Vec<Song> {
-    let player = PLAYER.lock().await;
-    let songs = get_audio_from_path("dir");
+#[tauri::command]
+async fn get_folder_playlist(path: PathBuf, recursive: Option<bool>) -> Result<Playlist, AppError> {
+    let path = CONFIG.lock().await.library_path(&path)?;
+    let mut song_list = vec![];
+
+    for song in folder_songs(&path, recursive.unwrap_or(false)) {
+        if let Ok(info) = Player::get_song_info(song).await {
+            song_list.push(info);
+        }
+    }
+
+    let mut playlist = Playlist::new_playlist_from(&path.display().to_string(), song_list);
+    playlist.name = folder_name(&path);
+    if let Some(cover_path) = folder_cover(&path) {
+        playlist.cover_path = cover_path;
+    }
+    Ok(playlist)
+}
+
+#[tauri::command]
+async fn play_folder(path: PathBuf, recursive: Option<bool>) -> Result<(), AppError> {
+    let playlist = get_folder_playlist(path, recursive).await?;
+    play_playlist(playlist).await
+}
+
+#[tauri::command]
+async fn enqueue_folder(path: PathBuf, recursive: Option<bool>) -> Result<(), AppError> {
+    let path = CONFIG.lock().await.library_path(&path)?;
+    let paths = folder_songs(&path, recursive.unwrap_or(false));
+
+    // Songs that can't be read are left out, as when playing the folder
+    let _ = dispatch(ControlRequest::QueueAdd { paths }).await;
+    Ok(())
+}
+
+#[tauri::command]
+async fn get_user_playlists() -> Result<Vec<UserPlaylist>, AppError> {
+    UserPlaylist::load_all()
+}
+
+#[tauri::command]
+async fn get_user_playlist(id: String) -> Result<UserPlaylist, AppError> {
+    let mut playlist = UserPlaylist::load(&id)?;
+    if playlist.has_moved_songs() {
+        let library = get_library_songs().await?;
+        playlist.resolve(&library);
+        playlist.save()?;
+    }
+    Ok(playlist)
+}
+
+#[tauri::command]
+async fn create_user_playlist(name: String) -> Result<UserPlaylist, AppError> {
+    let mut playlist = UserPlaylist::new(name);
+    playlist.save()?;
+    Ok(playlist)
+}
+
+#[tauri::command]
+async fn rename_user_playlist(id: String, name: String) -> Result<UserPlaylist, AppError> {
+    let mut playlist = UserPlaylist::load(&id)?;
+    playlist.name = name;
+    playlist.save()?;
+    Ok(playlist)
+}
+
+#[tauri::command]
+async fn delete_user_playlist(id: String) -> Result<(), AppError> {
+    UserPlaylist::load(&id)?.delete()
+}
+
+#[tauri::command]
+async fn duplicate_user_playlist(id: String) -> Result<UserPlaylist, AppError> {
+    let mut playlist = UserPlaylist::load(&id)?.duplicate()?;
+    playlist.save()?;
+    Ok(playlist)
+}
+
+#[tauri::command]
+async fn add_to_user_playlist(id: String, paths: Vec<PathBuf>) -> Result<UserPlaylist, AppError> {
+    let mut playlist = UserPlaylist::load(&id)?;
+    for path in paths {
+        let song = Player::get_song_info(path).await?;
+        playlist.songs.push(SongRef::from_song(&song));
+    }
+    playlist.save()?;
+    Ok(playlist)
+}
+
+#[tauri::command]
+async fn remove_from_user_playlist(id: String, index: usize) -> Result<UserPlaylist, AppError> {
+    let mut playlist = UserPlaylist::load(&id)?;
+    if index >= playlist.songs.len() {
+        return Err(AppError::OutOfRange(index));
+    }
+    playlist.songs.remove(index);
+    playlist.save()?;
+    Ok(playlist)
+}
+
+#[tauri::command]
+async fn move_in_user_playlist(
+    id: String,
+    from: usize,
+    to: usize,
+) -> Result<UserPlaylist, AppError> {
+    let mut playlist = UserPlaylist::load(&id)?;
+    playlist.move_song(from, to)?;
+    playlist.save()?;
+    Ok(playlist)
+}
+
+#[tauri::command]
+async fn set_user_playlist_cover(
+    id: String,
+    cover_path: Option<PathBuf>,
+) -> Result<UserPlaylist, AppError> {
+    let mut playlist = UserPlaylist::load(&id)?;
+    playlist.set_cover(cover_path.as_deref())?;
+    playlist.save()?;
+    Ok(playlist)
+}
+
+#[tauri::command]
+async fn play_user_playlist(id: String) -> Result<(), AppError> {
+    let playlist = get_user_playlist_songs(id).await?;
+    play_playlist(playlist).await
+}
+
+async fn get_user_playlist_songs(id: String) -> Result<Playlist, AppError> {
+    let user_playlist = get_user_playlist(id).await?;
+    let mut song_list = vec![];
+
+    for song in user_playlist.songs.iter().filter(|song| !song.missing) {
+        if let Ok(info) = Player::get_song_info(song.path.clone()).await {
+            song_list.push(info);
+        }
+    }
+    Ok(user_playlist.to_playlist(song_list))
+}
+
+#[tauri::command]
+async fn get_smart_playlists() -> Result<Vec<SmartPlaylist>, AppError> {
+    SmartPlaylist::load_all()
+}
+
+#[tauri::command]
+async fn save_smart_playlist(
+    id: Option<String>,
+    name: String,
+    query: String,
+    sort_by: Option<String>,
+    descending: Option<bool>,
+    limit: Option<usize>,
+) -> Result<SmartPlaylist, AppError> {
+    let mut playlists = SmartPlaylist::load_all()?;
+    let mut playlist = match &id {
+        Some(id) => SmartPlaylist::load(id)?,
+        None => SmartPlaylist::new(name.clone(), query.clone()),
+    };
+    playlist.name = name;
+    playlist.query = query;
+    playlist.sort_by = sort_by;
+    playlist.descending = descending.unwrap_or_default();
+    playlist.limit = limit;
+    playlist.validate()?;
+
+    match playlists.iter_mut().find(|p| p.id == playlist.id) {
+        Some(existing) => *existing = playlist.clone(),
+        None => playlists.push(playlist.clone()),
+    }
+    SmartPlaylist::save_all(&playlists)?;
+    Ok(playlist)
+}
+
+#[tauri::command]
+async fn delete_smart_playlist(id: String) -> Result<(), AppError> {
+    let mut playlists = SmartPlaylist::load_all()?;
+    playlists.retain(|playlist| playlist.id != id);
+    SmartPlaylist::save_all(&playlists)
+}
+
+#[tauri::command]
+async fn preview_smart_playlist(
+    query: String,
+    sort_by: Option<String>,
+    descending: Option<bool>,
+    limit: Option<usize>,
+) -> Result<Playlist, AppError> {
+    let mut playlist = SmartPlaylist::new(String::from("Preview"), query);
+    playlist.sort_by = sort_by;
+    playlist.descending = descending.unwrap_or_default();
+    playlist.limit = limit;
+    evaluate_smart_playlist(&playlist).await
+}
+
+// Evaluated against the current library, so results follow every rescan
+#[tauri::command]
+async fn get_smart_playlist(id: String) -> Result<Playlist, AppError> {
+    let playlist = SmartPlaylist::load(&id)?;
+    evaluate_smart_playlist(&playlist).await
+}
+
+#[tauri::command]
+async fn play_smart_playlist(id: String) -> Result<(), AppError> {
+    let playlist = get_smart_playlist(id).await?;
+    play_playlist(playlist).await
+}
+
+async fn evaluate_smart_playlist(playlist: &SmartPlaylist) -> Result<Playlist, AppError> {
+    playlist.validate()?;
+    let songs = get_library_songs().await?;
+    let stats = LIBRARY_STORE.lock().await.songs.clone();
+    let song_list = playlist.evaluate(&songs, &stats)?;
+
+    let mut result = Playlist::new_playlist_from(&playlist.id, song_list);
+    result.name = playlist.name.clone();
+    Ok(result)
+}
+
+async fn get_playlist(source: PlaylistSource) -> Result<Playlist, AppError> {
+    match source {
+        PlaylistSource::Album(album) => get_album_playlist(album).await,
+        PlaylistSource::Artist(artist) => get_artist_playlist(artist).await,
+        PlaylistSource::Genre(genre) => get_genre_playlist(genre).await,
+        PlaylistSource::Folder(path) => get_folder_playlist(path, Some(true)).await,
+        PlaylistSource::User(id) => get_user_playlist_songs(id).await,
+        PlaylistSource::Smart(id) => get_smart_playlist(id).await,
+        PlaylistSource::Queue => {
+            // The daemon's queue when attached to one
+            let queue = dispatch(ControlRequest::Queue).await?.queue;
+            let mut song_list = vec![];
+            for entry in queue.unwrap_or_default() {
+                let song = match Player::get_song_info(entry.path.clone()).await {
+                    Ok(song) => song,
+                    Err(_) => {
+                        let mut song = Song::new(entry.path);
+                        song.title = entry.title;
+                        song.artist = entry.artist;
+                        song.album = entry.album;
+                        song.duration = Duration::from_secs(entry.duration);
+                        song
+                    }
+                };
+                song_list.push(song);
+            }
+            Ok(Playlist::new_playlist_from(
+                &String::from("Queue"),
+                song_list,
+            ))
+        }
+    }
+}
+
+#[tauri::command]
+async fn import_playlist_file(path: PathBuf) -> Result<ImportReport, AppError> {
+    let format = PlaylistFormat::from_path(&path)?;
+    let entries = format.parse(&fs::read(&path)?);
+    let base = path.parent().map(Path::to_path_buf).unwrap_or_default();
+    let roots = CONFIG.lock().await.library_roots.clone();
+
+    let mut resolved: Vec<Option<PathBuf>> = entries
+        .iter()
+        .map(|entry| entry.resolve(&base, &roots))
+        .collect();
+
+    // Tracks that moved: look for the same file name anywhere in the library
+    if resolved.iter().any(Option::is_none) {
+        let files = get_library_audio().await?;
+        for (entry, song) in entries.iter().zip(resolved.iter_mut()) {
+            if song.is_none() {
+                *song = files
+                    .iter()
+                    .find(|file| {
+                        let file_name = file.file_name().unwrap_or_default().to_string_lossy();
+                        Some(file_name.to_lowercase()) == entry.file_name()
+                    })
+                    .cloned();
+            }
+        }
+    }
+
+    // Then for a song with the same title and artist
+    if resolved.iter().any(Option::is_none) {
+        let songs = get_library_songs().await?;
+        for (entry, song) in entries.iter().zip(resolved.iter_mut()) {
+            if song.is_none() {
+                *song = songs
+                    .iter()
+                    .find(|song| entry.matches(song))
+                    .map(|song| song.get_path());
+            }
+        }
+    }
+
+    let name = path.file_stem().unwrap_or_default().to_string_lossy();
+    let mut playlist = UserPlaylist::new(name.to_string());
+    let mut unresolved = vec![];
+
+    for (entry, song) in entries.into_iter().zip(resolved) {
+        match song.map(Player::get_song_info) {
+            Some(song) => match song.await {
+                Ok(song) => playlist.songs.push(SongRef::from_song(&song)),
+                Err(_) => unresolved.push(entry.location),
+            },
+            None => {
+                playlist.songs.push(SongRef {
+                    path: PathBuf::from(&entry.location),
+                    title: entry.title,
+                    artist: entry.artist,
+                    album: entry.album,
+                    duration: entry.duration.unwrap_or_default(),
+                    musicbrainz_recording: None,
+                    missing: true,
+                });
+                unresolved.push(entry.location);
+            }
+        }
+    }
+
+    playlist.save()?;
+    Ok(ImportReport {
+        playlist,
+        unresolved,
+    })
+}
+
+#[tauri::command]
+async fn export_playlist_file(
+    source: PlaylistSource,
+    path: PathBuf,
+    relative: Option<bool>,
+) -> Result<PathBuf, AppError> {
+    let mut path = path;
+    let mut format = PlaylistFormat::from_path(&path)?;
+    // Players read .m3u as Latin-1, so UTF-8 paths and titles go to .m3u8
+    if format == PlaylistFormat::M3u {
+        path.set_extension("m3u8");
+        format = PlaylistFormat::M3u8;
+    }
+    let playlist = get_playlist(source).await?;
+    let base = path.parent().filter(|_| relative.unwrap_or(false));
+
+    let entries: Vec<PlaylistEntry> = playlist
+        .song_list
+        .iter()
+        .map(|song| PlaylistEntry::from_song(song, base))
+        .collect();
+    fs::write(&path, format.write(&playlist.name, &entries))?;
+    Ok(path)
+}
+
+fn song_has_artist(song: &Song, artist: &String) -> bool {
+    song.artists.contains(artist) || song.album_artist.as_ref() == Some(artist)
+}
+
+#[tauri::command]
+async fn get_album_playlists() -> Result<Vec<Playlist>, AppError> {
+    let albums = LIBRARY_STORE.lock().await.albums.clone();
+    let mut playlist_list = vec![];
+
+    for album in albums {
+        let playlist = get_album_playlist(album).await?;
+        if playlist.cover_path.exists() {
+            playlist_list.push(playlist)
+        }
+    }
+    Ok(playlist_list)
+}
+
+#[tauri::command]
+async fn get_album_track_issues() -> Result<Vec<TrackIssues>, AppError> {
+    let mut albums: BTreeMap<String, Vec<Song>> = BTreeMap::new();
+
+    for song in get_library_audio().await? {
+        let Ok(info) = Player::get_song_info(song).await else {
+            continue;
+        };
+        if info.album.is_some() {
+            albums.entry(info.album_key()).or_default().push(info);
+        }
+    }
+
+    Ok(albums
+        .iter()
+        .flat_map(|(album, songs)| Playlist::new_playlist_from(album, songs.clone()).track_issues())
+        .collect())
+}
+
+async fn get_songs_of_album(album: &String) -> Result<Vec<Song>, AppError> {
+    let songs = get_library_songs().await?;
+    Ok(songs
+        .into_iter()
+        .filter(|song| &song.album_key() == album)
+        .collect())
+}
+
+async fn get_library_songs() -> Result<Vec<Song>, AppError> {
+    if let Some(song_list) = LIBRARY_SONGS.lock().await.as_ref() {
+        return Ok(song_list.clone());
+    }
+
+    let songs = get_library_audio().await?;
     let mut song_list = vec![];
 
     for song in songs {
-        let info = player.get_song_info(song).await;
-        if &info.album.clone().unwrap_or_default() == album {
+        if let Ok(mut info) = Player::get_song_info(song).await {
+            info.cover_data = None;
             song_list.push(info);
         }
     }
-    song_list
+    cache_library_songs(song_list.clone()).await;
+    Ok(song_list)
 }
 
-async fn get_album_playlist(album: String) -> Playlist {
-    let app_cache_path = format!(
-        "{}/{}/cache.bu",
-        config_dir().unwrap().display().to_string(),
-        Arc::clone(&APP_NAME)
-    );
-    let cache = read_to_string(&app_cache_path).unwrap_or_default();
-    let song_list: Vec<Song> = get_songs_of_album(&album).await;
+// An attached GUI shares library.json with the daemon, which saves plays to it, so changes
+// start from the file instead of what this process loaded
+async fn library_store_for_update() -> Result<MutexGuard<'static, LibraryStore>, AppError> {
+    let mut store = LIBRARY_STORE.lock().await;
+    if is_attached() {
+        *store = LibraryStore::load()?;
+    }
+    Ok(store)
+}
+
+// Has an attached daemon reread config.json and library.json after this process saved them
+async fn reload_daemon(songs: bool) -> Result<(), AppError> {
+    if is_attached() {
+        dispatch(ControlRequest::Reload { songs }).await?;
+    }
+    Ok(())
+}
+
+async fn cache_library_songs(song_list: Vec<Song>) {
+    SEARCH_INDEX.lock().await.sync(&song_list);
+    cache_library_ids(&song_list).await;
+    *LIBRARY_SONGS.lock().await = Some(song_list);
+}
+
+async fn get_album_playlist(album: String) -> Result<Playlist, AppError> {
     let mut playlist = Playlist::new();
+    if !LIBRARY_STORE.lock().await.albums.contains(&album) {
+        return Ok(playlist);
+    }
 
-    for l in cache.lines() {
-        let mut l = l.split_whitespace();
-        let _type_of = l.next().unwrap_or_default();
-        let album_of = &l.clone().collect::<Vec<&str>>().join(&String::from(" "));
-        if album_of == &album {
-            playlist = Playlist::new_playlist_from(
-                &song_list
-                    .get(0)
-                    .cloned()
-                    .unwrap_or_default()
-                    .album
-                    .clone()
-                    .unwrap_or_default(),
-                song_list.clone(),
-            );
-        }
+    let song_list: Vec<Song> = get_songs_of_album(&album).await?;
+    playlist = Playlist::new_playlist_from(&album, song_list.clone());
+    playlist.sort_by_track();
+    playlist.name = song_list
+        .first()
+        .cloned()
+        .unwrap_or_default()
+        .album
+        .unwrap_or_default();
+    Ok(playlist)
+}
+
+async fn get_library_audio() -> Result<Vec<PathBuf>, AppError> {
+    let roots = CONFIG.lock().await.library_roots.clone();
+    if roots.is_empty() {
+        return Err(AppError::DirectoryMissing("audio"));
     }
-    playlist
+    Ok(roots
+        .iter()
+        .flat_map(|root| get_audio_from_path(root))
+        .collect())
 }
 
-fn get_audio_from_path(_dir: &str) -> Vec<PathBuf> {
-    let paths = dirs::audio_dir().unwrap();
+fn get_audio_from_path(dir: &Path) -> Vec<PathBuf> {
     let mut path_list: Vec<PathBuf> = vec![];
 
-    for entry in WalkDir::new(paths).into_iter().filter_map(|e| e.ok()) {
-        let file_name = entry.file_name().to_str().unwrap();
-        let formats = [".mp3", ".ogg", ".wav", ".flac", ".aac", ".m4a"];
-        if formats.iter().any(|&format| file_name.contains(format)) {
+    for entry in WalkDir::new(dir).into_iter().filter_map(|e| e.ok()) {
+        if entry.file_type().is_file() && is_audio_file(entry.path()) {
             path_list.push(entry.path().to_owned());
         }
     }
     path_list
 }
 
-fn process_playlist_type(t: String) {
-    let app_cache_path = format!(
-        "{}/{}/cache.bu",
-        config_dir().unwrap().display().to_string(),
-        Arc::clone(&APP_NAME)
-    );
-    let cache = read_to_string(&app_cache_path).unwrap_or_default();
+// Advances the queue when a song runs out, so playback goes on without a window
+async fn run_player_ticks() {
+    let mut interval = tokio::time::interval(Duration::from_millis(250));
+    loop {
+        interval.tick().await;
+        if let Err(e) = PLAYER.lock().await.tick() {
+            println!("Could not play the next song: {}", e);
+        }
+    }
+}
+
+// Everything that owns or follows the player, started by whichever process plays audio
+fn start_player_services() {
+    tauri::async_runtime::spawn(rescan_outdated_library());
+    tauri::async_runtime::spawn(run_player_ticks());
+    tauri::async_runtime::spawn(record_playback());
+    tauri::async_runtime::spawn(run_scrobbler());
+    tauri::async_runtime::spawn(run_mpd_server());
+    tauri::async_runtime::spawn(run_subsonic_server());
+    tauri::async_runtime::spawn(run_remote_server());
+    #[cfg(unix)]
+    tauri::async_runtime::spawn(control::run_control_server());
+    #[cfg(target_os = "linux")]
+    tauri::async_runtime::spawn(mpris::run_mpris());
+}
 
-    if t == String::default() {
+// Stores from older versions miss what only a scan can fill in, like album keys
+async fn rescan_outdated_library() {
+    if !LIBRARY_STORE.lock().await.needs_rescan() {
         return;
     }
-    if cache.contains(&t) {
-        return;
+    if let Err(e) = create_playlist_types().await {
+        println!("Could not rescan the library: {}", e);
     }
+}
 
-    let _ = fs::create_dir_all(format!(
-        "{}/{}",
-        config_dir().unwrap().display().to_string(),
-        Arc::clone(&APP_NAME)
-    ));
-
-    let mut cache_file = OpenOptions::new()
-        .append(true)
-        .write(true)
-        .create(true)
-        .open(&app_cache_path)
-        .unwrap();
+// `bupl --daemon`: plays without a window and is controlled over the socket or MPRIS
+pub fn run_daemon() {
+    tauri::async_runtime::block_on(async {
+        start_player_services();
+        // There is no window to suppress them, an attached one included
+        tauri::async_runtime::spawn(run_notifications(|| false));
+        shutdown_signal().await;
+        PLAYER.lock().await.empty_queue();
+        #[cfg(unix)]
+        control::remove_control_socket();
+        // Give stats and scrobbling a moment to record the interrupted song
+        tokio::time::sleep(Duration::from_millis(500)).await;
+    });
+}
 
-    let wrt = t + "\n";
-    let _ = cache_file.write_all(wrt.as_bytes());
+async fn shutdown_signal() {
+    #[cfg(unix)]
+    {
+        use tokio::signal::unix::{signal, SignalKind};
+        if let Ok(mut terminate) = signal(SignalKind::terminate()) {
+            tokio::select! {
+                _ = tokio::signal::ctrl_c() => {}
+                _ = terminate.recv() => {}
+            }
+            return;
+        }
+    }
+    let _ = tokio::signal::ctrl_c().await;
 }
 
 #[cfg_attr(mobile, tauri::mobile_entry_point)]
 pub fn run() {
     tauri::Builder::default()
         .plugin(tauri_plugin_shell::init())
+        .plugin(shortcuts::plugin())
+        .on_window_event(handle_window_event)
+        .setup(|app| {
+            // Shortcuts and the tray go to the daemon too when attached to one
+            let handle = app.handle().clone();
+            tauri::async_runtime::spawn(async move { register_shortcuts(&handle).await });
+            create_tray(app.handle())?;
+            #[cfg(unix)]
+            let attached = tauri::async_runtime::block_on(control::attach_to_daemon());
+            #[cfg(not(unix))]
+            let attached = false;
+            if !attached {
+                start_player_services();
+                let handle = app.handle().clone();
+                tauri::async_runtime::spawn(run_notifications(move || {
+                    handle
+                        .get_webview_window("main")
+                        .and_then(|window| window.is_focused().ok())
+                        .unwrap_or(false)
+                }));
+            }
+            Ok(())
+        })
         .invoke_handler(tauri::generate_handler![
             player_song_position,
             player_song_paused,
             player_song_finished,
+            player_queue_index,
             player_skip,
             player_play_or_pause,
             player_current_song_info,
@@ -355,8 +1370,73 @@ pub fn run() {
             player_adjust_volume,
             player_shuffle_queue,
             create_playlist_types,
+            get_scan_report,
+            get_song_stats,
+            set_song_rating,
+            get_listening_report,
+            get_year_in_review,
+            export_listening_report,
+            get_scrobbler_log_path,
+            import_scrobbler_log,
+            get_scrobble_settings,
+            set_scrobble_settings,
+            get_scrobble_queue,
+            get_mpd_settings,
+            set_mpd_settings,
+            get_subsonic_settings,
+            set_subsonic_settings,
+            get_remote_settings,
+            set_remote_settings,
+            reset_remote_token,
+            get_notification_settings,
+            set_notification_settings,
+            get_tray_settings,
+            set_tray_settings,
+            get_shortcut_settings,
+            set_shortcut_settings,
+            get_shortcut_conflicts,
+            flush_scrobble_queue,
+            lastfm_begin_auth,
+            lastfm_finish_auth,
+            get_path_patterns,
+            set_path_patterns,
+            preview_path_pattern,
             get_album_playlists,
             play_album_playlist,
+            get_artists,
+            get_artist_albums,
+            get_artist_playlist,
+            play_artist_playlist,
+            get_genres,
+            get_genre_playlist,
+            play_genre_playlist,
+            library_search,
+            get_library_roots,
+            set_library_roots,
+            get_folder,
+            get_folder_playlist,
+            play_folder,
+            enqueue_folder,
+            get_user_playlists,
+            get_user_playlist,
+            create_user_playlist,
+            rename_user_playlist,
+            delete_user_playlist,
+            duplicate_user_playlist,
+            add_to_user_playlist,
+            remove_from_user_playlist,
+            move_in_user_playlist,
+            set_user_playlist_cover,
+            play_user_playlist,
+            get_smart_playlists,
+            save_smart_playlist,
+            delete_smart_playlist,
+            preview_smart_playlist,
+            get_smart_playlist,
+            play_smart_playlist,
+            import_playlist_file,
+            export_playlist_file,
+            get_album_track_issues,
             fetch_album_cover
         ])
         .run(tauri::generate_context!())
@@ -0,0 +1,230 @@
+use lazy_static::lazy_static;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, BTreeSet};
+use std::fs::{self, read_to_string};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::{SystemTime, UNIX_EPOCH};
+use tokio::sync::Mutex;
+
+use crate::config::app_config_path;
+use crate::error::AppError;
+use crate::player::PlayRecord;
+use crate::song::{Song, SongError};
+
+const LIBRARY_VERSION: u32 = 3;
+const AUDIO_FORMATS: [&str; 6] = ["mp3", "ogg", "wav", "flac", "aac", "m4a"];
+
+lazy_static! {
+    pub static ref LIBRARY_STORE: Arc<Mutex<LibraryStore>> =
+        Arc::new(Mutex::new(LibraryStore::load().unwrap_or_else(|e| {
+            println!("Could not load the library store: {}", e);
+            LibraryStore::default()
+        })));
+    // Songs of the last scan, None until the library is first read
+    pub static ref LIBRARY_SONGS: Arc<Mutex<Option<Vec<Song>>>> = Arc::new(Mutex::new(None));
+}
+
+#[derive(Debug, Default, Clone, Serialize)]
+pub struct ScanReport {
+    pub scanned: usize,
+    pub failed: Vec<ScanFailure>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct ScanFailure {
+    pub path: PathBuf,
+    pub error: String,
+}
+
+impl ScanReport {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn add_failure(&mut self, path: PathBuf, error: &SongError) {
+        println!("Skipping {}: {}", path.display(), error);
+        self.failed.push(ScanFailure {
+            path,
+            error: error.to_string(),
+        });
+    }
+}
+
+// Playlist categories found while scanning, saved as library.json
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct LibraryStore {
+    pub version: u32,
+    pub albums: BTreeSet<String>,
+    pub artists: BTreeSet<String>,
+    pub genres: BTreeSet<String>,
+    pub songs: BTreeMap<PathBuf, SongStats>,
+}
+
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct SongStats {
+    pub date_added: u64,
+    pub play_count: u32,
+    pub skip_count: u32,
+    pub last_played: Option<u64>,
+    pub rating: Option<u8>,
+}
+
+impl SongStats {
+    // Files found on the first scan count as added when they were created
+    pub fn added(path: &Path) -> Self {
+        let created = fs::metadata(path)
+            .and_then(|metadata| metadata.created().or(metadata.modified()))
+            .unwrap_or(SystemTime::now());
+        Self {
+            date_added: unix_time(created),
+            ..Self::default()
+        }
+    }
+}
+
+impl Default for LibraryStore {
+    fn default() -> Self {
+        Self {
+            version: LIBRARY_VERSION,
+            albums: BTreeSet::default(),
+            artists: BTreeSet::default(),
+            genres: BTreeSet::default(),
+            songs: BTreeMap::default(),
+        }
+    }
+}
+
+impl LibraryStore {
+    pub fn load() -> Result<Self, AppError> {
+        let config_path = app_config_path()?;
+        let store_path = format!("{}/library.json", config_path);
+        let legacy_path = format!("{}/cache.bu", config_path);
+
+        if Path::new(&store_path).exists() {
+            let store = read_to_string(&store_path)
+                .map_err(AppError::from)
+                .and_then(|store| Ok(serde_json::from_str::<Self>(&store)?));
+            return match store {
+                Ok(store) => Ok(store.migrate()),
+                // Kept aside so the play counts and ratings in it can still be recovered
+                Err(e) => {
+                    let backup = format!("{}.bak", store_path);
+                    println!("Could not read {}, moving it to {}: {}", store_path, backup, e);
+                    fs::rename(&store_path, &backup)?;
+                    // Outdated, so a rescan fills the categories back in
+                    Ok(Self {
+                        version: 0,
+                        ..Self::default()
+                    })
+                }
+            };
+        }
+
+        let Ok(cache) = read_to_string(&legacy_path) else {
+            return Ok(Self::default());
+        };
+        println!("Migrating {} to {}", legacy_path, store_path);
+        let store = Self::from_legacy_cache(&cache);
+        store.save()?;
+        fs::rename(&legacy_path, format!("{}.bak", legacy_path))?;
+        Ok(store)
+    }
+
+    pub fn save(&self) -> Result<(), AppError> {
+        let config_path = app_config_path()?;
+        fs::create_dir_all(&config_path)?;
+        let store = serde_json::to_string_pretty(self)?;
+        fs::write(format!("{}/library.json", config_path), store)?;
+        Ok(())
+    }
+
+    pub fn add_song(&mut self, song: &Song) {
+        if song.album.is_some() {
+            self.albums.insert(song.album_key());
+        }
+        self.artists.extend(song.artists.iter().cloned());
+        self.genres.extend(song.genres.iter().cloned());
+    }
+
+    // Ratings from tags fill in songs not rated here, so they survive a library wipe
+    pub fn add_stats(&mut self, song: &Song) {
+        let path = song.get_path();
+        let stats = self
+            .songs
+            .entry(path.clone())
+            .or_insert_with(|| SongStats::added(&path));
+        if stats.rating.is_none() {
+            stats.rating = song.rating;
+        }
+    }
+
+    pub fn record_play(&mut self, record: &PlayRecord) {
+        let path = record.song.get_path();
+        let stats = self
+            .songs
+            .entry(path.clone())
+            .or_insert_with(|| SongStats::added(&path));
+        if record.counts_as_play() {
+            stats.play_count += 1;
+            stats.last_played = stats.last_played.max(Some(record.started));
+        } else {
+            stats.skip_count += 1;
+        }
+    }
+
+    // Upgrades stores written by older versions, one version at a time
+    fn migrate(self) -> Self {
+        if self.version < LIBRARY_VERSION {
+            println!(
+                "Migrating library store from version {} to {}",
+                self.version, LIBRARY_VERSION
+            );
+        }
+        // Version 2 added per song stats and version 3 keys albums by Song::album_key,
+        // both are filled in by a rescan, which then bumps the version
+        self
+    }
+
+    pub fn needs_rescan(&self) -> bool {
+        self.version < LIBRARY_VERSION
+    }
+
+    // cache.bu held one `<Type> <name>` line per category
+    fn from_legacy_cache(cache: &str) -> Self {
+        let mut store = Self {
+            version: 1,
+            ..Self::default()
+        };
+
+        for line in cache.lines() {
+            let Some((type_of, name)) = line.split_once(' ') else {
+                continue;
+            };
+            if name.is_empty() {
+                continue;
+            }
+            let category = match type_of {
+                "Album" => &mut store.albums,
+                "Artist" => &mut store.artists,
+                "Genre" => &mut store.genres,
+                _ => continue,
+            };
+            category.insert(name.to_string());
+        }
+        store
+    }
+}
+
+pub fn unix_time(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+}
+
+pub fn is_audio_file(path: &Path) -> bool {
+    let extension = path.extension().unwrap_or_default().to_string_lossy();
+    AUDIO_FORMATS.contains(&extension.to_lowercase().as_str())
+}
@@ -2,5 +2,9 @@
 #![cfg_attr(not(debug_assertions), windows_subsystem = "windows")]
 
 fn main() {
-    bupl_lib::run()
+    if std::env::args().any(|arg| arg == "--daemon") {
+        bupl_lib::run_daemon()
+    } else {
+        bupl_lib::run()
+    }
 }
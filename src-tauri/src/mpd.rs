@@ -0,0 +1,1427 @@
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeSet, HashMap};
+use std::fmt::Write;
+use std::path::{Component, Path, PathBuf};
+use std::time::{Duration, Instant};
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::broadcast::error::{RecvError, TryRecvError};
+use tokio::sync::broadcast::Receiver;
+
+use crate::config::CONFIG;
+use crate::control::{handle_request, ControlRequest};
+use crate::error::AppError;
+use crate::folder::folder_songs;
+use crate::library::is_audio_file;
+use crate::player::{Player, PlayerEvent, PLAYER_EVENTS};
+use crate::song::Song;
+use crate::user_playlist::UserPlaylist;
+use crate::{create_playlist_types, get_library_songs, PLAYER};
+
+const PROTOCOL_VERSION: &str = "0.23.0";
+
+const COMMANDS: &[&str] = &[
+    "add",
+    "addid",
+    "clear",
+    "close",
+    "commands",
+    "consume",
+    "count",
+    "currentsong",
+    "decoders",
+    "delete",
+    "deleteid",
+    "find",
+    "findadd",
+    "getvol",
+    "idle",
+    "list",
+    "listall",
+    "listallinfo",
+    "listplaylist",
+    "listplaylistinfo",
+    "listplaylists",
+    "load",
+    "lsinfo",
+    "move",
+    "moveid",
+    "next",
+    "noidle",
+    "notcommands",
+    "outputs",
+    "password",
+    "pause",
+    "ping",
+    "play",
+    "playid",
+    "playlistid",
+    "playlistinfo",
+    "plchanges",
+    "plchangesposid",
+    "previous",
+    "random",
+    "repeat",
+    "replay_gain_status",
+    "rescan",
+    "search",
+    "searchadd",
+    "seek",
+    "seekcur",
+    "seekid",
+    "setvol",
+    "shuffle",
+    "single",
+    "stats",
+    "status",
+    "stop",
+    "tagtypes",
+    "update",
+    "urlhandlers",
+    "volume",
+];
+
+const TAG_TYPES: &[&str] = &[
+    "Artist",
+    "Album",
+    "AlbumArtist",
+    "Title",
+    "Track",
+    "Genre",
+    "Date",
+    "Composer",
+    "Disc",
+    "MUSICBRAINZ_TRACKID",
+];
+
+// Off by default, set `address` to e.g. 0.0.0.0:6600 to let phones on the network connect
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct MpdSettings {
+    pub enabled: bool,
+    pub address: String,
+    pub password: Option<String>,
+}
+
+impl Default for MpdSettings {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            address: String::from("127.0.0.1:6600"),
+            password: None,
+        }
+    }
+}
+
+// Error codes from the MPD protocol, sent as `ACK [code@index] {command} message`
+#[derive(Debug, Clone, Copy)]
+enum Ack {
+    Arg = 2,
+    Password = 3,
+    Permission = 4,
+    Unknown = 5,
+    NoExist = 50,
+    System = 52,
+}
+
+#[derive(Debug)]
+struct MpdError {
+    ack: Ack,
+    message: String,
+}
+
+impl MpdError {
+    fn new(ack: Ack, message: impl Into<String>) -> Self {
+        Self {
+            ack,
+            message: message.into(),
+        }
+    }
+}
+
+impl From<AppError> for MpdError {
+    fn from(e: AppError) -> Self {
+        let ack = match e {
+            AppError::OutOfRange(_) => Ack::Arg,
+            AppError::QueueEmpty
+            | AppError::FileMissing(_)
+            | AppError::PlaylistNotFound(_)
+            | AppError::OutsideLibrary(_) => Ack::NoExist,
+            _ => Ack::System,
+        };
+        Self::new(ack, e.to_string())
+    }
+}
+
+type MpdResult = Result<String, MpdError>;
+
+struct Session {
+    authorized: bool,
+    events: Receiver<PlayerEvent>,
+    started: Instant,
+}
+
+pub async fn run_mpd_server() {
+    let settings = CONFIG.lock().await.mpd.clone();
+    if !settings.enabled {
+        return;
+    }
+    let served = match TcpListener::bind(&settings.address).await {
+        Ok(listener) => serve(listener, settings.password).await,
+        Err(e) => Err(AppError::from(e)),
+    };
+    if let Err(e) = served {
+        println!("MPD server unavailable: {}", e);
+    }
+}
+
+async fn serve(listener: TcpListener, password: Option<String>) -> Result<(), AppError> {
+    let started = Instant::now();
+    loop {
+        let (stream, _) = listener.accept().await?;
+        let session = Session {
+            authorized: password.is_none(),
+            events: PLAYER_EVENTS.subscribe(),
+            started,
+        };
+        tokio::spawn(handle_client(stream, session, password.clone()));
+    }
+}
+
+async fn handle_client(
+    stream: TcpStream,
+    mut session: Session,
+    password: Option<String>,
+) -> Result<(), AppError> {
+    let (reader, mut writer) = stream.into_split();
+    let mut lines = BufReader::new(reader).lines();
+    writer
+        .write_all(format!("OK MPD {}\n", PROTOCOL_VERSION).as_bytes())
+        .await?;
+
+    // Commands between command_list_begin and command_list_end run together
+    let mut command_list: Option<(bool, Vec<String>)> = None;
+    while let Some(line) = lines.next_line().await? {
+        let line = line.trim_end().to_string();
+        match (line.as_str(), command_list.as_mut()) {
+            ("command_list_begin", None) => command_list = Some((false, vec![])),
+            ("command_list_ok_begin", None) => command_list = Some((true, vec![])),
+            ("command_list_end", Some(_)) => {
+                let (list_ok, commands) = command_list.take().unwrap_or_default();
+                let response = run_command_list(&mut session, &password, &commands, list_ok).await;
+                writer.write_all(response.as_bytes()).await?;
+            }
+            (_, Some((_, commands))) => commands.push(line),
+            ("close", None) => return Ok(()),
+            (_, None) if line == "idle" || line.starts_with("idle ") => {
+                if !session.authorized {
+                    let response = ack(
+                        &MpdError::new(Ack::Permission, "you don't have permission for \"idle\""),
+                        0,
+                        "idle",
+                    );
+                    writer.write_all(response.as_bytes()).await?;
+                    continue;
+                }
+                let filter = tokenize(&line).unwrap_or_default().split_off(1);
+                let response = idle(&mut session, &filter, &mut lines).await?;
+                writer.write_all(response.as_bytes()).await?;
+            }
+            (_, None) => {
+                let response = match run_line(&mut session, &password, &line).await {
+                    Ok(output) => output + "OK\n",
+                    Err((command, e)) => ack(&e, 0, &command),
+                };
+                writer.write_all(response.as_bytes()).await?;
+            }
+        }
+    }
+    Ok(())
+}
+
+async fn run_command_list(
+    session: &mut Session,
+    password: &Option<String>,
+    commands: &[String],
+    list_ok: bool,
+) -> String {
+    let mut response = String::new();
+    for (i, line) in commands.iter().enumerate() {
+        match run_line(session, password, line).await {
+            Ok(output) => {
+                response.push_str(&output);
+                if list_ok {
+                    response.push_str("list_OK\n");
+                }
+            }
+            Err((command, e)) => return response + &ack(&e, i, &command),
+        }
+    }
+    response + "OK\n"
+}
+
+async fn run_line(
+    session: &mut Session,
+    password: &Option<String>,
+    line: &str,
+) -> Result<String, (String, MpdError)> {
+    let args = tokenize(line).map_err(|e| (String::new(), e))?;
+    let Some((command, args)) = args.split_first() else {
+        return Err((
+            String::new(),
+            MpdError::new(Ack::Unknown, "No command given"),
+        ));
+    };
+    let command = command.to_lowercase();
+
+    let result = match command.as_str() {
+        "password" => match (args.first(), password) {
+            (Some(given), Some(password)) if given == password => {
+                session.authorized = true;
+                Ok(String::new())
+            }
+            (_, None) => Ok(String::new()),
+            _ => Err(MpdError::new(Ack::Password, "incorrect password")),
+        },
+        "ping" | "commands" | "notcommands" | "tagtypes" if !session.authorized => {
+            execute(session, &command, args).await
+        }
+        _ if !session.authorized => Err(MpdError::new(
+            Ack::Permission,
+            format!("you don't have permission for \"{}\"", command),
+        )),
+        _ => execute(session, &command, args).await,
+    };
+    result.map_err(|e| (command, e))
+}
+
+fn ack(e: &MpdError, index: usize, command: &str) -> String {
+    format!(
+        "ACK [{}@{}] {{{}}} {}\n",
+        e.ack as u8, index, command, e.message
+    )
+}
+
+// Waits for the next change, or answers right away if something changed since the last idle
+async fn idle(
+    session: &mut Session,
+    filter: &[String],
+    lines: &mut tokio::io::Lines<BufReader<tokio::net::tcp::OwnedReadHalf>>,
+) -> Result<String, AppError> {
+    let mut changed: BTreeSet<&str> = BTreeSet::new();
+    let mut note = |subsystems: &[&'static str]| {
+        let wanted = subsystems
+            .iter()
+            .filter(|s| filter.is_empty() || filter.iter().any(|f| f == *s));
+        changed.extend(wanted);
+        !changed.is_empty()
+    };
+
+    let mut found = false;
+    loop {
+        found = match session.events.try_recv() {
+            Ok(event) => note(subsystems(&event)),
+            Err(TryRecvError::Lagged(_)) => note(ALL_SUBSYSTEMS),
+            Err(TryRecvError::Empty | TryRecvError::Closed) => break,
+        };
+    }
+
+    while !found {
+        tokio::select! {
+            event = session.events.recv() => found = match event {
+                Ok(event) => note(subsystems(&event)),
+                Err(RecvError::Lagged(_)) => note(ALL_SUBSYSTEMS),
+                Err(RecvError::Closed) => break,
+            },
+            line = lines.next_line() => match line?.as_deref().map(str::trim) {
+                Some("noidle") => break,
+                // Anything else while idle is a protocol error, MPD hangs up too
+                _ => return Err(AppError::Io(String::from("Client left idle"))),
+            },
+        }
+    }
+
+    let mut response: String = changed
+        .iter()
+        .map(|subsystem| format!("changed: {}\n", subsystem))
+        .collect();
+    response.push_str("OK\n");
+    Ok(response)
+}
+
+const ALL_SUBSYSTEMS: &[&str] = &["player", "mixer", "options", "playlist"];
+
+fn subsystems(event: &PlayerEvent) -> &'static [&'static str] {
+    match event {
+        PlayerEvent::TrackStarted(_) | PlayerEvent::TrackEnded(_) | PlayerEvent::Seeked(_) => {
+            &["player"]
+        }
+        // Pause, volume, repeat and shuffle all arrive as one event
+        PlayerEvent::StateChanged => &["player", "mixer", "options"],
+        PlayerEvent::QueueChanged => &["playlist"],
+    }
+}
+
+async fn execute(session: &mut Session, command: &str, args: &[String]) -> MpdResult {
+    match command {
+        "ping" => Ok(String::new()),
+        // Only ends an idle, on its own there is nothing to do
+        "noidle" => Ok(String::new()),
+        "commands" => Ok(COMMANDS
+            .iter()
+            .map(|c| format!("command: {}\n", c))
+            .collect()),
+        "notcommands" => Ok(String::new()),
+        "tagtypes" => Ok(TAG_TYPES
+            .iter()
+            .map(|t| format!("tagtype: {}\n", t))
+            .collect()),
+        "urlhandlers" => Ok(String::from("handler: file://\n")),
+        "decoders" => Ok(String::new()),
+        "outputs" => Ok(String::from(
+            "outputid: 0\noutputname: default\nplugin: rodio\noutputenabled: 1\n",
+        )),
+        "replay_gain_status" => Ok(String::from("replay_gain_mode: off\n")),
+        "status" => status().await,
+        "currentsong" => current_song().await,
+        "stats" => stats(session).await,
+
+        "play" | "playid" => play(args.first()).await,
+        "pause" => pause(args.first()).await,
+        "stop" => pause(Some(&String::from("1"))).await,
+        "next" => control(ControlRequest::Next).await,
+        "previous" => control(ControlRequest::Prev).await,
+        "seek" | "seekid" => {
+            let index = parse_index(arg(args, 0)?)?;
+            let position = parse_time(arg(args, 1)?)?;
+            let mut player = PLAYER.lock().await;
+            if index != player.queue_index() || player.current_song.is_none() {
+                player.play_index(index)?;
+            }
+            player.seek_to(position)?;
+            Ok(String::new())
+        }
+        "seekcur" => {
+            let time = arg(args, 0)?;
+            let position = parse_time(time.trim_start_matches(['+', '-']))?;
+            let player = PLAYER.lock().await;
+            let position = match time.chars().next() {
+                Some('+') => player.position() + position,
+                Some('-') => player.position().saturating_sub(position),
+                _ => position,
+            };
+            player.seek_to(position)?;
+            Ok(String::new())
+        }
+        "setvol" => {
+            let volume: f32 = parse(arg(args, 0)?)?;
+            control(ControlRequest::Volume {
+                volume: volume / 100.0,
+            })
+            .await
+        }
+        "volume" => {
+            let by: f32 = parse(arg(args, 0)?)?;
+            control(ControlRequest::AdjustVolume { by: by / 100.0 }).await
+        }
+        "getvol" => Ok(format!(
+            "volume: {}\n",
+            (PLAYER.lock().await.volume() * 100.0).round()
+        )),
+        // The queue always starts over at the end, single is the player's repeat
+        "repeat" => match arg(args, 0)?.as_str() {
+            "1" => Ok(String::new()),
+            _ => Err(MpdError::new(Ack::Arg, "The queue always repeats")),
+        },
+        "single" => {
+            PLAYER.lock().await.set_repeat(arg(args, 0)? != "0");
+            Ok(String::new())
+        }
+        "random" => {
+            PLAYER.lock().await.set_shuffle(arg(args, 0)? != "0");
+            Ok(String::new())
+        }
+        "consume" => match arg(args, 0)?.as_str() {
+            "0" => Ok(String::new()),
+            _ => Err(MpdError::new(Ack::Arg, "Consume mode is not supported")),
+        },
+        "shuffle" => control(ControlRequest::Shuffle).await,
+
+        "playlistinfo" | "playlistid" | "plchanges" => {
+            let player = PLAYER.lock().await;
+            let range = match (command, args.first()) {
+                ("plchanges", Some(version))
+                    if parse::<u32>(version)? == player.queue_version() =>
+                {
+                    0..0
+                }
+                ("plchanges", _) | (_, None) => 0..player.queue.len(),
+                (_, Some(range)) => parse_range(range, player.queue.len())?,
+            };
+            let mut response = String::new();
+            for index in range {
+                let song = player.queue.get(index).ok_or(AppError::OutOfRange(index))?;
+                response.push_str(&song_info(song, Some(index)).await);
+            }
+            Ok(response)
+        }
+        "plchangesposid" => {
+            let player = PLAYER.lock().await;
+            Ok((0..player.queue.len())
+                .map(|index| format!("cpos: {}\nId: {}\n", index, index))
+                .collect())
+        }
+        "add" | "addid" => {
+            let songs = load_songs(resolve_uri(arg(args, 0)?).await?).await?;
+            let mut player = PLAYER.lock().await;
+            player.push(songs);
+            let mut index = player.queue.len().saturating_sub(1);
+            if let Some(position) = args.get(1) {
+                let to = parse_index(position)?;
+                player.move_in_queue(index, to)?;
+                index = to;
+            }
+            match command {
+                "addid" => Ok(format!("Id: {}\n", index)),
+                _ => Ok(String::new()),
+            }
+        }
+        "delete" | "deleteid" => {
+            let mut player = PLAYER.lock().await;
+            let range = parse_range(arg(args, 0)?, player.queue.len())?;
+            for index in range.rev() {
+                player.remove_from_queue(index)?;
+            }
+            Ok(String::new())
+        }
+        "move" | "moveid" => {
+            let mut player = PLAYER.lock().await;
+            let range = parse_range(arg(args, 0)?, player.queue.len())?;
+            let to = parse_index(arg(args, 1)?)?;
+            if to + range.len() > player.queue.len() {
+                return Err(AppError::OutOfRange(to).into());
+            }
+            // Songs move one at a time, so the block keeps its order
+            if to <= range.start {
+                for (i, from) in range.enumerate() {
+                    player.move_in_queue(from, to + i)?;
+                }
+            } else {
+                for _ in range.clone() {
+                    player.move_in_queue(range.start, to + range.len() - 1)?;
+                }
+            }
+            Ok(String::new())
+        }
+        "clear" => {
+            PLAYER.lock().await.empty_queue();
+            Ok(String::new())
+        }
+
+        "list" => list(args).await,
+        "find" | "search" | "findadd" | "searchadd" | "count" => {
+            let exact = matches!(command, "find" | "findadd" | "count");
+            let filters = parse_filters(args, exact)?;
+            let songs: Vec<Song> = get_library_songs()
+                .await?
+                .into_iter()
+                .filter(|song| filters.iter().all(|filter| filter.matches(song)))
+                .collect();
+
+            match command {
+                "findadd" | "searchadd" => {
+                    PLAYER.lock().await.push(songs);
+                    Ok(String::new())
+                }
+                "count" => Ok(format!(
+                    "songs: {}\nplaytime: {}\n",
+                    songs.len(),
+                    songs.iter().map(|s| s.duration.as_secs()).sum::<u64>()
+                )),
+                _ => {
+                    let mut response = String::new();
+                    for song in &songs {
+                        response.push_str(&song_info(song, None).await);
+                    }
+                    Ok(response)
+                }
+            }
+        }
+        "lsinfo" => lsinfo(args.first().map(String::as_str).unwrap_or_default()).await,
+        "listall" | "listallinfo" => {
+            let dir = args.first().map(String::as_str).unwrap_or_default();
+            let songs = songs_under(dir).await?;
+            let mut response = String::new();
+            for song in &songs {
+                match command {
+                    "listall" => {
+                        let _ = writeln!(response, "file: {}", song_uri(&song.get_path()).await);
+                    }
+                    _ => response.push_str(&song_info(song, None).await),
+                }
+            }
+            Ok(response)
+        }
+        "update" | "rescan" => {
+            tokio::spawn(create_playlist_types());
+            Ok(String::from("updating_db: 1\n"))
+        }
+
+        "listplaylists" => {
+            let mut response = String::new();
+            for playlist in UserPlaylist::load_all()? {
+                let _ = writeln!(response, "playlist: {}", playlist.name);
+                let _ = writeln!(response, "Last-Modified: {}", rfc3339(playlist.modified));
+            }
+            Ok(response)
+        }
+        "listplaylist" | "listplaylistinfo" | "load" => {
+            let playlist = find_playlist(arg(args, 0)?)?;
+            let paths: Vec<PathBuf> = playlist
+                .songs
+                .iter()
+                .filter(|song| !song.missing)
+                .map(|song| song.path.clone())
+                .collect();
+            let mut response = String::new();
+            match command {
+                "load" => {
+                    let songs = load_songs(paths).await?;
+                    PLAYER.lock().await.push(songs);
+                }
+                "listplaylist" => {
+                    for path in paths {
+                        let _ = writeln!(response, "file: {}", song_uri(&path).await);
+                    }
+                }
+                _ => {
+                    let library = library_by_path().await?;
+                    for path in paths {
+                        match library.get(&path) {
+                            Some(song) => response.push_str(&song_info(song, None).await),
+                            None => {
+                                let _ = writeln!(response, "file: {}", song_uri(&path).await);
+                            }
+                        }
+                    }
+                }
+            }
+            Ok(response)
+        }
+
+        _ => Err(MpdError::new(
+            Ack::Unknown,
+            format!("unknown command \"{}\"", command),
+        )),
+    }
+}
+
+async fn control(request: ControlRequest) -> MpdResult {
+    handle_request(request).await?;
+    Ok(String::new())
+}
+
+async fn status() -> MpdResult {
+    let player = PLAYER.lock().await;
+    let state = match &player.current_song {
+        Some(_) if player.song_finished() => "stop",
+        Some(_) if player.song_paused() => "pause",
+        Some(_) => "play",
+        None => "stop",
+    };
+
+    let mut response = String::new();
+    let _ = writeln!(response, "volume: {}", (player.volume() * 100.0).round());
+    let _ = writeln!(response, "repeat: 1");
+    let _ = writeln!(response, "random: {}", player.is_shuffled as u8);
+    let _ = writeln!(response, "single: {}", player.repeat as u8);
+    let _ = writeln!(response, "consume: 0");
+    let _ = writeln!(response, "playlist: {}", player.queue_version());
+    let _ = writeln!(response, "playlistlength: {}", player.queue.len());
+    let _ = writeln!(response, "state: {}", state);
+    if let (Some(song), true) = (&player.current_song, state != "stop") {
+        let index = player.queue_index();
+        let elapsed = player.position().as_secs_f64();
+        let duration = song.duration.as_secs_f64();
+        let _ = writeln!(response, "song: {}\nsongid: {}", index, index);
+        if index + 1 < player.queue.len() {
+            let _ = writeln!(
+                response,
+                "nextsong: {}\nnextsongid: {}",
+                index + 1,
+                index + 1
+            );
+        }
+        let _ = writeln!(response, "time: {}:{}", elapsed as u64, duration as u64);
+        let _ = writeln!(
+            response,
+            "elapsed: {:.3}\nduration: {:.3}",
+            elapsed, duration
+        );
+    }
+    Ok(response)
+}
+
+async fn current_song() -> MpdResult {
+    let player = PLAYER.lock().await;
+    match &player.current_song {
+        Some(song) => Ok(song_info(song, Some(player.queue_index())).await),
+        None => Ok(String::new()),
+    }
+}
+
+async fn stats(session: &Session) -> MpdResult {
+    let songs = get_library_songs().await?;
+    let artists: BTreeSet<String> = songs.iter().flat_map(artists).collect();
+    let albums: BTreeSet<String> = songs.iter().map(|song| song.album_key()).collect();
+    let playtime: u64 = songs.iter().map(|song| song.duration.as_secs()).sum();
+    Ok(format!(
+        "artists: {}\nalbums: {}\nsongs: {}\nuptime: {}\nplaytime: 0\ndb_playtime: {}\n",
+        artists.len(),
+        albums.len(),
+        songs.len(),
+        session.started.elapsed().as_secs(),
+        playtime
+    ))
+}
+
+// Without a position it resumes, or starts the current song over once it has finished
+async fn play(index: Option<&String>) -> MpdResult {
+    let mut player = PLAYER.lock().await;
+    match index {
+        Some(index) => player.play_index(parse_index(index)?)?,
+        None if player.song_paused() && !player.song_finished() => player.pause_resume(),
+        None if player.song_finished() => {
+            let index = player.queue_index();
+            player.play_index(index)?;
+        }
+        None => {}
+    }
+    Ok(String::new())
+}
+
+async fn pause(state: Option<&String>) -> MpdResult {
+    let mut player = PLAYER.lock().await;
+    let paused = player.song_paused();
+    let toggle = match state.map(String::as_str) {
+        Some("1") => !paused,
+        Some("0") => paused,
+        _ => true,
+    };
+    if toggle && !player.song_finished() {
+        player.pause_resume();
+    }
+    Ok(String::new())
+}
+
+async fn list(args: &[String]) -> MpdResult {
+    let tag = arg(args, 0)?.to_lowercase();
+    let (args, group) = match args.iter().position(|a| a.eq_ignore_ascii_case("group")) {
+        Some(i) => (&args[1..i], args.get(i + 1).map(|g| g.to_lowercase())),
+        None => (&args[1..], None),
+    };
+    // `list album <artist>` is the old form of `list album artist <artist>`
+    let filters = match (tag.as_str(), args) {
+        ("album", [artist]) => vec![Filter::exact("artist", artist)],
+        _ => parse_filters(args, true)?,
+    };
+
+    let mut values = BTreeSet::new();
+    for song in get_library_songs().await? {
+        if !filters.iter().all(|filter| filter.matches(&song)) {
+            continue;
+        }
+        let groups = match &group {
+            Some(group) => tag_values(&song, group),
+            None => vec![String::new()],
+        };
+        for group_value in groups {
+            for value in tag_values(&song, &tag) {
+                values.insert((group_value.clone(), value));
+            }
+        }
+    }
+
+    let mut response = String::new();
+    let mut last_group = None;
+    for (group_value, value) in values {
+        if let Some(group) = &group {
+            if last_group.as_ref() != Some(&group_value) {
+                let _ = writeln!(response, "{}: {}", tag_name(group), group_value);
+                last_group = Some(group_value);
+            }
+        }
+        let _ = writeln!(response, "{}: {}", tag_name(&tag), value);
+    }
+    Ok(response)
+}
+
+async fn lsinfo(uri: &str) -> MpdResult {
+    let roots = CONFIG.lock().await.library_roots.clone();
+    let mut response = String::new();
+
+    // With several roots, each one shows up as a top level directory
+    if uri.is_empty() && roots.len() > 1 {
+        for root in &roots {
+            let _ = writeln!(response, "directory: {}", root_name(root));
+        }
+        return Ok(response);
+    }
+
+    let dir = resolve_path(uri).await?;
+    if dir.is_file() {
+        let library = library_by_path().await?;
+        return match library.get(&dir) {
+            Some(song) => Ok(song_info(song, None).await),
+            None => Err(MpdError::new(Ack::NoExist, "Not in the library")),
+        };
+    }
+
+    let mut entries: Vec<PathBuf> = std::fs::read_dir(&dir)
+        .map_err(AppError::from)?
+        .filter_map(|entry| entry.ok().map(|entry| entry.path()))
+        .collect();
+    entries.sort();
+    let library = library_by_path().await?;
+    for entry in entries {
+        if entry.is_dir() {
+            let _ = writeln!(response, "directory: {}", song_uri(&entry).await);
+        } else if let Some(song) = library.get(&entry) {
+            response.push_str(&song_info(song, None).await);
+        } else if is_audio_file(&entry) {
+            let _ = writeln!(response, "file: {}", song_uri(&entry).await);
+        }
+    }
+    Ok(response)
+}
+
+async fn songs_under(uri: &str) -> Result<Vec<Song>, MpdError> {
+    let songs = get_library_songs().await?;
+    if uri.is_empty() {
+        return Ok(songs);
+    }
+    let dir = resolve_path(uri).await?;
+    Ok(songs
+        .into_iter()
+        .filter(|song| song.get_path().starts_with(&dir))
+        .collect())
+}
+
+async fn library_by_path() -> Result<HashMap<PathBuf, Song>, AppError> {
+    Ok(get_library_songs()
+        .await?
+        .into_iter()
+        .map(|song| (song.get_path(), song))
+        .collect())
+}
+
+fn find_playlist(name: &str) -> Result<UserPlaylist, AppError> {
+    UserPlaylist::load_all()?
+        .into_iter()
+        .find(|playlist| playlist.name == name || playlist.id == name)
+        .ok_or(AppError::PlaylistNotFound(name.to_string()))
+}
+
+async fn song_info(song: &Song, position: Option<usize>) -> String {
+    let mut info = format!("file: {}\n", song_uri(&song.get_path()).await);
+    let mut field = |name: &str, value: Option<String>| {
+        if let Some(value) = value.filter(|v| !v.is_empty()) {
+            let _ = writeln!(info, "{}: {}", name, value.replace('\n', " "));
+        }
+    };
+    field("Title", song.title.clone());
+    for artist in artists(song) {
+        field("Artist", Some(artist));
+    }
+    field("Album", song.album.clone());
+    field("AlbumArtist", song.album_artist.clone());
+    field("Composer", song.composer.clone());
+    for genre in genres(song) {
+        field("Genre", Some(genre));
+    }
+    field("Date", song.year.map(|y| y.to_string()));
+    field("Track", song.track.map(|t| t.to_string()));
+    field("Disc", song.disc.map(|d| d.to_string()));
+    field("MUSICBRAINZ_TRACKID", song.musicbrainz.recording.clone());
+    field("Time", Some(song.duration.as_secs().to_string()));
+    field(
+        "duration",
+        Some(format!("{:.3}", song.duration.as_secs_f64())),
+    );
+    // Ids are queue positions, clients refetch the queue when it changes
+    field("Pos", position.map(|p| p.to_string()));
+    field("Id", position.map(|p| p.to_string()));
+    info
+}
+
+// Paths relative to their library root, prefixed with the root's name when there are several
+pub(crate) async fn song_uri(path: &Path) -> String {
+    let roots = CONFIG.lock().await.library_roots.clone();
+    for root in &roots {
+        if let Ok(relative) = path.strip_prefix(root) {
+            let relative = relative.display().to_string();
+            return match roots.len() {
+                1 => relative,
+                _ => format!("{}/{}", root_name(root), relative),
+            };
+        }
+    }
+    path.display().to_string()
+}
+
+fn root_name(root: &Path) -> String {
+    root.file_name()
+        .map(|name| name.to_string_lossy().to_string())
+        .unwrap_or_else(|| root.display().to_string())
+}
+
+// Only paths inside the library can be reached, whatever the client sends
+async fn resolve_path(uri: &str) -> Result<PathBuf, MpdError> {
+    let config = CONFIG.lock().await;
+    let uri = uri.strip_prefix("file://").unwrap_or(uri);
+    if Path::new(uri)
+        .components()
+        .any(|c| matches!(c, Component::ParentDir))
+    {
+        return Err(MpdError::new(Ack::Arg, "Malformed URI"));
+    }
+
+    let candidates: Vec<PathBuf> = match Path::new(uri).is_absolute() {
+        true => vec![PathBuf::from(uri)],
+        false => config
+            .library_roots
+            .iter()
+            .flat_map(|root| {
+                let by_name = uri
+                    .strip_prefix(&root_name(root))
+                    .map(|rest| root.join(rest.trim_start_matches('/')));
+                [Some(root.join(uri)), by_name]
+            })
+            .flatten()
+            .collect(),
+    };
+    candidates
+        .iter()
+        .find(|path| path.exists())
+        .map(|path| config.library_path(path))
+        .unwrap_or(Err(AppError::FileMissing(PathBuf::from(uri))))
+        .map_err(MpdError::from)
+}
+
+// A directory adds every song in it
+async fn resolve_uri(uri: &str) -> Result<Vec<PathBuf>, MpdError> {
+    let path = resolve_path(uri).await?;
+    match path.is_dir() {
+        true => Ok(folder_songs(&path, true)),
+        false => Ok(vec![path]),
+    }
+}
+
+// Reads every song before the queue is touched, so an unreadable one adds nothing
+async fn load_songs(paths: Vec<PathBuf>) -> Result<Vec<Song>, AppError> {
+    let mut songs = Vec::new();
+    for path in paths {
+        songs.push(Player::get_song_info(path).await?);
+    }
+    Ok(songs)
+}
+
+#[derive(Debug)]
+enum FilterOp {
+    Equals,
+    NotEquals,
+    Contains,
+    StartsWith,
+}
+
+#[derive(Debug)]
+struct Filter {
+    tag: String,
+    op: FilterOp,
+    value: String,
+    ignore_case: bool,
+}
+
+impl Filter {
+    fn exact(tag: &str, value: &str) -> Self {
+        Self {
+            tag: tag.to_lowercase(),
+            op: FilterOp::Equals,
+            value: value.to_string(),
+            ignore_case: false,
+        }
+    }
+
+    fn matches(&self, song: &Song) -> bool {
+        let values = match self.tag.as_str() {
+            "any" => [
+                "title",
+                "artist",
+                "album",
+                "albumartist",
+                "genre",
+                "composer",
+            ]
+            .iter()
+            .flat_map(|tag| tag_values(song, tag))
+            .collect(),
+            "file" | "base" => vec![song.get_path().display().to_string()],
+            tag => tag_values(song, tag),
+        };
+        let fold = |text: &str| match self.ignore_case {
+            true => text.to_lowercase(),
+            false => text.to_string(),
+        };
+        let expected = fold(&self.value);
+        let matched = |value: &String| {
+            let value = fold(value);
+            match self.op {
+                FilterOp::Equals | FilterOp::NotEquals => value == expected,
+                FilterOp::Contains => value.contains(&expected),
+                FilterOp::StartsWith => value.starts_with(&expected),
+            }
+        };
+        match self.op {
+            FilterOp::NotEquals => !values.iter().any(matched),
+            // `base` filters on a directory, so it matches everything below it
+            _ if self.tag == "base" => values.iter().any(|v| v.contains(&self.value)),
+            _ => values.iter().any(matched),
+        }
+    }
+}
+
+// Either `TAG VALUE [TAG VALUE...]` or an expression like `((artist == 'x') AND (album == 'y'))`
+fn parse_filters(args: &[String], exact: bool) -> Result<Vec<Filter>, MpdError> {
+    if let [expression] = args {
+        if expression.starts_with('(') {
+            let mut filters = vec![];
+            parse_expression(expression, exact, &mut filters)?;
+            return Ok(filters);
+        }
+    }
+    if !args.len().is_multiple_of(2) {
+        return Err(MpdError::new(
+            Ack::Arg,
+            "Incorrect number of filter arguments",
+        ));
+    }
+    Ok(args
+        .chunks(2)
+        .map(|pair| Filter {
+            tag: pair[0].to_lowercase(),
+            op: match exact {
+                true => FilterOp::Equals,
+                false => FilterOp::Contains,
+            },
+            value: pair[1].clone(),
+            ignore_case: !exact,
+        })
+        .collect())
+}
+
+fn parse_expression(text: &str, exact: bool, filters: &mut Vec<Filter>) -> Result<(), MpdError> {
+    let malformed = || MpdError::new(Ack::Arg, format!("Malformed filter: {}", text));
+    let inner = text
+        .trim()
+        .strip_prefix('(')
+        .and_then(|t| t.strip_suffix(')'))
+        .ok_or_else(malformed)?
+        .trim();
+
+    if inner.starts_with('(') {
+        for part in split_and(inner) {
+            parse_expression(part, exact, filters)?;
+        }
+        return Ok(());
+    }
+
+    let (tag, rest) = inner.split_once(' ').ok_or_else(malformed)?;
+    let (op, value) = rest.trim().split_once(' ').ok_or_else(malformed)?;
+    let op = match op {
+        "==" => FilterOp::Equals,
+        "!=" => FilterOp::NotEquals,
+        "contains" | "=~" => FilterOp::Contains,
+        "starts_with" => FilterOp::StartsWith,
+        _ => return Err(malformed()),
+    };
+    let value = tokenize(value.trim())?.pop().ok_or_else(malformed)?;
+    filters.push(Filter {
+        tag: tag.to_lowercase(),
+        op,
+        value,
+        ignore_case: !exact,
+    });
+    Ok(())
+}
+
+// Splits `(a) AND (b)` at the top level, quoted values may contain parentheses
+fn split_and(text: &str) -> Vec<&str> {
+    let mut parts = vec![];
+    let (mut depth, mut start, mut quote) = (0, 0, None);
+    let mut chars = text.char_indices();
+    while let Some((i, c)) = chars.next() {
+        match (c, quote) {
+            ('\\', Some(_)) => {
+                chars.next();
+            }
+            ('\'' | '"', None) => quote = Some(c),
+            (c, Some(q)) if c == q => quote = None,
+            ('(', None) => {
+                if depth == 0 {
+                    start = i;
+                }
+                depth += 1;
+            }
+            (')', None) => {
+                depth -= 1;
+                if depth == 0 {
+                    parts.push(&text[start..=i]);
+                }
+            }
+            _ => {}
+        }
+    }
+    parts
+}
+
+fn tag_values(song: &Song, tag: &str) -> Vec<String> {
+    match tag {
+        "title" => song.title.iter().cloned().collect(),
+        "artist" => artists(song),
+        "album" => song.album.iter().cloned().collect(),
+        "albumartist" => song
+            .album_artist
+            .clone()
+            .or(song.artist.clone())
+            .into_iter()
+            .collect(),
+        "genre" => genres(song),
+        "composer" => song.composer.iter().cloned().collect(),
+        "date" => song.year.iter().map(|y| y.to_string()).collect(),
+        "track" => song.track.iter().map(|t| t.to_string()).collect(),
+        "disc" => song.disc.iter().map(|d| d.to_string()).collect(),
+        "musicbrainz_trackid" => song.musicbrainz.recording.iter().cloned().collect(),
+        _ => vec![],
+    }
+}
+
+fn tag_name(tag: &str) -> &str {
+    TAG_TYPES
+        .iter()
+        .find(|name| name.eq_ignore_ascii_case(tag))
+        .copied()
+        .unwrap_or(tag)
+}
+
+fn artists(song: &Song) -> Vec<String> {
+    match song.artists.is_empty() {
+        true => song.artist.iter().cloned().collect(),
+        false => song.artists.clone(),
+    }
+}
+
+fn genres(song: &Song) -> Vec<String> {
+    match song.genres.is_empty() {
+        true => song.genre.iter().cloned().collect(),
+        false => song.genres.clone(),
+    }
+}
+
+// Arguments are separated by spaces, double quotes group them and backslashes escape
+fn tokenize(line: &str) -> Result<Vec<String>, MpdError> {
+    let mut args = vec![];
+    let mut chars = line.chars().peekable();
+    while let Some(&c) = chars.peek() {
+        if c.is_whitespace() {
+            chars.next();
+            continue;
+        }
+        let mut arg = String::new();
+        if c == '"' || c == '\'' {
+            chars.next();
+            loop {
+                match chars.next() {
+                    Some('\\') => arg.extend(chars.next()),
+                    Some(q) if q == c => break,
+                    Some(other) => arg.push(other),
+                    None => return Err(MpdError::new(Ack::Arg, "Missing closing quote")),
+                }
+            }
+        } else {
+            while let Some(&c) = chars.peek() {
+                if c.is_whitespace() {
+                    break;
+                }
+                arg.push(c);
+                chars.next();
+            }
+        }
+        args.push(arg);
+    }
+    Ok(args)
+}
+
+fn arg(args: &[String], index: usize) -> Result<&String, MpdError> {
+    args.get(index)
+        .ok_or(MpdError::new(Ack::Arg, "Missing argument"))
+}
+
+fn parse<T: std::str::FromStr>(text: &str) -> Result<T, MpdError> {
+    text.parse()
+        .map_err(|_| MpdError::new(Ack::Arg, format!("Invalid number: {}", text)))
+}
+
+fn parse_index(text: &str) -> Result<usize, MpdError> {
+    parse(text)
+}
+
+fn parse_time(text: &str) -> Result<Duration, MpdError> {
+    let seconds: f64 = parse(text)?;
+    Duration::try_from_secs_f64(seconds).map_err(|_| MpdError::new(Ack::Arg, "Invalid time"))
+}
+
+// `3` or `2:5`, an open end like `2:` goes to the end of the queue
+fn parse_range(text: &str, len: usize) -> Result<std::ops::Range<usize>, MpdError> {
+    let range = match text.split_once(':') {
+        Some((start, "")) => parse_index(start)?..len,
+        Some((start, end)) => parse_index(start)?..parse_index(end)?,
+        None => {
+            let index = parse_index(text)?;
+            index..index + 1
+        }
+    };
+    if range.start >= len || range.end > len || range.start >= range.end {
+        return Err(MpdError::new(Ack::Arg, "Bad song index"));
+    }
+    Ok(range)
+}
+
+fn rfc3339(timestamp: u64) -> String {
+    DateTime::<Utc>::from_timestamp(timestamp as i64, 0)
+        .unwrap_or_default()
+        .format("%Y-%m-%dT%H:%M:%SZ")
+        .to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::Config;
+    use tokio::io::{BufWriter, Lines};
+    use tokio::net::tcp::{OwnedReadHalf, OwnedWriteHalf};
+
+    struct Client {
+        lines: Lines<BufReader<OwnedReadHalf>>,
+        writer: BufWriter<OwnedWriteHalf>,
+    }
+
+    impl Client {
+        async fn connect(address: std::net::SocketAddr) -> (Self, String) {
+            let (reader, writer) = TcpStream::connect(address).await.unwrap().into_split();
+            let mut client = Self {
+                lines: BufReader::new(reader).lines(),
+                writer: BufWriter::new(writer),
+            };
+            let greeting = client.lines.next_line().await.unwrap().unwrap();
+            (client, greeting)
+        }
+
+        async fn send(&mut self, line: &str) {
+            self.writer
+                .write_all(format!("{}\n", line).as_bytes())
+                .await
+                .unwrap();
+            self.writer.flush().await.unwrap();
+        }
+
+        // Everything up to and including the closing OK or ACK line
+        async fn response(&mut self) -> Vec<String> {
+            let mut response = vec![];
+            while let Some(line) = self.lines.next_line().await.unwrap() {
+                let done = line == "OK" || line.starts_with("ACK ");
+                response.push(line);
+                if done {
+                    break;
+                }
+            }
+            response
+        }
+
+        async fn command(&mut self, line: &str) -> Vec<String> {
+            self.send(line).await;
+            self.response().await
+        }
+    }
+
+    // One second of silence as 16 bit mono PCM
+    fn write_wav(path: &Path) {
+        let samples = vec![0u8; 2 * 8000];
+        let mut wav = b"RIFF".to_vec();
+        wav.extend((36 + samples.len() as u32).to_le_bytes());
+        wav.extend(b"WAVEfmt ");
+        wav.extend(16u32.to_le_bytes());
+        wav.extend(1u16.to_le_bytes());
+        wav.extend(1u16.to_le_bytes());
+        wav.extend(8000u32.to_le_bytes());
+        wav.extend(16000u32.to_le_bytes());
+        wav.extend(2u16.to_le_bytes());
+        wav.extend(16u16.to_le_bytes());
+        wav.extend(b"data");
+        wav.extend((samples.len() as u32).to_le_bytes());
+        wav.extend(samples);
+        std::fs::write(path, wav).unwrap();
+    }
+
+    fn args(line: &str) -> Vec<String> {
+        tokenize(line).unwrap()
+    }
+
+    #[test]
+    fn tokenizes_quoted_and_escaped_arguments() {
+        assert_eq!(args("add one.wav"), ["add", "one.wav"]);
+        assert_eq!(
+            args("  find  title \"two words\" "),
+            ["find", "title", "two words"]
+        );
+        assert_eq!(
+            args(r#"find title "say \"hi\"""#),
+            ["find", "title", "say \"hi\""]
+        );
+        assert_eq!(args("find title 'it''s'"), ["find", "title", "it", "s"]);
+        assert_eq!(args(r"add back\slash"), ["add", r"back\slash"]);
+        assert!(args("").is_empty());
+        assert!(tokenize("add \"one.wav").is_err());
+    }
+
+    #[test]
+    fn parses_ranges_within_the_queue() {
+        assert_eq!(parse_range("3", 5).unwrap(), 3..4);
+        assert_eq!(parse_range("1:3", 5).unwrap(), 1..3);
+        assert_eq!(parse_range("2:", 5).unwrap(), 2..5);
+        for bad in ["5", "0:6", "3:3", "3:1", "-1", "x", "1:x"] {
+            assert!(parse_range(bad, 5).is_err(), "{}", bad);
+        }
+        assert!(parse_range("0", 0).is_err());
+    }
+
+    #[test]
+    fn parses_tag_value_pairs() {
+        let filters = parse_filters(&args("Artist Foo album \"Bar Baz\""), true).unwrap();
+        assert_eq!(filters.len(), 2);
+        assert_eq!(filters[0].tag, "artist");
+        assert_eq!(filters[1].value, "Bar Baz");
+        assert!(matches!(filters[0].op, FilterOp::Equals));
+        assert!(!filters[0].ignore_case);
+
+        let filters = parse_filters(&args("title foo"), false).unwrap();
+        assert!(matches!(filters[0].op, FilterOp::Contains));
+        assert!(filters[0].ignore_case);
+
+        assert!(parse_filters(&args("artist"), true).is_err());
+        assert!(parse_filters(&args("artist Foo album"), true).is_err());
+    }
+
+    #[test]
+    fn parses_filter_expressions() {
+        let expression = r#"((artist == 'A (live)') AND (album != "B") AND (title contains 'c'))"#;
+        let filters = parse_filters(&[expression.to_string()], true).unwrap();
+        assert_eq!(filters.len(), 3);
+        assert_eq!(filters[0].value, "A (live)");
+        assert!(matches!(filters[1].op, FilterOp::NotEquals));
+        assert_eq!(filters[1].value, "B");
+        assert!(matches!(filters[2].op, FilterOp::Contains));
+
+        let filters = parse_filters(&["(Genre starts_with 'Ro')".to_string()], false).unwrap();
+        assert_eq!(filters[0].tag, "genre");
+        assert!(matches!(filters[0].op, FilterOp::StartsWith));
+
+        for bad in [
+            "(artist)",
+            "(artist ~~ 'x')",
+            "(artist == 'x'",
+            "(artist == 'x)",
+        ] {
+            let mut filters = vec![];
+            assert!(
+                parse_expression(bad, true, &mut filters).is_err(),
+                "{}",
+                bad
+            );
+        }
+    }
+
+    #[test]
+    fn splits_at_top_level_and() {
+        assert_eq!(
+            split_and("(a == 'x') AND (b == ')')"),
+            ["(a == 'x')", "(b == ')')"]
+        );
+        assert_eq!(
+            split_and(r#"((a == 'x') AND (b == "\"(")) AND (c == 'z')"#),
+            [r#"((a == 'x') AND (b == "\"("))"#, "(c == 'z')"]
+        );
+        assert!(split_and("a == 'x'").is_empty());
+    }
+
+    // Needs an audio output for the player: cargo test -- --ignored mpd
+    #[tokio::test]
+    #[ignore]
+    async fn serves_mpd_clients() {
+        let dir = std::env::temp_dir().join(format!("bupl-mpd-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let dir = dir.canonicalize().unwrap();
+        for name in ["one", "two"] {
+            write_wav(&dir.join(format!("{}.wav", name)));
+        }
+        // Its own config directory, so the user's config.json is never read
+        std::env::set_var("XDG_CONFIG_HOME", &dir);
+        *CONFIG.lock().await = Config {
+            library_roots: vec![dir.clone()],
+            ..Config::default()
+        };
+        PLAYER.lock().await.empty_queue();
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        tokio::spawn(serve(listener, None));
+
+        let (mut client, greeting) = Client::connect(address).await;
+        assert_eq!(greeting, format!("OK MPD {}", PROTOCOL_VERSION));
+
+        let status = client.command("status").await;
+        assert!(status.contains(&String::from("state: stop")));
+        assert!(status.contains(&String::from("playlistlength: 0")));
+        assert_eq!(status.last().unwrap(), "OK");
+
+        assert_eq!(client.command("add one.wav").await, ["OK"]);
+        let queue = client.command("playlistinfo").await;
+        assert!(queue.contains(&String::from("file: one.wav")));
+        assert!(queue.contains(&String::from("Pos: 0")));
+
+        client.send("command_list_ok_begin").await;
+        client.send("add \"two.wav\"").await;
+        client.send("status").await;
+        client.send("command_list_end").await;
+        let response = client.response().await;
+        assert_eq!(response.first().unwrap(), "list_OK");
+        assert!(response.contains(&String::from("playlistlength: 2")));
+        assert_eq!(response[response.len() - 2..], ["list_OK", "OK"]);
+
+        // The adds above are reported right away
+        let changed = client.command("idle playlist").await;
+        assert_eq!(changed, ["changed: playlist", "OK"]);
+        client.send("idle").await;
+        client.send("noidle").await;
+        assert_eq!(client.response().await, ["OK"]);
+        assert_eq!(client.command("noidle").await, ["OK"]);
+
+        assert_eq!(
+            client.command("frobnicate").await,
+            ["ACK [5@0] {frobnicate} unknown command \"frobnicate\""]
+        );
+
+        PLAYER.lock().await.empty_queue();
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+}
@@ -0,0 +1,632 @@
+use std::collections::HashMap;
+use std::path::PathBuf;
+use std::time::Duration;
+use tokio::sync::broadcast::error::RecvError;
+use zbus::fdo;
+use zbus::interface;
+use zbus::object_server::SignalContext;
+use zbus::zvariant::{ObjectPath, OwnedObjectPath, OwnedValue, Value};
+use zbus::Connection;
+
+use crate::error::AppError;
+use crate::player::{Player, PlayerEvent, PLAYER_EVENTS};
+use crate::song::Song;
+use crate::PLAYER;
+
+const BUS_NAME: &str = "org.mpris.MediaPlayer2.bupl";
+const OBJECT_PATH: &str = "/org/mpris/MediaPlayer2";
+const NO_TRACK: &str = "/org/mpris/MediaPlayer2/TrackList/NoTrack";
+
+type Metadata = HashMap<String, OwnedValue>;
+
+struct MediaPlayer2;
+struct MprisPlayer;
+struct TrackList;
+
+#[interface(name = "org.mpris.MediaPlayer2")]
+impl MediaPlayer2 {
+    fn raise(&self) {}
+
+    fn quit(&self) {}
+
+    #[zbus(property)]
+    fn can_quit(&self) -> bool {
+        false
+    }
+
+    #[zbus(property)]
+    fn can_raise(&self) -> bool {
+        false
+    }
+
+    #[zbus(property)]
+    fn has_track_list(&self) -> bool {
+        true
+    }
+
+    #[zbus(property)]
+    fn identity(&self) -> String {
+        String::from("bupl")
+    }
+
+    #[zbus(property)]
+    fn desktop_entry(&self) -> String {
+        String::from("bupl")
+    }
+
+    #[zbus(property)]
+    fn supported_uri_schemes(&self) -> Vec<String> {
+        vec![String::from("file")]
+    }
+
+    #[zbus(property)]
+    fn supported_mime_types(&self) -> Vec<String> {
+        [
+            "audio/mpeg",
+            "audio/ogg",
+            "audio/wav",
+            "audio/flac",
+            "audio/aac",
+            "audio/mp4",
+        ]
+        .map(String::from)
+        .to_vec()
+    }
+}
+
+#[interface(name = "org.mpris.MediaPlayer2.Player")]
+impl MprisPlayer {
+    async fn next(&self) -> fdo::Result<()> {
+        PLAYER.lock().await.skip(1).map_err(failed)
+    }
+
+    async fn previous(&self) -> fdo::Result<()> {
+        PLAYER.lock().await.skip(-1).map_err(failed)
+    }
+
+    async fn pause(&self) {
+        let mut player = PLAYER.lock().await;
+        if !player.song_paused() {
+            player.pause_resume();
+        }
+    }
+
+    async fn play_pause(&self) -> fdo::Result<()> {
+        self.play_or_resume(true).await
+    }
+
+    async fn stop(&self) {
+        self.pause().await
+    }
+
+    async fn play(&self) -> fdo::Result<()> {
+        self.play_or_resume(false).await
+    }
+
+    // Offsets are in microseconds and relative to the current position
+    async fn seek(&self, offset: i64) -> fdo::Result<()> {
+        let player = PLAYER.lock().await;
+        let position = player.position().as_micros() as i64 + offset;
+        if position < 0 {
+            return player.seek_to(Duration::ZERO).map_err(failed);
+        }
+        let duration = player.current_song.clone().unwrap_or_default().duration;
+        if position as u128 > duration.as_micros() {
+            drop(player);
+            return self.next().await;
+        }
+        player
+            .seek_to(Duration::from_micros(position as u64))
+            .map_err(failed)
+    }
+
+    async fn set_position(&self, track_id: ObjectPath<'_>, position: i64) -> fdo::Result<()> {
+        let player = PLAYER.lock().await;
+        let duration = player.current_song.clone().unwrap_or_default().duration;
+        if track_id.as_str() != track_path(player.queue_index()).as_str()
+            || position < 0
+            || position as u128 > duration.as_micros()
+        {
+            return Ok(());
+        }
+        player
+            .seek_to(Duration::from_micros(position as u64))
+            .map_err(failed)
+    }
+
+    async fn open_uri(&self, uri: String) -> fdo::Result<()> {
+        let path = file_uri_path(&uri).ok_or(fdo::Error::InvalidArgs(uri))?;
+        let song = Player::get_song_info(path)
+            .await
+            .map_err(|e| failed(e.into()))?;
+        let mut player = PLAYER.lock().await;
+        player.push(vec![song]);
+        let last = player.queue.len() - 1;
+        player.play_index(last).map_err(failed)
+    }
+
+    #[zbus(signal)]
+    async fn seeked(ctxt: &SignalContext<'_>, position: i64) -> zbus::Result<()>;
+
+    #[zbus(property)]
+    async fn playback_status(&self) -> String {
+        let player = PLAYER.lock().await;
+        let status = match (
+            player.current_song.is_some(),
+            player.song_finished(),
+            player.song_paused(),
+        ) {
+            (false, _, _) | (_, true, _) => "Stopped",
+            (_, _, true) => "Paused",
+            _ => "Playing",
+        };
+        String::from(status)
+    }
+
+    // Repeat plays the current song again, otherwise the queue goes on
+    #[zbus(property)]
+    async fn loop_status(&self) -> String {
+        let status = if PLAYER.lock().await.repeat {
+            "Track"
+        } else {
+            "Playlist"
+        };
+        String::from(status)
+    }
+
+    #[zbus(property)]
+    async fn set_loop_status(&self, status: String) {
+        PLAYER.lock().await.set_repeat(status == "Track");
+    }
+
+    #[zbus(property)]
+    fn rate(&self) -> f64 {
+        1.0
+    }
+
+    #[zbus(property)]
+    fn set_rate(&self, _rate: f64) {}
+
+    #[zbus(property)]
+    fn minimum_rate(&self) -> f64 {
+        1.0
+    }
+
+    #[zbus(property)]
+    fn maximum_rate(&self) -> f64 {
+        1.0
+    }
+
+    #[zbus(property)]
+    async fn shuffle(&self) -> bool {
+        PLAYER.lock().await.is_shuffled
+    }
+
+    #[zbus(property)]
+    async fn set_shuffle(&self, shuffle: bool) {
+        PLAYER.lock().await.set_shuffle(shuffle);
+    }
+
+    #[zbus(property)]
+    async fn metadata(&self) -> Metadata {
+        let player = PLAYER.lock().await;
+        match &player.current_song {
+            Some(song) => metadata(song, player.queue_index()),
+            None => Metadata::new(),
+        }
+    }
+
+    #[zbus(property)]
+    async fn volume(&self) -> f64 {
+        PLAYER.lock().await.volume() as f64
+    }
+
+    #[zbus(property)]
+    async fn set_volume(&self, volume: f64) {
+        PLAYER.lock().await.set_volume(volume as f32);
+    }
+
+    // Read on demand, clients interpolate between Seeked signals
+    #[zbus(property(emits_changed_signal = "false"))]
+    async fn position(&self) -> i64 {
+        PLAYER.lock().await.position().as_micros() as i64
+    }
+
+    #[zbus(property)]
+    async fn can_go_next(&self) -> bool {
+        let player = PLAYER.lock().await;
+        player.queue_index() + 1 < player.queue.len()
+    }
+
+    #[zbus(property)]
+    async fn can_go_previous(&self) -> bool {
+        PLAYER.lock().await.queue_index() > 0
+    }
+
+    #[zbus(property)]
+    async fn can_play(&self) -> bool {
+        !PLAYER.lock().await.queue.is_empty()
+    }
+
+    #[zbus(property)]
+    async fn can_pause(&self) -> bool {
+        PLAYER.lock().await.current_song.is_some()
+    }
+
+    #[zbus(property)]
+    async fn can_seek(&self) -> bool {
+        PLAYER.lock().await.current_song.is_some()
+    }
+
+    #[zbus(property(emits_changed_signal = "const"))]
+    fn can_control(&self) -> bool {
+        true
+    }
+}
+
+impl MprisPlayer {
+    // Starts the queue over once it has finished, like the play button does
+    async fn play_or_resume(&self, toggle: bool) -> fdo::Result<()> {
+        let mut player = PLAYER.lock().await;
+        if player.song_finished() {
+            let first_song = player.queue.first().ok_or(failed(AppError::QueueEmpty))?;
+            let path = first_song.get_path();
+            return player.play(path).map_err(failed);
+        }
+        if toggle || player.song_paused() {
+            player.pause_resume();
+        }
+        Ok(())
+    }
+}
+
+// Track ids are the position in the queue
+#[interface(name = "org.mpris.MediaPlayer2.TrackList")]
+impl TrackList {
+    async fn get_tracks_metadata(&self, track_ids: Vec<OwnedObjectPath>) -> Vec<Metadata> {
+        let player = PLAYER.lock().await;
+        track_ids
+            .iter()
+            .filter_map(|id| track_index(id.as_str()))
+            .filter_map(|index| Some(metadata(player.queue.get(index)?, index)))
+            .collect()
+    }
+
+    async fn add_track(
+        &self,
+        uri: String,
+        after_track: ObjectPath<'_>,
+        set_as_current: bool,
+        #[zbus(signal_context)] ctxt: SignalContext<'_>,
+    ) -> fdo::Result<()> {
+        let path = file_uri_path(&uri).ok_or(fdo::Error::InvalidArgs(uri))?;
+        let song = Player::get_song_info(path)
+            .await
+            .map_err(|e| failed(e.into()))?;
+        let mut player = PLAYER.lock().await;
+        player.push(vec![song]);
+
+        // Songs are appended, then moved after the requested track
+        let mut index = player.queue.len() - 1;
+        if let Some(after) = track_index(after_track.as_str()).filter(|after| after + 1 < index) {
+            player.move_in_queue(index, after + 1).map_err(failed)?;
+            index = after + 1;
+        } else if after_track.as_str() == NO_TRACK && index > 0 {
+            player.move_in_queue(index, 0).map_err(failed)?;
+            index = 0;
+        }
+        if set_as_current {
+            player.play_index(index).map_err(failed)?;
+        }
+        let tracks = track_paths(player.queue.len());
+        drop(player);
+        Self::track_list_replaced(&ctxt, tracks, track_path_or_none(index)).await?;
+        Ok(())
+    }
+
+    async fn remove_track(
+        &self,
+        track_id: ObjectPath<'_>,
+        #[zbus(signal_context)] ctxt: SignalContext<'_>,
+    ) -> fdo::Result<()> {
+        let index =
+            track_index(track_id.as_str()).ok_or(fdo::Error::InvalidArgs(track_id.to_string()))?;
+        let mut player = PLAYER.lock().await;
+        if index == player.queue_index() && player.current_song.is_some() {
+            return Err(fdo::Error::Failed(String::from(
+                "Can't remove the current track",
+            )));
+        }
+        player.remove_from_queue(index).map_err(failed)?;
+        let tracks = track_paths(player.queue.len());
+        let current = track_path_or_none(player.queue_index());
+        drop(player);
+        Self::track_list_replaced(&ctxt, tracks, current).await?;
+        Ok(())
+    }
+
+    async fn go_to(&self, track_id: ObjectPath<'_>) -> fdo::Result<()> {
+        let index =
+            track_index(track_id.as_str()).ok_or(fdo::Error::InvalidArgs(track_id.to_string()))?;
+        PLAYER.lock().await.play_index(index).map_err(failed)
+    }
+
+    #[zbus(signal)]
+    async fn track_list_replaced(
+        ctxt: &SignalContext<'_>,
+        tracks: Vec<OwnedObjectPath>,
+        current_track: OwnedObjectPath,
+    ) -> zbus::Result<()>;
+
+    #[zbus(property(emits_changed_signal = "invalidates"))]
+    async fn tracks(&self) -> Vec<OwnedObjectPath> {
+        track_paths(PLAYER.lock().await.queue.len())
+    }
+
+    #[zbus(property)]
+    fn can_edit_tracks(&self) -> bool {
+        true
+    }
+}
+
+// Serves the player on the session bus (DBUS_SESSION_BUS_ADDRESS) and keeps clients in sync
+pub async fn run_mpris() {
+    if let Err(e) = serve().await {
+        println!("MPRIS unavailable: {}", e);
+    }
+}
+
+async fn serve() -> zbus::Result<()> {
+    let connection = zbus::connection::Builder::session()?
+        .name(BUS_NAME)?
+        .serve_at(OBJECT_PATH, MediaPlayer2)?
+        .serve_at(OBJECT_PATH, MprisPlayer)?
+        .serve_at(OBJECT_PATH, TrackList)?
+        .build()
+        .await?;
+
+    let mut events = PLAYER_EVENTS.subscribe();
+    loop {
+        match events.recv().await {
+            Ok(PlayerEvent::Seeked(position)) => {
+                let ctxt = SignalContext::new(&connection, OBJECT_PATH)?;
+                MprisPlayer::seeked(&ctxt, position.as_micros() as i64).await?;
+            }
+            Ok(PlayerEvent::TrackStarted(_) | PlayerEvent::QueueChanged) => {
+                properties_changed(&connection, true).await?;
+            }
+            Ok(_) | Err(RecvError::Lagged(_)) => {
+                properties_changed(&connection, false).await?;
+            }
+            Err(RecvError::Closed) => return Ok(()),
+        }
+    }
+}
+
+async fn properties_changed(connection: &Connection, track_changed: bool) -> zbus::Result<()> {
+    let server = connection.object_server();
+    let player = server.interface::<_, MprisPlayer>(OBJECT_PATH).await?;
+    let ctxt = player.signal_context();
+    let player = player.get().await;
+
+    player.playback_status_changed(ctxt).await?;
+    player.loop_status_changed(ctxt).await?;
+    player.shuffle_changed(ctxt).await?;
+    player.volume_changed(ctxt).await?;
+    if track_changed {
+        player.metadata_changed(ctxt).await?;
+        player.can_go_next_changed(ctxt).await?;
+        player.can_go_previous_changed(ctxt).await?;
+        player.can_play_changed(ctxt).await?;
+        player.can_pause_changed(ctxt).await?;
+        player.can_seek_changed(ctxt).await?;
+
+        let track_list = server.interface::<_, TrackList>(OBJECT_PATH).await?;
+        track_list
+            .get()
+            .await
+            .tracks_invalidate(track_list.signal_context())
+            .await?;
+    }
+    Ok(())
+}
+
+fn metadata(song: &Song, index: usize) -> Metadata {
+    let mut metadata = Metadata::new();
+    let mut insert = |key: &str, value: Value<'_>| {
+        if let Ok(value) = OwnedValue::try_from(value) {
+            metadata.insert(key.to_string(), value);
+        }
+    };
+
+    insert("mpris:trackid", Value::from(track_path(index)));
+    insert(
+        "mpris:length",
+        Value::from(song.duration.as_micros() as i64),
+    );
+    insert("xesam:url", Value::from(file_uri(&song.get_path())));
+    let cover_path = song.get_cover_path();
+    if cover_path.is_file() {
+        insert("mpris:artUrl", Value::from(file_uri(&cover_path)));
+    }
+    if let Some(title) = &song.title {
+        insert("xesam:title", Value::from(title.as_str()));
+    }
+    if let Some(album) = &song.album {
+        insert("xesam:album", Value::from(album.as_str()));
+    }
+    let artists = match song.artists.is_empty() {
+        true => song.artist.iter().cloned().collect(),
+        false => song.artists.clone(),
+    };
+    insert("xesam:artist", Value::from(artists));
+    if let Some(album_artist) = &song.album_artist {
+        insert("xesam:albumArtist", Value::from(vec![album_artist.clone()]));
+    }
+    if let Some(composer) = &song.composer {
+        insert("xesam:composer", Value::from(vec![composer.clone()]));
+    }
+    if !song.genres.is_empty() {
+        insert("xesam:genre", Value::from(song.genres.clone()));
+    }
+    if let Some(track) = song.track {
+        insert("xesam:trackNumber", Value::from(track as i32));
+    }
+    if let Some(disc) = song.disc {
+        insert("xesam:discNumber", Value::from(disc as i32));
+    }
+    metadata
+}
+
+fn track_path(index: usize) -> OwnedObjectPath {
+    let path = format!("/org/bupl/track/{}", index);
+    ObjectPath::try_from(path)
+        .map(OwnedObjectPath::from)
+        .unwrap_or_else(|_| track_path_or_none(usize::MAX))
+}
+
+fn track_path_or_none(index: usize) -> OwnedObjectPath {
+    match index {
+        usize::MAX => OwnedObjectPath::from(ObjectPath::from_static_str_unchecked(NO_TRACK)),
+        index => track_path(index),
+    }
+}
+
+fn track_paths(len: usize) -> Vec<OwnedObjectPath> {
+    (0..len).map(track_path).collect()
+}
+
+fn track_index(path: &str) -> Option<usize> {
+    path.strip_prefix("/org/bupl/track/")?.parse().ok()
+}
+
+fn file_uri(path: &std::path::Path) -> String {
+    format!(
+        "file://{}",
+        crate::playlist_format::percent_encode(&path.display().to_string())
+    )
+}
+
+fn file_uri_path(uri: &str) -> Option<PathBuf> {
+    let path = uri.strip_prefix("file://")?;
+    Some(PathBuf::from(crate::playlist_format::percent_decode(path)))
+}
+
+fn failed(e: AppError) -> fdo::Error {
+    fdo::Error::Failed(e.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::{Path, PathBuf};
+    use std::process::Stdio;
+    use std::time::Duration;
+    use tokio::io::{AsyncBufReadExt, BufReader};
+    use zbus::fdo::{DBusProxy, PropertiesProxy};
+    use zbus::names::InterfaceName;
+
+    const PLAYER_INTERFACE: &str = "org.mpris.MediaPlayer2.Player";
+
+    // One second of silence as 16 bit mono PCM
+    fn write_wav(path: &Path) {
+        let samples = vec![0u8; 2 * 8000];
+        let mut wav = b"RIFF".to_vec();
+        wav.extend((36 + samples.len() as u32).to_le_bytes());
+        wav.extend(b"WAVEfmt ");
+        wav.extend(16u32.to_le_bytes());
+        wav.extend(1u16.to_le_bytes());
+        wav.extend(1u16.to_le_bytes());
+        wav.extend(8000u32.to_le_bytes());
+        wav.extend(16000u32.to_le_bytes());
+        wav.extend(2u16.to_le_bytes());
+        wav.extend(16u16.to_le_bytes());
+        wav.extend(b"data");
+        wav.extend((samples.len() as u32).to_le_bytes());
+        wav.extend(samples);
+        std::fs::write(path, wav).unwrap();
+    }
+
+    async fn property(properties: &PropertiesProxy<'_>, name: &str) -> OwnedValue {
+        let interface = InterfaceName::from_static_str(PLAYER_INTERFACE).unwrap();
+        properties.get(interface, name).await.unwrap()
+    }
+
+    // Needs dbus-daemon and an audio output: cargo test -- --ignored mpris
+    #[tokio::test]
+    #[ignore]
+    async fn serves_mpris_on_a_private_bus() {
+        let mut daemon = tokio::process::Command::new("dbus-daemon")
+            .args(["--session", "--nofork", "--print-address"])
+            .stdout(Stdio::piped())
+            .kill_on_drop(true)
+            .spawn()
+            .expect("dbus-daemon is installed");
+        let mut address = String::new();
+        BufReader::new(daemon.stdout.take().unwrap())
+            .read_line(&mut address)
+            .await
+            .unwrap();
+        let address = address.trim().to_string();
+        std::env::set_var("DBUS_SESSION_BUS_ADDRESS", &address);
+
+        let dir = std::env::temp_dir().join(format!("bupl-mpris-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let songs: Vec<PathBuf> = ["one", "two"]
+            .iter()
+            .map(|name| dir.join(format!("{}.wav", name)))
+            .collect();
+        for song in &songs {
+            write_wav(song);
+            let song = Player::get_song_info(song.clone()).await.unwrap();
+            PLAYER.lock().await.push(vec![song]);
+        }
+        tokio::spawn(serve());
+
+        let connection = zbus::connection::Builder::address(address.as_str())
+            .unwrap()
+            .build()
+            .await
+            .unwrap();
+        let dbus = DBusProxy::new(&connection).await.unwrap();
+        let mut owned = false;
+        for _ in 0..50 {
+            owned = dbus
+                .name_has_owner(BUS_NAME.try_into().unwrap())
+                .await
+                .unwrap();
+            if owned {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(100)).await;
+        }
+        assert!(owned, "{} is owned", BUS_NAME);
+
+        let player = zbus::Proxy::new(&connection, BUS_NAME, OBJECT_PATH, PLAYER_INTERFACE)
+            .await
+            .unwrap();
+        let properties = PropertiesProxy::builder(&connection)
+            .destination(BUS_NAME)
+            .unwrap()
+            .path(OBJECT_PATH)
+            .unwrap()
+            .build()
+            .await
+            .unwrap();
+
+        player.call_method("PlayPause", &()).await.unwrap();
+        let status = property(&properties, "PlaybackStatus").await;
+        assert_eq!(String::try_from(status).unwrap(), "Playing");
+
+        player.call_method("Next", &()).await.unwrap();
+        let metadata = Metadata::try_from(property(&properties, "Metadata").await).unwrap();
+        let title = metadata["xesam:title"].try_clone().unwrap();
+        assert_eq!(String::try_from(title).unwrap(), "two");
+
+        player.call_method("PlayPause", &()).await.unwrap();
+        let status = property(&properties, "PlaybackStatus").await;
+        assert_eq!(String::try_from(status).unwrap(), "Paused");
+
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+}
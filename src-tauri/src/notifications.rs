@@ -0,0 +1,232 @@
+use serde::{Deserialize, Serialize};
+use tokio::sync::broadcast::error::RecvError;
+
+use crate::config::CONFIG;
+use crate::player::{PlayerEvent, PLAYER_EVENTS};
+use crate::song::Song;
+use crate::APP_NAME;
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct NotificationSettings {
+    pub enabled: bool,
+    // Seconds, 0 leaves it to the notification server
+    pub timeout: u32,
+    pub suppress_when_focused: bool,
+    // Next and Pause buttons, where the notification server supports them
+    pub actions: bool,
+}
+
+impl Default for NotificationSettings {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            timeout: 5,
+            suppress_when_focused: true,
+            actions: true,
+        }
+    }
+}
+
+// Runs where the player is, `focused` tells whether the window is in front
+pub async fn run_notifications<F: Fn() -> bool>(focused: F) {
+    if let Err(e) = serve(focused).await {
+        println!("Notifications unavailable: {}", e);
+    }
+}
+
+// None when notifications are off, or the window is focused and they are suppressed
+async fn active_settings(focused: &impl Fn() -> bool) -> Option<NotificationSettings> {
+    let settings = CONFIG.lock().await.notifications.clone();
+    (settings.enabled && !(settings.suppress_when_focused && focused())).then_some(settings)
+}
+
+// Milliseconds as the notification servers take them
+fn timeout_millis(settings: &NotificationSettings) -> u32 {
+    settings.timeout.saturating_mul(1000)
+}
+
+// The title, then the artist and album on their own lines
+fn summary_and_body(song: &Song) -> (String, String) {
+    let summary = song.title.clone().unwrap_or_else(|| {
+        song.get_path()
+            .file_stem()
+            .map(|stem| stem.to_string_lossy().to_string())
+            .unwrap_or_default()
+    });
+    let body = [&song.artist, &song.album]
+        .into_iter()
+        .flatten()
+        .cloned()
+        .collect::<Vec<_>>()
+        .join("\n");
+    (summary, body)
+}
+
+#[cfg(target_os = "linux")]
+use linux::serve;
+
+#[cfg(target_os = "linux")]
+mod linux {
+    use futures_util::StreamExt;
+    use std::collections::HashMap;
+    use zbus::zvariant::Value;
+    use zbus::{proxy, Connection};
+
+    use super::*;
+    use crate::control::{dispatch, ControlRequest};
+
+    #[proxy(
+        interface = "org.freedesktop.Notifications",
+        default_service = "org.freedesktop.Notifications",
+        default_path = "/org/freedesktop/Notifications"
+    )]
+    trait Notifications {
+        #[allow(clippy::too_many_arguments)]
+        fn notify(
+            &self,
+            app_name: &str,
+            replaces_id: u32,
+            app_icon: &str,
+            summary: &str,
+            body: &str,
+            actions: &[&str],
+            hints: HashMap<&str, Value<'_>>,
+            expire_timeout: i32,
+        ) -> zbus::Result<u32>;
+
+        fn get_capabilities(&self) -> zbus::Result<Vec<String>>;
+
+        #[zbus(signal)]
+        fn action_invoked(&self, id: u32, action_key: String) -> zbus::Result<()>;
+    }
+
+    pub async fn serve<F: Fn() -> bool>(focused: F) -> zbus::Result<()> {
+        let connection = Connection::session().await?;
+        let proxy = NotificationsProxy::new(&connection).await?;
+        let capabilities = proxy.get_capabilities().await?;
+        let mut actions = proxy.receive_action_invoked().await?;
+        let mut events = PLAYER_EVENTS.subscribe();
+
+        // Each song replaces the last notification, so only its buttons are handled
+        let mut current = 0;
+        loop {
+            tokio::select! {
+                event = events.recv() => match event {
+                    Ok(PlayerEvent::TrackStarted(song)) => {
+                        let Some(settings) = active_settings(&focused).await else {
+                            continue;
+                        };
+                        match notify(&proxy, &capabilities, current, &song, &settings).await {
+                            Ok(id) => current = id,
+                            Err(e) => println!("Could not show notification: {}", e),
+                        }
+                    }
+                    Ok(_) | Err(RecvError::Lagged(_)) => {}
+                    Err(RecvError::Closed) => return Ok(()),
+                },
+                Some(signal) = actions.next() => {
+                    let args = match signal.args() {
+                        Ok(args) => args,
+                        Err(e) => {
+                            println!("Could not read notification action: {}", e);
+                            continue;
+                        }
+                    };
+                    let request = match args.action_key().as_str() {
+                        "next" => ControlRequest::Next,
+                        "pause" => ControlRequest::Pause,
+                        _ => continue,
+                    };
+                    if *args.id() != current {
+                        continue;
+                    }
+                    if let Err(e) = dispatch(request).await {
+                        println!("Notification action failed: {}", e);
+                    }
+                }
+            }
+        }
+    }
+
+    async fn notify(
+        proxy: &NotificationsProxy<'_>,
+        capabilities: &[String],
+        replaces_id: u32,
+        song: &Song,
+        settings: &NotificationSettings,
+    ) -> zbus::Result<u32> {
+        let supports = |capability: &str| capabilities.iter().any(|c| c == capability);
+        let (summary, mut body) = summary_and_body(song);
+        if supports("body-markup") {
+            body = body
+                .replace('&', "&amp;")
+                .replace('<', "&lt;")
+                .replace('>', "&gt;");
+        }
+        let actions = match settings.actions && supports("actions") {
+            true => vec!["next", "Next", "pause", "Pause"],
+            false => vec![],
+        };
+
+        // Transient so a notification per song doesn't fill up the history
+        let mut hints = HashMap::from([("transient", Value::from(true))]);
+        let cover = song.get_cover_path();
+        if cover.is_file() {
+            hints.insert("image-path", Value::from(cover.display().to_string()));
+        }
+        let timeout = match timeout_millis(settings) {
+            0 => -1,
+            millis => millis.min(i32::MAX as u32) as i32,
+        };
+        proxy
+            .notify(
+                APP_NAME.as_str(),
+                replaces_id,
+                "",
+                &summary,
+                &body,
+                &actions,
+                hints,
+                timeout,
+            )
+            .await
+    }
+}
+
+// Without the freedesktop server there are no buttons, just the song
+#[cfg(not(target_os = "linux"))]
+async fn serve<F: Fn() -> bool>(focused: F) -> Result<(), crate::error::AppError> {
+    let mut events = PLAYER_EVENTS.subscribe();
+    loop {
+        let song = match events.recv().await {
+            Ok(PlayerEvent::TrackStarted(song)) => song,
+            Ok(_) | Err(RecvError::Lagged(_)) => continue,
+            Err(RecvError::Closed) => return Ok(()),
+        };
+        let Some(settings) = active_settings(&focused).await else {
+            continue;
+        };
+        let (summary, body) = summary_and_body(&song);
+        let cover = song.get_cover_path();
+        let shown = tokio::task::spawn_blocking(move || {
+            let mut notification = notify_rust::Notification::new();
+            notification
+                .appname(APP_NAME.as_str())
+                .summary(&summary)
+                .body(&body);
+            if settings.timeout > 0 {
+                let timeout = notify_rust::Timeout::Milliseconds(timeout_millis(&settings));
+                notification.timeout(timeout);
+            }
+            if cover.is_file() {
+                notification.image_path(&cover.display().to_string());
+            }
+            notification.show().map(|_| ())
+        })
+        .await;
+        if let Ok(Err(e)) = shown {
+            println!("Could not show notification: {}", e);
+        }
+    }
+}
@@ -0,0 +1,144 @@
+use serde::Serialize;
+use std::path::Path;
+
+use crate::error::AppError;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Field {
+    Artist,
+    Album,
+    Title,
+    Genre,
+    Year,
+    Track,
+    Ignore,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Token {
+    Literal(String),
+    Field(Field),
+}
+
+#[derive(Debug, Default, Clone, Serialize)]
+pub struct PathFields {
+    pub title: Option<String>,
+    pub artist: Option<String>,
+    pub album: Option<String>,
+    pub genre: Option<String>,
+    pub year: Option<u32>,
+    pub track: Option<u32>,
+}
+
+// A pattern like `{artist}/{year} - {album}/{track} - {title}`, matched
+// against the last components of a song path with the extension removed
+#[derive(Debug, Clone)]
+pub struct PathPattern {
+    segments: Vec<Vec<Token>>,
+}
+
+impl PathPattern {
+    pub fn parse(pattern: &str) -> Result<Self, AppError> {
+        let invalid = |reason: &str| AppError::InvalidPattern(format!("{}: {}", pattern, reason));
+        let mut segments = vec![];
+
+        for segment in pattern.trim_matches('/').split('/') {
+            let mut tokens = vec![];
+            let mut rest = segment;
+
+            while !rest.is_empty() {
+                if let Some(inner) = rest.strip_prefix('{') {
+                    let end = inner.find('}').ok_or_else(|| invalid("unclosed {"))?;
+                    let field = match &inner[..end] {
+                        "artist" => Field::Artist,
+                        "album" => Field::Album,
+                        "title" => Field::Title,
+                        "genre" => Field::Genre,
+                        "year" => Field::Year,
+                        "track" => Field::Track,
+                        "_" => Field::Ignore,
+                        other => return Err(invalid(&format!("unknown field {{{}}}", other))),
+                    };
+                    if let Some(Token::Field(_)) = tokens.last() {
+                        return Err(invalid("fields must be separated by text"));
+                    }
+                    tokens.push(Token::Field(field));
+                    rest = &inner[end + 1..];
+                } else {
+                    let end = rest.find('{').unwrap_or(rest.len());
+                    tokens.push(Token::Literal(rest[..end].to_string()));
+                    rest = &rest[end..];
+                }
+            }
+            if tokens.is_empty() {
+                return Err(invalid("empty path segment"));
+            }
+            segments.push(tokens);
+        }
+        Ok(Self { segments })
+    }
+
+    pub fn parse_path(&self, path: &Path) -> Option<PathFields> {
+        let mut components: Vec<String> = path
+            .parent()?
+            .components()
+            .map(|c| c.as_os_str().to_string_lossy().to_string())
+            .collect();
+        components.push(path.file_stem()?.to_string_lossy().to_string());
+
+        if components.len() < self.segments.len() {
+            return None;
+        }
+        let components = &components[components.len() - self.segments.len()..];
+
+        let mut fields = PathFields::default();
+        for (tokens, component) in self.segments.iter().zip(components) {
+            let mut values = vec![];
+            if !match_tokens(tokens, component, &mut values) {
+                return None;
+            }
+            for (field, value) in values {
+                let value = value.trim();
+                match field {
+                    Field::Artist => fields.artist = Some(value.to_string()),
+                    Field::Album => fields.album = Some(value.to_string()),
+                    Field::Title => fields.title = Some(value.to_string()),
+                    Field::Genre => fields.genre = Some(value.to_string()),
+                    Field::Year => fields.year = value.parse().ok(),
+                    Field::Track => fields.track = value.parse().ok(),
+                    Field::Ignore => {}
+                }
+            }
+        }
+        Some(fields)
+    }
+}
+
+fn match_tokens<'a>(tokens: &[Token], text: &'a str, values: &mut Vec<(Field, &'a str)>) -> bool {
+    match tokens.split_first() {
+        None => text.is_empty(),
+        Some((Token::Literal(literal), rest)) => match text.strip_prefix(literal.as_str()) {
+            Some(text) => match_tokens(rest, text, values),
+            None => false,
+        },
+        Some((Token::Field(field), rest)) => {
+            // Try the shortest value first so literals after the field split as early as possible
+            for (end, _) in text.char_indices().skip(1).chain([(text.len(), ' ')]) {
+                let value = &text[..end];
+                if value.trim().is_empty() {
+                    continue;
+                }
+                let numeric = matches!(field, Field::Year | Field::Track);
+                if numeric && !value.trim().chars().all(|c| c.is_ascii_digit()) {
+                    break;
+                }
+                values.push((*field, value));
+                if match_tokens(rest, &text[end..], values) {
+                    return true;
+                }
+                values.pop();
+            }
+            false
+        }
+    }
+}
@@ -1,9 +1,51 @@
+use lazy_static::lazy_static;
 use rand::seq::SliceRandom;
 use rand::thread_rng;
 use rodio::{Decoder, OutputStream, OutputStreamHandle, Sink};
-use std::{fs::File, io::BufReader, path::PathBuf, time::Duration, vec};
+use std::time::SystemTime;
+use std::{fs::File, io, io::BufReader, path::PathBuf, time::Duration, vec};
+use tokio::sync::broadcast;
 
-use crate::song::Song;
+use crate::config::CONFIG;
+use crate::error::AppError;
+use crate::library::unix_time;
+use crate::song::{Song, SongError};
+
+lazy_static! {
+    // Anything that follows playback (stats, history, scrobbling) subscribes here
+    pub static ref PLAYER_EVENTS: broadcast::Sender<PlayerEvent> = broadcast::channel(64).0;
+}
+
+#[derive(Debug, Clone)]
+pub enum PlayerEvent {
+    TrackStarted(Song),
+    TrackEnded(PlayRecord),
+    // Paused, resumed, or volume, repeat or shuffle changed
+    StateChanged,
+    Seeked(Duration),
+    // Songs were added, removed or moved
+    QueueChanged,
+}
+
+#[derive(Debug, Clone)]
+pub struct PlayRecord {
+    pub song: Song,
+    pub started: u64,
+    pub played: Duration,
+}
+
+impl PlayRecord {
+    // A play counts after half the song or 4 minutes, whichever comes first
+    pub fn counts_as_play(&self) -> bool {
+        let threshold = (self.song.duration / 2).min(Duration::from_secs(240));
+        !self.played.is_zero() && self.played >= threshold
+    }
+
+    // Scrobbling services also ignore songs of 30 seconds or less
+    pub fn counts_as_scrobble(&self) -> bool {
+        self.song.duration > Duration::from_secs(30) && self.counts_as_play()
+    }
+}
 
 pub struct Player {
     _output_stream: (OutputStream, OutputStreamHandle),
@@ -14,6 +56,8 @@ pub struct Player {
     pub is_shuffled: bool,
     queue_index: i32,
     volume: f32,
+    started: u64,
+    queue_version: u32,
 }
 
 impl Player {
@@ -30,25 +74,66 @@ impl Player {
             is_shuffled: false,
             queue_index: 0,
             volume: 0.5,
+            started: 0,
+            queue_version: 0,
         }
     }
 
-    pub fn play(&mut self, path: PathBuf) {
+    pub fn play(&mut self, path: PathBuf) -> Result<(), AppError> {
+        self.end_current_song();
         self.sink.stop(); // If it is already running stop it
 
         self.current_song = self.queue.get(self.queue_index as usize).cloned();
 
-        let file = BufReader::new(File::open(path.clone()).unwrap());
-        let source = Decoder::new(file).unwrap();
+        let file = File::open(&path).map_err(|e| match e.kind() {
+            io::ErrorKind::NotFound => AppError::FileMissing(path.clone()),
+            _ => AppError::from(e),
+        })?;
+        let source =
+            Decoder::new(BufReader::new(file)).map_err(|e| AppError::Decode(e.to_string()))?;
 
         self.sink.append(source);
         self.sink.play();
+        self.started = unix_time(SystemTime::now());
+        if let Some(song) = &self.current_song {
+            let _ = PLAYER_EVENTS.send(PlayerEvent::TrackStarted(song.clone()));
+        }
+        Ok(())
+    }
+
+    // An empty sink means the song ran to the end, otherwise it was cut short
+    fn end_current_song(&mut self) {
+        let started = std::mem::take(&mut self.started);
+        let Some(song) = self.current_song.take() else {
+            return;
+        };
+        // The song never started playing, e.g. it failed to decode
+        if started == 0 {
+            return;
+        }
+        let played = if self.sink.empty() {
+            song.duration
+        } else {
+            self.sink.get_pos()
+        };
+        let record = PlayRecord {
+            song,
+            started,
+            played,
+        };
+        let _ = PLAYER_EVENTS.send(PlayerEvent::TrackEnded(record));
     }
 
-    pub fn skip(&mut self, to: i32) {
-        let index = (self.queue_index as i32 + to) as usize;
-        let current_song = self.queue.get(self.queue_index as usize).cloned().unwrap();
-        let next_song = self.queue.get(index).unwrap_or(&current_song);
+    pub fn skip(&mut self, to: i32) -> Result<(), AppError> {
+        let current_song = self
+            .queue
+            .get(self.queue_index as usize)
+            .cloned()
+            .ok_or(AppError::QueueEmpty)?;
+        let next_song = usize::try_from(self.queue_index + to)
+            .ok()
+            .and_then(|index| self.queue.get(index))
+            .unwrap_or(&current_song);
 
         if next_song.get_path() != current_song.get_path() {
             self.queue_index += to;
@@ -56,15 +141,46 @@ impl Player {
         self.play(next_song.get_path())
     }
 
-    pub async fn add_to_queue(&mut self, path: PathBuf) {
-        let song = self.get_song_info(path).await;
-        self.queue.push(song);
+    // Moves on once the song ran out: repeat it, play the next one, or start the queue over
+    pub fn tick(&mut self) -> Result<(), AppError> {
+        if self.started == 0 || !self.sink.empty() {
+            return Ok(());
+        }
+        let to = match self.repeat {
+            true => 0,
+            false if self.queue_index() + 1 < self.queue.len() => 1,
+            false => -self.queue_index,
+        };
+        self.skip(to)
+    }
+
+    // Takes songs loaded with get_song_info, so tags aren't read while the player is locked
+    pub fn push(&mut self, songs: Vec<Song>) {
+        if songs.is_empty() {
+            return;
+        }
+        self.queue.extend(songs);
+        self.queue_changed();
     }
 
-    pub async fn get_song_info(&self, path: PathBuf) -> Song {
+    // Loads every path, the ones that can't be read come back with their error
+    pub async fn get_songs_info(paths: Vec<PathBuf>) -> (Vec<Song>, Vec<SongError>) {
+        let mut songs = Vec::new();
+        let mut failed = Vec::new();
+        for path in paths {
+            match Self::get_song_info(path).await {
+                Ok(song) => songs.push(song),
+                Err(e) => failed.push(e),
+            }
+        }
+        (songs, failed)
+    }
+
+    pub async fn get_song_info(path: PathBuf) -> Result<Song, SongError> {
+        let patterns = CONFIG.lock().await.path_patterns();
         let mut song = Song::new(path);
-        song.load_metadata().await;
-        song
+        song.load_metadata(&patterns).await?;
+        Ok(song)
     }
 
     pub fn pause_resume(&mut self) {
@@ -73,6 +189,7 @@ impl Player {
         } else {
             self.sink.pause()
         }
+        let _ = PLAYER_EVENTS.send(PlayerEvent::StateChanged);
     }
 
     pub fn song_finished(&self) -> bool {
@@ -87,28 +204,115 @@ impl Player {
         self.sink.get_pos().as_secs()
     }
 
+    pub fn position(&self) -> Duration {
+        self.sink.get_pos()
+    }
+
+    pub fn queue_index(&self) -> usize {
+        self.queue_index as usize
+    }
+
+    // Goes up every time the queue changes, so clients can tell their copy is stale
+    pub fn queue_version(&self) -> u32 {
+        self.queue_version
+    }
+
+    fn queue_changed(&mut self) {
+        self.queue_version = self.queue_version.wrapping_add(1);
+        let _ = PLAYER_EVENTS.send(PlayerEvent::QueueChanged);
+    }
+
+    pub fn play_index(&mut self, index: usize) -> Result<(), AppError> {
+        let song = self.queue.get(index).ok_or(AppError::OutOfRange(index))?;
+        let path = song.get_path();
+        self.queue_index = index as i32;
+        self.play(path)
+    }
+
+    pub fn volume(&self) -> f32 {
+        self.volume
+    }
+
     pub fn shuffle_queue(&mut self) {
         if !self.is_shuffled {
             let mut rng = thread_rng();
             self.queue.shuffle(&mut rng);
+            self.queue_changed();
         }
         self.is_shuffled = true;
+        let _ = PLAYER_EVENTS.send(PlayerEvent::StateChanged);
+    }
+
+    // The queue can't be put back in order, so turning it off only clears the flag
+    pub fn set_shuffle(&mut self, shuffle: bool) {
+        if shuffle {
+            return self.shuffle_queue();
+        }
+        self.is_shuffled = false;
+        let _ = PLAYER_EVENTS.send(PlayerEvent::StateChanged);
+    }
+
+    pub fn set_repeat(&mut self, repeat: bool) {
+        self.repeat = repeat;
+        let _ = PLAYER_EVENTS.send(PlayerEvent::StateChanged);
+    }
+
+    pub fn remove_from_queue(&mut self, index: usize) -> Result<Song, AppError> {
+        if index >= self.queue.len() {
+            return Err(AppError::OutOfRange(index));
+        }
+        if index < self.queue_index() {
+            self.queue_index -= 1;
+        }
+        let song = self.queue.remove(index);
+        // Removing the last song while it plays leaves the index on the new last one
+        self.queue_index = self
+            .queue_index
+            .min(self.queue.len().saturating_sub(1) as i32);
+        self.queue_changed();
+        Ok(song)
+    }
+
+    // The current song keeps playing, wherever it ends up
+    pub fn move_in_queue(&mut self, from: usize, to: usize) -> Result<(), AppError> {
+        if from >= self.queue.len() || to >= self.queue.len() {
+            return Err(AppError::OutOfRange(from.max(to)));
+        }
+        let current = self.queue_index();
+        self.queue_index = if from == current {
+            to as i32
+        } else if from < current && to >= current {
+            self.queue_index - 1
+        } else if from > current && to <= current {
+            self.queue_index + 1
+        } else {
+            self.queue_index
+        };
+        let song = self.queue.remove(from);
+        self.queue.insert(to, song);
+        self.queue_changed();
+        Ok(())
     }
 
     pub fn empty_queue(&mut self) {
+        self.end_current_song();
         self.queue.clear();
         self.sink.clear();
         self.queue_index = 0;
+        self.queue_changed();
     }
 
     pub fn adjust_volume(&mut self, by: f32) {
-        self.volume += by;
+        self.set_volume(self.volume + by);
+    }
 
-        self.volume = self.volume.clamp(0.0, 1.0);
-        self.sink.set_volume(self.volume)
+    pub fn set_volume(&mut self, volume: f32) {
+        self.volume = volume.clamp(0.0, 1.0);
+        self.sink.set_volume(self.volume);
+        let _ = PLAYER_EVENTS.send(PlayerEvent::StateChanged);
     }
 
-    pub fn seek_position(&self, by: i32) {
+    pub fn seek_position(&self, by: i32) -> Result<(), AppError> {
         let mut new_position = self.sink.get_pos().as_secs() as i32 + by;
         let song_duration = self
             .current_song
@@ -118,9 +322,15 @@ impl Player {
             .as_secs() as i32;
         new_position = new_position.clamp(0, song_duration);
 
+        self.seek_to(Duration::from_secs(new_position as u64))
+    }
+
+    pub fn seek_to(&self, position: Duration) -> Result<(), AppError> {
         self.sink
-            .try_seek(Duration::from_secs(new_position as u64))
-            .expect("Cannot change player position");
+            .try_seek(position)
+            .map_err(|e| AppError::Seek(e.to_string()))?;
+        let _ = PLAYER_EVENTS.send(PlayerEvent::Seeked(position));
+        Ok(())
     }
 }
 
@@ -1,10 +1,49 @@
-use serde::Serialize;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
 use std::path::PathBuf;
 
 use crate::song::Song;
 
+#[derive(Debug, Default, Serialize)]
+pub struct TrackIssues {
+    pub album: String,
+    pub disc: u32,
+    pub missing: Vec<u32>,
+    pub duplicates: Vec<u32>,
+    pub untagged: Vec<PathBuf>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct ArtistSummary {
+    pub name: String,
+    pub album_count: usize,
+    pub song_count: usize,
+    pub cover_path: PathBuf,
+}
+
+#[derive(Debug, Serialize)]
+pub struct GenreSummary {
+    pub name: String,
+    pub song_count: usize,
+    pub cover_path: PathBuf,
+}
+
+// Identifies any playlist the library can build, e.g. `{ "kind": "album", "id": "..." }`
+#[derive(Debug, Clone, Deserialize)]
+#[serde(tag = "kind", content = "id", rename_all = "snake_case")]
+pub enum PlaylistSource {
+    Album(String),
+    Artist(String),
+    Genre(String),
+    Folder(PathBuf),
+    User(String),
+    Smart(String),
+    Queue,
+}
+
 #[derive(Debug, Serialize)]
 pub struct Playlist {
+    pub id: String,
     pub name: String,
     pub cover_path: PathBuf,
     pub song_list: Vec<Song>,
@@ -13,6 +52,7 @@ pub struct Playlist {
 impl Playlist {
     pub fn new() -> Self {
         Self {
+            id: String::default(),
             name: String::from("New playlist"),
             cover_path: PathBuf::default(),
             song_list: Vec::default(),
@@ -21,14 +61,100 @@ impl Playlist {
 
     pub fn new_playlist_from(this: &String, song_list: Vec<Song>) -> Self {
         let first_song_cover = song_list
-            .get(0)
+            .first()
             .cloned()
             .unwrap_or_default()
             .get_cover_path();
         Self {
+            id: this.to_owned(),
             name: this.to_owned(),
             cover_path: first_song_cover,
             song_list,
         }
     }
+
+    // Splits songs into album playlists, oldest album first
+    pub fn albums_from(songs: Vec<Song>) -> Vec<Playlist> {
+        let mut albums: BTreeMap<String, Vec<Song>> = BTreeMap::new();
+        for song in songs {
+            albums.entry(song.album_key()).or_default().push(song);
+        }
+
+        let mut playlists: Vec<Playlist> = albums
+            .into_iter()
+            .map(|(album, song_list)| {
+                let name = song_list
+                    .first()
+                    .and_then(|song| song.album.clone())
+                    .unwrap_or_default();
+                let mut playlist = Playlist::new_playlist_from(&album, song_list);
+                playlist.name = name;
+                playlist.sort_by_track();
+                playlist
+            })
+            .collect();
+        playlists.sort_by_key(|playlist| playlist.year());
+        playlists
+    }
+
+    pub fn year(&self) -> Option<u32> {
+        self.song_list.iter().filter_map(|song| song.year).max()
+    }
+
+    pub fn sort_by_track(&mut self) {
+        self.song_list.sort_by_cached_key(|song| {
+            (
+                song.disc.unwrap_or(1),
+                song.track.unwrap_or(u32::MAX),
+                song.get_path().file_name().map(|name| name.to_owned()),
+            )
+        });
+    }
+
+    pub fn track_issues(&self) -> Vec<TrackIssues> {
+        let mut discs: BTreeMap<u32, Vec<&Song>> = BTreeMap::new();
+        for song in &self.song_list {
+            discs.entry(song.disc.unwrap_or(1)).or_default().push(song);
+        }
+
+        let mut issues = vec![];
+        for (disc, songs) in discs {
+            let mut counts: BTreeMap<u32, usize> = BTreeMap::new();
+            let mut untagged = vec![];
+            for song in &songs {
+                match song.track {
+                    Some(track) => *counts.entry(track).or_default() += 1,
+                    None => untagged.push(song.get_path()),
+                }
+            }
+
+            let last_track = songs
+                .iter()
+                .filter_map(|song| song.total_tracks)
+                .chain(counts.keys().copied())
+                .max()
+                .unwrap_or_default();
+            let disc_issues = TrackIssues {
+                album: self.id.clone(),
+                disc,
+                missing: (1..=last_track)
+                    .filter(|track| !counts.contains_key(track))
+                    .collect(),
+                duplicates: counts
+                    .into_iter()
+                    .filter(|(_, count)| *count > 1)
+                    .map(|(track, _)| track)
+                    .collect(),
+                untagged,
+            };
+
+            if !disc_issues.missing.is_empty()
+                || !disc_issues.duplicates.is_empty()
+                || !disc_issues.untagged.is_empty()
+            {
+                issues.push(disc_issues);
+            }
+        }
+        issues
+    }
 }
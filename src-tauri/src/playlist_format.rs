@@ -0,0 +1,577 @@
+use serde::Serialize;
+use std::path::{Component, Path, PathBuf};
+
+use crate::error::AppError;
+use crate::song::Song;
+use crate::user_playlist::UserPlaylist;
+
+#[derive(Debug, Serialize)]
+pub struct ImportReport {
+    pub playlist: UserPlaylist,
+    pub unresolved: Vec<String>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum PlaylistFormat {
+    M3u,
+    M3u8,
+    Pls,
+    Xspf,
+}
+
+#[derive(Debug, Default, Clone)]
+pub struct PlaylistEntry {
+    pub location: String,
+    pub title: Option<String>,
+    pub artist: Option<String>,
+    pub album: Option<String>,
+    pub duration: Option<u64>,
+}
+
+impl PlaylistFormat {
+    pub fn from_path(path: &Path) -> Result<Self, AppError> {
+        let extension = path.extension().unwrap_or_default().to_string_lossy();
+        match extension.to_lowercase().as_str() {
+            "m3u" => Ok(PlaylistFormat::M3u),
+            "m3u8" => Ok(PlaylistFormat::M3u8),
+            "pls" => Ok(PlaylistFormat::Pls),
+            "xspf" => Ok(PlaylistFormat::Xspf),
+            _ => Err(AppError::UnsupportedFormat(extension.to_string())),
+        }
+    }
+
+    pub fn parse(&self, data: &[u8]) -> Vec<PlaylistEntry> {
+        let content = match String::from_utf8(data.to_vec()) {
+            Ok(content) => content,
+            // Plain .m3u files are often Latin-1
+            Err(_) => data.iter().map(|&b| b as char).collect(),
+        };
+        let content = content.trim_start_matches('\u{feff}');
+
+        match self {
+            PlaylistFormat::M3u | PlaylistFormat::M3u8 => parse_m3u(content),
+            PlaylistFormat::Pls => parse_pls(content),
+            PlaylistFormat::Xspf => parse_xspf(content),
+        }
+    }
+
+    pub fn write(&self, name: &str, entries: &[PlaylistEntry]) -> String {
+        match self {
+            PlaylistFormat::M3u | PlaylistFormat::M3u8 => write_m3u(entries),
+            PlaylistFormat::Pls => write_pls(entries),
+            PlaylistFormat::Xspf => write_xspf(name, entries),
+        }
+    }
+}
+
+impl PlaylistEntry {
+    // `base` is the folder of the playlist file when locations should be relative
+    pub fn from_song(song: &Song, base: Option<&Path>) -> Self {
+        let path = song.get_path();
+        let location = match base {
+            Some(base) => relative_path(base, &path),
+            None => path,
+        };
+        Self {
+            location: location.display().to_string(),
+            title: song.title.clone(),
+            artist: song.artist.clone(),
+            album: song.album.clone(),
+            duration: Some(song.duration.as_secs()),
+        }
+    }
+
+    // Resolves the location against the playlist folder and then the library roots
+    pub fn resolve(&self, base: &Path, roots: &[PathBuf]) -> Option<PathBuf> {
+        let location = self
+            .location
+            .strip_prefix("file://")
+            .map_or_else(|| self.location.replace('\\', "/"), percent_decode);
+        if location.contains("://") {
+            return None;
+        }
+
+        let path = base.join(&location);
+        if path.is_file() {
+            return Some(path);
+        }
+
+        // The playlist came from another machine, try the tail of its path in our roots
+        let components: Vec<&str> = location.split('/').filter(|c| !c.is_empty()).collect();
+        for skip in 0..components.len() {
+            let suffix: PathBuf = components[skip..].iter().collect();
+            if let Some(path) = roots
+                .iter()
+                .map(|root| root.join(&suffix))
+                .find(|p| p.is_file())
+            {
+                return Some(path);
+            }
+        }
+        None
+    }
+
+    pub fn matches(&self, song: &Song) -> bool {
+        let same = |a: &Option<String>, b: &Option<String>| match (a, b) {
+            (Some(a), Some(b)) => a.to_lowercase() == b.to_lowercase(),
+            _ => false,
+        };
+        same(&self.title, &song.title)
+            && (self.artist.is_none() || same(&self.artist, &song.artist))
+    }
+
+    pub fn file_name(&self) -> Option<String> {
+        let location = self.location.replace('\\', "/");
+        let file_name = location.rsplit('/').next()?;
+        Some(percent_decode(file_name).to_lowercase())
+    }
+}
+
+fn parse_m3u(content: &str) -> Vec<PlaylistEntry> {
+    let mut entries = vec![];
+    let mut next = PlaylistEntry::default();
+
+    for line in content.lines().map(str::trim) {
+        if let Some(info) = line.strip_prefix("#EXTINF:") {
+            // #EXTINF:<seconds>,<artist> - <title>
+            let (duration, name) = info.split_once(',').unwrap_or((info, ""));
+            next.duration = duration
+                .trim()
+                .parse::<i64>()
+                .ok()
+                .and_then(|d| d.try_into().ok());
+            match name.split_once(" - ") {
+                Some((artist, title)) => {
+                    next.artist = Some(artist.trim().to_string());
+                    next.title = Some(title.trim().to_string());
+                }
+                None if !name.is_empty() => next.title = Some(name.trim().to_string()),
+                None => {}
+            }
+        } else if let Some(album) = line.strip_prefix("#EXTALB:") {
+            next.album = Some(album.trim().to_string());
+        } else if !line.is_empty() && !line.starts_with('#') {
+            next.location = line.to_string();
+            entries.push(std::mem::take(&mut next));
+        }
+    }
+    entries
+}
+
+fn parse_pls(content: &str) -> Vec<PlaylistEntry> {
+    let mut entries: Vec<(usize, PlaylistEntry)> = vec![];
+
+    for line in content.lines().map(str::trim) {
+        let Some((key, value)) = line.split_once('=') else {
+            continue;
+        };
+        let key = key.trim().to_lowercase();
+        let (field, index) =
+            key.split_at(key.find(|c: char| c.is_ascii_digit()).unwrap_or(key.len()));
+        let Ok(index) = index.parse::<usize>() else {
+            continue;
+        };
+
+        let position = match entries.iter().position(|(i, _)| *i == index) {
+            Some(position) => position,
+            None => {
+                entries.push((index, PlaylistEntry::default()));
+                entries.len() - 1
+            }
+        };
+        let entry = &mut entries[position].1;
+        let value = value.trim().to_string();
+        match field {
+            "file" => entry.location = value,
+            "title" => entry.title = Some(value),
+            "length" => entry.duration = value.parse::<i64>().ok().and_then(|d| d.try_into().ok()),
+            _ => {}
+        }
+    }
+
+    entries.sort_by_key(|(index, _)| *index);
+    entries
+        .into_iter()
+        .map(|(_, entry)| entry)
+        .filter(|entry| !entry.location.is_empty())
+        .collect()
+}
+
+fn parse_xspf(content: &str) -> Vec<PlaylistEntry> {
+    let mut entries = vec![];
+    let mut rest = content;
+
+    while let Some(start) = rest.find("<track>") {
+        let end = rest[start..]
+            .find("</track>")
+            .map_or(rest.len(), |end| start + end);
+        let track = &rest[start..end];
+        rest = &rest[end..];
+
+        let Some(location) = xml_element(track, "location") else {
+            continue;
+        };
+        // Locations are URIs, file:// ones are decoded when resolved
+        let location = match location.contains("://") {
+            true => location,
+            false => percent_decode(&location),
+        };
+        entries.push(PlaylistEntry {
+            location,
+            title: xml_element(track, "title"),
+            artist: xml_element(track, "creator"),
+            album: xml_element(track, "album"),
+            // XSPF durations are in milliseconds
+            duration: xml_element(track, "duration")
+                .and_then(|d| d.parse::<u64>().ok())
+                .map(|d| d / 1000),
+        });
+    }
+    entries
+}
+
+fn write_m3u(entries: &[PlaylistEntry]) -> String {
+    let mut content = String::from("#EXTM3U\n");
+    for entry in entries {
+        let name = match (&entry.artist, &entry.title) {
+            (Some(artist), Some(title)) => format!("{} - {}", artist, title),
+            (None, Some(title)) => title.clone(),
+            _ => String::default(),
+        };
+        content += &format!(
+            "#EXTINF:{},{}\n",
+            entry.duration.map_or(-1, |d| d as i64),
+            name
+        );
+        if let Some(album) = &entry.album {
+            content += &format!("#EXTALB:{}\n", album);
+        }
+        content += &format!("{}\n", entry.location);
+    }
+    content
+}
+
+fn write_pls(entries: &[PlaylistEntry]) -> String {
+    let mut content = String::from("[playlist]\n");
+    for (i, entry) in entries.iter().enumerate() {
+        let n = i + 1;
+        content += &format!("File{}={}\n", n, entry.location);
+        if let Some(title) = &entry.title {
+            content += &format!("Title{}={}\n", n, title);
+        }
+        content += &format!("Length{}={}\n", n, entry.duration.map_or(-1, |d| d as i64));
+    }
+    content += &format!("NumberOfEntries={}\nVersion=2\n", entries.len());
+    content
+}
+
+fn write_xspf(name: &str, entries: &[PlaylistEntry]) -> String {
+    let mut content = String::from("<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n");
+    content += "<playlist version=\"1\" xmlns=\"http://xspf.org/ns/0/\">\n";
+    content += &format!("  <title>{}</title>\n  <trackList>\n", xml_escape(name));
+
+    for entry in entries {
+        let location = if Path::new(&entry.location).is_absolute() {
+            format!("file://{}", percent_encode(&entry.location))
+        } else {
+            percent_encode(&entry.location)
+        };
+        content += "    <track>\n";
+        content += &format!("      <location>{}</location>\n", xml_escape(&location));
+        for (tag, value) in [
+            ("title", &entry.title),
+            ("creator", &entry.artist),
+            ("album", &entry.album),
+        ] {
+            if let Some(value) = value {
+                content += &format!("      <{0}>{1}</{0}>\n", tag, xml_escape(value));
+            }
+        }
+        if let Some(duration) = entry.duration {
+            content += &format!("      <duration>{}</duration>\n", duration * 1000);
+        }
+        content += "    </track>\n";
+    }
+    content += "  </trackList>\n</playlist>\n";
+    content
+}
+
+fn xml_element(xml: &str, tag: &str) -> Option<String> {
+    let open = format!("<{}>", tag);
+    let start = xml.find(&open)? + open.len();
+    let end = start + xml[start..].find(&format!("</{}>", tag))?;
+    let value = xml_unescape(xml[start..end].trim());
+    (!value.is_empty()).then_some(value)
+}
+
+fn xml_escape(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+fn xml_unescape(text: &str) -> String {
+    text.replace("&lt;", "<")
+        .replace("&gt;", ">")
+        .replace("&quot;", "\"")
+        .replace("&apos;", "'")
+        .replace("&amp;", "&")
+}
+
+pub(crate) fn percent_encode(text: &str) -> String {
+    let mut encoded = String::new();
+    for byte in text.bytes() {
+        match byte {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'/' | b'-' | b'_' | b'.' | b'~' => {
+                encoded.push(byte as char)
+            }
+            _ => encoded += &format!("%{:02X}", byte),
+        }
+    }
+    encoded
+}
+
+pub(crate) fn percent_decode(text: &str) -> String {
+    let bytes = text.as_bytes();
+    let mut decoded = vec![];
+    let mut i = 0;
+
+    while i < bytes.len() {
+        let hex = bytes
+            .get(i + 1..i + 3)
+            .and_then(|h| std::str::from_utf8(h).ok());
+        match (bytes[i], hex.and_then(|h| u8::from_str_radix(h, 16).ok())) {
+            (b'%', Some(byte)) => {
+                decoded.push(byte);
+                i += 3;
+            }
+            (byte, _) => {
+                decoded.push(byte);
+                i += 1;
+            }
+        }
+    }
+    String::from_utf8_lossy(&decoded).to_string()
+}
+
+pub fn relative_path(base: &Path, path: &Path) -> PathBuf {
+    let base: Vec<Component> = base.components().collect();
+    let target: Vec<Component> = path.components().collect();
+    let common = base.iter().zip(&target).take_while(|(a, b)| a == b).count();
+
+    // Nothing in common (e.g. another drive), keep it absolute
+    if common == 0 {
+        return path.to_owned();
+    }
+    let mut relative = PathBuf::new();
+    for _ in common..base.len() {
+        relative.push("..");
+    }
+    relative.extend(&target[common..]);
+    relative
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+
+    fn entry(location: &str, title: Option<&str>, artist: Option<&str>) -> PlaylistEntry {
+        PlaylistEntry {
+            location: location.to_string(),
+            title: title.map(String::from),
+            artist: artist.map(String::from),
+            album: None,
+            duration: None,
+        }
+    }
+
+    fn locations(entries: &[PlaylistEntry]) -> Vec<&str> {
+        entries
+            .iter()
+            .map(|entry| entry.location.as_str())
+            .collect()
+    }
+
+    #[test]
+    fn m3u_round_trip() {
+        let entries = vec![
+            PlaylistEntry {
+                album: Some(String::from("Album")),
+                duration: Some(215),
+                ..entry(
+                    "/music/Artist - Album/01.flac",
+                    Some("Title"),
+                    Some("Artist"),
+                )
+            },
+            entry("../other/02.mp3", Some("Only a title"), None),
+            entry("03.mp3", None, None),
+        ];
+        let content = PlaylistFormat::M3u8.write("", &entries);
+        let parsed = PlaylistFormat::M3u8.parse(content.as_bytes());
+
+        assert_eq!(locations(&parsed), locations(&entries));
+        assert_eq!(parsed[0].title.as_deref(), Some("Title"));
+        assert_eq!(parsed[0].artist.as_deref(), Some("Artist"));
+        assert_eq!(parsed[0].album.as_deref(), Some("Album"));
+        assert_eq!(parsed[0].duration, Some(215));
+        assert_eq!(parsed[1].title.as_deref(), Some("Only a title"));
+        assert_eq!(parsed[1].artist, None);
+        assert_eq!(parsed[2].title, None);
+        assert_eq!(parsed[2].duration, None);
+    }
+
+    #[test]
+    fn m3u_skips_comments_and_reads_latin1() {
+        let data = b"\xef\xbb\xbf#EXTM3U\n# a comment\n\n#EXTINF:10,Caf\xc3\xa9\ncaf\xc3\xa9.mp3\n";
+        let parsed = PlaylistFormat::M3u8.parse(data);
+        assert_eq!(locations(&parsed), ["café.mp3"]);
+        assert_eq!(parsed[0].title.as_deref(), Some("Café"));
+
+        let data = b"#EXTINF:10,Caf\xe9\ncaf\xe9.mp3\n";
+        let parsed = PlaylistFormat::M3u.parse(data);
+        assert_eq!(locations(&parsed), ["café.mp3"]);
+        assert_eq!(parsed[0].title.as_deref(), Some("Café"));
+    }
+
+    #[test]
+    fn pls_orders_entries_by_index() {
+        let content = "[playlist]\nFile10=ten.mp3\nLength10=-1\nFile2=two.mp3\nTitle2=Two\n\
+                       Length2=5\nTitle3=No file\nNumberOfEntries=2\nVersion=2\n";
+        let parsed = PlaylistFormat::Pls.parse(content.as_bytes());
+        assert_eq!(locations(&parsed), ["two.mp3", "ten.mp3"]);
+        assert_eq!(parsed[0].title.as_deref(), Some("Two"));
+        assert_eq!(parsed[0].duration, Some(5));
+        assert_eq!(parsed[1].duration, None);
+    }
+
+    #[test]
+    fn pls_round_trip() {
+        let entries = vec![
+            PlaylistEntry {
+                duration: Some(61),
+                ..entry("/music/a.mp3", Some("A = B"), None)
+            },
+            entry("b.mp3", None, None),
+        ];
+        let content = PlaylistFormat::Pls.write("", &entries);
+        let parsed = PlaylistFormat::Pls.parse(content.as_bytes());
+        assert_eq!(locations(&parsed), locations(&entries));
+        assert_eq!(parsed[0].title.as_deref(), Some("A = B"));
+        assert_eq!(parsed[0].duration, Some(61));
+        assert_eq!(parsed[1].duration, None);
+    }
+
+    #[test]
+    fn xspf_round_trip() {
+        let entries = vec![
+            PlaylistEntry {
+                album: Some(String::from("Rock & Roll")),
+                duration: Some(61),
+                ..entry("/music/A & B/01 é.flac", Some("<Title>"), Some("Artist"))
+            },
+            entry("sub dir/02.mp3", None, None),
+        ];
+        let content = PlaylistFormat::Xspf.write("Mine", &entries);
+        assert!(content.contains("<title>Mine</title>"));
+        assert!(content.contains("<duration>61000</duration>"));
+        let parsed = PlaylistFormat::Xspf.parse(content.as_bytes());
+
+        // Absolute locations stay file:// URIs until they are resolved
+        assert_eq!(
+            locations(&parsed),
+            [
+                "file:///music/A%20%26%20B/01%20%C3%A9.flac",
+                "sub dir/02.mp3"
+            ]
+        );
+        assert_eq!(parsed[0].title.as_deref(), Some("<Title>"));
+        assert_eq!(parsed[0].artist.as_deref(), Some("Artist"));
+        assert_eq!(parsed[0].album.as_deref(), Some("Rock & Roll"));
+        assert_eq!(parsed[0].duration, Some(61));
+        assert_eq!(parsed[1].duration, None);
+    }
+
+    #[test]
+    fn xspf_durations_are_milliseconds() {
+        let content = "<playlist><trackList><track><location>a.mp3</location>\
+                       <duration>61999</duration></track><track><title>No location</title>\
+                       </track></trackList></playlist>";
+        let parsed = PlaylistFormat::Xspf.parse(content.as_bytes());
+        assert_eq!(locations(&parsed), ["a.mp3"]);
+        assert_eq!(parsed[0].duration, Some(61));
+    }
+
+    #[test]
+    fn percent_encoding_round_trip() {
+        let text = "/music/a b/ü%&.mp3";
+        let encoded = percent_encode(text);
+        assert_eq!(encoded, "/music/a%20b/%C3%BC%25%26.mp3");
+        assert_eq!(percent_decode(&encoded), text);
+        // Stray percent signs are kept
+        assert_eq!(percent_decode("100% %zz %4"), "100% %zz %4");
+    }
+
+    #[test]
+    fn relative_paths() {
+        let base = Path::new("/music/lists");
+        assert_eq!(
+            relative_path(base, Path::new("/music/rock/a.mp3")),
+            PathBuf::from("../rock/a.mp3")
+        );
+        assert_eq!(
+            relative_path(base, Path::new("/music/lists/a.mp3")),
+            PathBuf::from("a.mp3")
+        );
+        assert_eq!(
+            relative_path(Path::new("lists"), Path::new("/music/a.mp3")),
+            PathBuf::from("/music/a.mp3")
+        );
+    }
+
+    #[test]
+    fn resolves_relative_absolute_and_foreign_locations() {
+        let dir = std::env::temp_dir().join(format!("bupl-playlists-{}", std::process::id()));
+        let base = dir.join("lists");
+        let song = dir.join("rock").join("a b.mp3");
+        fs::create_dir_all(&base).unwrap();
+        fs::create_dir_all(song.parent().unwrap()).unwrap();
+        fs::write(&song, b"").unwrap();
+        let roots = vec![dir.clone()];
+
+        let mut from_song = Song::new(song.clone());
+        from_song.title = Some(String::from("A"));
+        let relative = PlaylistEntry::from_song(&from_song, Some(&base));
+        assert_eq!(relative.location, "../rock/a b.mp3");
+        assert_eq!(
+            relative.resolve(&base, &[]),
+            Some(base.join("../rock/a b.mp3"))
+        );
+
+        let absolute = PlaylistEntry::from_song(&from_song, None);
+        assert_eq!(absolute.resolve(&base, &[]), Some(song.clone()));
+
+        let uri = format!("file://{}", percent_encode(&song.display().to_string()));
+        assert_eq!(
+            entry(&uri, None, None).resolve(&base, &[]),
+            Some(song.clone())
+        );
+
+        // Made on another machine, the tail of the path is found in the library roots
+        let foreign = entry("C:\\Users\\me\\Music\\rock\\a b.mp3", None, None);
+        assert_eq!(
+            foreign.resolve(&base, &roots),
+            Some(dir.join("rock/a b.mp3"))
+        );
+        assert_eq!(foreign.resolve(&base, &[]), None);
+
+        assert_eq!(
+            entry("http://example.com/a.mp3", None, None).resolve(&base, &roots),
+            None
+        );
+
+        let _ = fs::remove_dir_all(&dir);
+    }
+}
@@ -0,0 +1,383 @@
+use futures_util::{SinkExt, StreamExt};
+use rand::distributions::{Alphanumeric, DistString};
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use std::time::Duration;
+use tokio::io::{AsyncWriteExt, BufReader};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::broadcast::error::RecvError;
+use tokio_tungstenite::tungstenite::handshake::derive_accept_key;
+use tokio_tungstenite::tungstenite::protocol::Role;
+use tokio_tungstenite::tungstenite::Message;
+use tokio_tungstenite::WebSocketStream;
+
+use crate::config::CONFIG;
+use crate::control::{handle_request, ControlRequest, PlayerStatus};
+use crate::error::AppError;
+use crate::http::{read_head, same_secret};
+use crate::player::{PlayerEvent, PLAYER_EVENTS};
+
+const REMOTE_PAGE: &str = include_str!("../remote/index.html");
+const REMOTE_SCHEMA: &str = include_str!("../remote/schema.json");
+
+// The same names and parameters as the control socket commands
+const METHODS: &[&str] = &[
+    "play",
+    "pause",
+    "play_pause",
+    "next",
+    "prev",
+    "skip",
+    "seek",
+    "seek_to",
+    "volume",
+    "adjust_volume",
+    "toggle_repeat",
+    "shuffle",
+    "toggle_shuffle",
+    "queue_add",
+    "play_paths",
+    "play_album",
+    "queue",
+    "status",
+];
+
+const POSITION_INTERVAL: Duration = Duration::from_secs(1);
+
+// Off by default, set `address` to e.g. 0.0.0.0:8737 to open the remote on a phone
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct RemoteSettings {
+    pub enabled: bool,
+    pub address: String,
+    // Clients pair by sending it, a new one unpairs every device
+    pub token: String,
+}
+
+impl Default for RemoteSettings {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            address: String::from("127.0.0.1:8737"),
+            token: String::new(),
+        }
+    }
+}
+
+pub fn new_pairing_token() -> String {
+    Alphanumeric.sample_string(&mut rand::thread_rng(), 32)
+}
+
+struct Request {
+    path: String,
+    token: Option<String>,
+    websocket_key: Option<String>,
+}
+
+pub async fn run_remote_server() {
+    let mut config = CONFIG.lock().await;
+    if !config.remote.enabled {
+        return;
+    }
+    if config.remote.token.is_empty() {
+        config.remote.token = new_pairing_token();
+        if let Err(e) = config.save() {
+            println!("Could not save the remote pairing token: {}", e);
+        }
+    }
+    let address = config.remote.address.clone();
+    drop(config);
+
+    if let Err(e) = serve(&address).await {
+        println!("Remote server unavailable: {}", e);
+    }
+}
+
+async fn serve(address: &str) -> Result<(), AppError> {
+    let listener = TcpListener::bind(address).await?;
+    loop {
+        let (stream, _) = listener.accept().await?;
+        tokio::spawn(handle_client(stream));
+    }
+}
+
+// Serves the remote page and schema, and upgrades `/ws?token=...` to a WebSocket
+async fn handle_client(stream: TcpStream) -> Result<(), AppError> {
+    let mut stream = BufReader::new(stream);
+    let Some(request) = read_request(&mut stream).await? else {
+        return respond(&mut stream, "400 Bad Request", "text/plain", "").await;
+    };
+    // Read on every connection so a new token takes effect right away
+    let token = CONFIG.lock().await.remote.token.clone();
+    let paired = !token.is_empty()
+        && request
+            .token
+            .as_ref()
+            .is_some_and(|given| same_secret(given.as_bytes(), token.as_bytes()));
+
+    match (request.path.as_str(), request.websocket_key) {
+        ("/", _) | ("/index.html", _) => {
+            respond(
+                &mut stream,
+                "200 OK",
+                "text/html; charset=utf-8",
+                REMOTE_PAGE,
+            )
+            .await
+        }
+        ("/schema.json", _) => {
+            respond(
+                &mut stream,
+                "200 OK",
+                "application/schema+json",
+                REMOTE_SCHEMA,
+            )
+            .await
+        }
+        ("/cover", _) if paired => {
+            let cover = status().await.cover_path.unwrap_or_default();
+            let content_type = match cover.extension().and_then(|ext| ext.to_str()) {
+                Some("png") => "image/png",
+                Some("webp") => "image/webp",
+                _ => "image/jpeg",
+            };
+            match tokio::fs::read(&cover).await {
+                Ok(data) => respond(&mut stream, "200 OK", content_type, data).await,
+                Err(_) => respond(&mut stream, "404 Not Found", "text/plain", "").await,
+            }
+        }
+        ("/ws", _) if !paired => {
+            respond(
+                &mut stream,
+                "401 Unauthorized",
+                "text/plain",
+                "Wrong pairing token",
+            )
+            .await
+        }
+        ("/ws", Some(key)) => {
+            let head = format!(
+                "HTTP/1.1 101 Switching Protocols\r\nUpgrade: websocket\r\nConnection: Upgrade\r\nSec-WebSocket-Accept: {}\r\n\r\n",
+                derive_accept_key(key.as_bytes())
+            );
+            stream.write_all(head.as_bytes()).await?;
+            let socket = WebSocketStream::from_raw_socket(stream, Role::Server, None).await;
+            run_session(socket).await
+        }
+        ("/ws", None) => respond(&mut stream, "426 Upgrade Required", "text/plain", "").await,
+        _ => respond(&mut stream, "404 Not Found", "text/plain", "").await,
+    }
+}
+
+async fn read_request(stream: &mut BufReader<TcpStream>) -> Result<Option<Request>, AppError> {
+    let Some(head) = read_head(stream).await? else {
+        return Ok(None);
+    };
+    let (path, query) = head.target.split_once('?').unwrap_or((&head.target, ""));
+    let token = query
+        .split('&')
+        .find_map(|pair| pair.strip_prefix("token="))
+        .map(str::to_string);
+    Ok(Some(Request {
+        path: path.to_string(),
+        token,
+        websocket_key: head.header("sec-websocket-key").map(str::to_string),
+    }))
+}
+
+async fn respond(
+    stream: &mut BufReader<TcpStream>,
+    status: &str,
+    content_type: &str,
+    body: impl AsRef<[u8]>,
+) -> Result<(), AppError> {
+    let body = body.as_ref();
+    let head = format!(
+        "HTTP/1.1 {}\r\nConnection: close\r\nContent-Type: {}\r\nContent-Length: {}\r\n\r\n",
+        status,
+        content_type,
+        body.len()
+    );
+    stream.write_all(head.as_bytes()).await?;
+    stream.write_all(body).await?;
+    Ok(())
+}
+
+// Answers JSON-RPC calls and pushes player events as notifications until the client leaves
+async fn run_session(mut socket: WebSocketStream<BufReader<TcpStream>>) -> Result<(), AppError> {
+    let mut events = PLAYER_EVENTS.subscribe();
+    let mut ticks = tokio::time::interval(POSITION_INTERVAL);
+    loop {
+        let outgoing = tokio::select! {
+            message = socket.next() => match message {
+                Some(Ok(Message::Text(text))) => handle_message(&text).await,
+                Some(Ok(Message::Close(_))) | None => return Ok(()),
+                Some(Ok(_)) => None,
+                Some(Err(e)) => return Err(AppError::Network(e.to_string())),
+            },
+            event = events.recv() => match event {
+                Ok(event) => notification(event).await,
+                Err(RecvError::Lagged(_)) => {
+                    Some(notify("state_changed", json!(status().await)))
+                }
+                Err(RecvError::Closed) => return Ok(()),
+            },
+            _ = ticks.tick() => {
+                let status = status().await;
+                (status.state == "playing").then(|| {
+                    notify("position", json!({ "position": status.position, "duration": status.duration }))
+                })
+            }
+        };
+        if let Some(outgoing) = outgoing {
+            socket
+                .send(Message::Text(outgoing.to_string()))
+                .await
+                .map_err(|e| AppError::Network(e.to_string()))?;
+        }
+    }
+}
+
+// Returns the response, or nothing for notifications sent by the client
+async fn handle_message(text: &str) -> Option<Value> {
+    let Ok(message) = serde_json::from_str::<Value>(text) else {
+        let error = rpc_error(-32700, "Parse error", None);
+        return Some(json!({ "jsonrpc": "2.0", "id": null, "error": error }));
+    };
+    let id = message.get("id").cloned();
+    let reply = |result: Result<Value, Value>| {
+        let id = id.clone()?;
+        Some(match result {
+            Ok(result) => json!({ "jsonrpc": "2.0", "id": id, "result": result }),
+            Err(error) => json!({ "jsonrpc": "2.0", "id": id, "error": error }),
+        })
+    };
+
+    let Some(method) = message.get("method").and_then(Value::as_str) else {
+        let error = rpc_error(-32600, "Invalid request", None);
+        return Some(json!({ "jsonrpc": "2.0", "id": id, "error": error }));
+    };
+    if !METHODS.contains(&method) {
+        return reply(Err(rpc_error(-32601, "Method not found", None)));
+    }
+    let mut params = match message.get("params") {
+        None | Some(Value::Null) => json!({}),
+        Some(Value::Object(params)) => Value::Object(params.clone()),
+        Some(_) => return reply(Err(rpc_error(-32602, "Params must be an object", None))),
+    };
+    params["command"] = json!(method);
+    let request = match serde_json::from_value::<ControlRequest>(params) {
+        Ok(request) => request,
+        Err(e) => return reply(Err(rpc_error(-32602, &e.to_string(), None))),
+    };
+
+    reply(match handle_request(request).await {
+        Ok(response) => Ok(match (response.status, response.queue) {
+            (Some(status), _) => json!(status),
+            (_, Some(queue)) => json!(queue),
+            (None, None) => Value::Null,
+        }),
+        Err(e) => Err(rpc_error(
+            -32000,
+            &e.to_string(),
+            Some(json!({ "code": e.code() })),
+        )),
+    })
+}
+
+async fn notification(event: PlayerEvent) -> Option<Value> {
+    match event {
+        PlayerEvent::TrackStarted(_) => Some(notify("track_changed", json!(status().await))),
+        PlayerEvent::StateChanged => Some(notify("state_changed", json!(status().await))),
+        PlayerEvent::Seeked(position) => {
+            let status = status().await;
+            let position = json!({ "position": position.as_secs(), "duration": status.duration });
+            Some(notify("position", position))
+        }
+        PlayerEvent::QueueChanged => {
+            let queue = handle_request(ControlRequest::Queue).await.ok()?.queue;
+            let queue_index = status().await.queue_index;
+            Some(notify(
+                "queue_changed",
+                json!({ "queue": queue, "queue_index": queue_index }),
+            ))
+        }
+        PlayerEvent::TrackEnded(_) => None,
+    }
+}
+
+async fn status() -> PlayerStatus {
+    handle_request(ControlRequest::Status)
+        .await
+        .ok()
+        .and_then(|response| response.status)
+        .unwrap_or_default()
+}
+
+fn notify(method: &str, params: Value) -> Value {
+    json!({ "jsonrpc": "2.0", "method": method, "params": params })
+}
+
+fn rpc_error(code: i32, message: &str, data: Option<Value>) -> Value {
+    match data {
+        Some(data) => json!({ "code": code, "message": message, "data": data }),
+        None => json!({ "code": code, "message": message }),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    async fn error_code(text: &str) -> (Value, i64) {
+        let reply = handle_message(text).await.unwrap();
+        assert_eq!(reply["jsonrpc"], "2.0");
+        (
+            reply["id"].clone(),
+            reply["error"]["code"].as_i64().unwrap(),
+        )
+    }
+
+    #[tokio::test]
+    async fn answers_malformed_messages() {
+        assert_eq!(error_code("{\"id\": 1,").await, (Value::Null, -32700));
+        assert_eq!(error_code(r#"{"id": 2}"#).await, (json!(2), -32600));
+        assert_eq!(
+            error_code(r#"{"id": 3, "method": 7}"#).await,
+            (json!(3), -32600)
+        );
+    }
+
+    #[tokio::test]
+    async fn answers_unknown_methods() {
+        let (id, code) =
+            error_code(r#"{"jsonrpc": "2.0", "id": "a", "method": "frobnicate"}"#).await;
+        assert_eq!((id, code), (json!("a"), -32601));
+        // Only the control socket commands in METHODS are open to the remote
+        let (_, code) =
+            error_code(r#"{"id": 1, "method": "reload", "params": {"songs": true}}"#).await;
+        assert_eq!(code, -32601);
+    }
+
+    #[tokio::test]
+    async fn answers_params_that_are_not_an_object() {
+        for params in ["[10]", "10", "\"by\""] {
+            let message = format!(r#"{{"id": 4, "method": "seek", "params": {}}}"#, params);
+            assert_eq!(error_code(&message).await, (json!(4), -32602), "{}", params);
+        }
+        let message = r#"{"id": 5, "method": "seek", "params": {"by": "ten"}}"#;
+        assert_eq!(error_code(message).await, (json!(5), -32602));
+    }
+
+    // Without an id the message is a notification and gets no reply, even when it fails
+    #[tokio::test]
+    async fn does_not_answer_notifications() {
+        for message in [
+            r#"{"method": "frobnicate"}"#,
+            r#"{"method": "seek", "params": [10]}"#,
+            r#"{"method": "seek", "params": {"by": "ten"}}"#,
+        ] {
+            assert_eq!(handle_message(message).await, None, "{}", message);
+        }
+    }
+}
@@ -0,0 +1,158 @@
+use chrono::{DateTime, Local, TimeZone};
+use serde::Serialize;
+use std::fs::{self, OpenOptions};
+use std::io::Write;
+use std::path::PathBuf;
+use std::time::Duration;
+
+use crate::config::app_config_path;
+use crate::error::AppError;
+use crate::player::PlayRecord;
+use crate::song::Song;
+
+const LOG_HEADER: &str = "#AUDIOSCROBBLER/1.1\n#TZ/UTC\n#CLIENT/bupl 0.1.0\n";
+
+// One line of an Audioscrobbler portable player log, fields are tab separated:
+// artist, album, title, track, duration, rating (L or S), timestamp, MusicBrainz track id
+#[derive(Debug, Clone, PartialEq)]
+pub struct ScrobbleLogEntry {
+    pub artist: String,
+    pub album: Option<String>,
+    pub title: String,
+    pub track: Option<u32>,
+    pub duration: u64,
+    pub listened: bool,
+    pub timestamp: u64,
+    pub musicbrainz_track: Option<String>,
+}
+
+#[derive(Debug, Default, Serialize)]
+pub struct ScrobbleImport {
+    pub imported: usize,
+    pub matched: usize,
+    pub skipped: usize,
+}
+
+impl ScrobbleLogEntry {
+    pub fn from_record(record: &PlayRecord) -> Option<Self> {
+        let song = &record.song;
+        Some(Self {
+            artist: song.artist.clone()?,
+            album: song.album.clone(),
+            title: song.title.clone()?,
+            track: song.track,
+            duration: song.duration.as_secs(),
+            listened: true,
+            timestamp: record.started,
+            musicbrainz_track: song.musicbrainz.recording.clone(),
+        })
+    }
+
+    pub fn append(&self) -> Result<(), AppError> {
+        let path = scrobbler_log_path()?;
+        let is_new = !path.exists();
+        fs::create_dir_all(app_config_path()?)?;
+
+        let mut log = OpenOptions::new().create(true).append(true).open(&path)?;
+        if is_new {
+            log.write_all(LOG_HEADER.as_bytes())?;
+        }
+        writeln!(log, "{}", self.to_line())?;
+        Ok(())
+    }
+
+    fn to_line(&self) -> String {
+        let field = |value: &str| value.replace(['\t', '\n'], " ");
+        [
+            field(&self.artist),
+            field(self.album.as_deref().unwrap_or_default()),
+            field(&self.title),
+            self.track.map(|t| t.to_string()).unwrap_or_default(),
+            self.duration.to_string(),
+            String::from(if self.listened { "L" } else { "S" }),
+            self.timestamp.to_string(),
+            self.musicbrainz_track.clone().unwrap_or_default(),
+        ]
+        .join("\t")
+    }
+
+    // Portable players without a clock set `#TZ/UNKNOWN` and log local time as if it were UTC
+    pub fn parse_log(log: &str) -> Vec<Self> {
+        let local_time = log
+            .lines()
+            .take_while(|line| line.starts_with('#'))
+            .any(|line| line.trim() == "#TZ/UNKNOWN");
+
+        log.lines()
+            .filter(|line| !line.starts_with('#') && !line.trim().is_empty())
+            .filter_map(|line| {
+                let fields: Vec<&str> = line.trim_end_matches('\r').split('\t').collect();
+                let text = |i: usize| {
+                    fields
+                        .get(i)
+                        .map(|f| f.to_string())
+                        .filter(|f| !f.is_empty())
+                };
+                let mut timestamp = fields.get(6)?.parse::<u64>().ok()?;
+                if local_time {
+                    timestamp = from_local_timestamp(timestamp)?;
+                }
+                Some(Self {
+                    artist: text(0)?,
+                    album: text(1),
+                    title: text(2)?,
+                    track: text(3).and_then(|t| t.parse().ok()),
+                    duration: text(4).and_then(|d| d.parse().ok()).unwrap_or_default(),
+                    listened: fields.get(5) == Some(&"L"),
+                    timestamp,
+                    musicbrainz_track: text(7),
+                })
+            })
+            .collect()
+    }
+
+    // Stands in for a song that isn't in the library
+    pub fn to_song(&self) -> Song {
+        let mut song = Song::new(PathBuf::new());
+        song.artist = Some(self.artist.clone());
+        song.artists = vec![self.artist.clone()];
+        song.album = self.album.clone();
+        song.title = Some(self.title.clone());
+        song.track = self.track;
+        song.duration = Duration::from_secs(self.duration);
+        song.musicbrainz.recording = self.musicbrainz_track.clone();
+        song
+    }
+
+    pub fn matches(&self, song: &Song) -> bool {
+        if let (Some(id), Some(recording)) = (&self.musicbrainz_track, &song.musicbrainz.recording)
+        {
+            return id == recording;
+        }
+        let same =
+            |a: &str, b: &Option<String>| b.as_ref().is_some_and(|b| a.eq_ignore_ascii_case(b));
+        same(&self.title, &song.title)
+            && (same(&self.artist, &song.artist)
+                || song
+                    .artists
+                    .iter()
+                    .any(|a| a.eq_ignore_ascii_case(&self.artist)))
+            && self
+                .album
+                .as_ref()
+                .is_none_or(|album| same(album, &song.album))
+    }
+}
+
+fn from_local_timestamp(timestamp: u64) -> Option<u64> {
+    let naive = DateTime::from_timestamp(timestamp as i64, 0)?.naive_utc();
+    let local = Local.from_local_datetime(&naive).earliest()?;
+    u64::try_from(local.timestamp()).ok()
+}
+
+pub fn scrobbler_log_path() -> Result<PathBuf, AppError> {
+    Ok(PathBuf::from(format!(
+        "{}/.scrobbler.log",
+        app_config_path()?
+    )))
+}
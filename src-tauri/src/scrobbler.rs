@@ -0,0 +1,746 @@
+use lazy_static::lazy_static;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use std::fs::{self, read_to_string};
+use std::sync::Arc;
+use std::time::{Duration, SystemTime};
+use tokio::sync::broadcast::error::RecvError;
+use tokio::sync::{Mutex, Notify};
+
+use crate::config::{app_config_path, CONFIG};
+use crate::error::AppError;
+use crate::library::unix_time;
+use crate::player::{PlayRecord, PlayerEvent, PLAYER_EVENTS};
+use crate::song::Song;
+
+const FLUSH_INTERVAL: Duration = Duration::from_secs(60);
+const MAX_BACKOFF: u64 = 24 * 60 * 60;
+// A hung service fails like an outage and is retried later
+const HTTP_TIMEOUT: Duration = Duration::from_secs(30);
+
+lazy_static! {
+    pub static ref SCROBBLE_QUEUE: Arc<Mutex<ScrobbleQueue>> =
+        Arc::new(Mutex::new(ScrobbleQueue::load().unwrap_or_default()));
+    // Wakes the sending loop as soon as a play is queued
+    static ref SCROBBLES_QUEUED: Notify = Notify::new();
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ScrobbleSettings {
+    pub listenbrainz: ListenBrainzSettings,
+    pub lastfm: LastFmSettings,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ListenBrainzSettings {
+    pub enabled: bool,
+    pub token: String,
+    pub base_url: String,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct LastFmSettings {
+    pub enabled: bool,
+    pub api_key: String,
+    pub api_secret: String,
+    pub session_key: Option<String>,
+    pub username: Option<String>,
+    pub base_url: String,
+    pub auth_url: String,
+}
+
+impl Default for ListenBrainzSettings {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            token: String::default(),
+            base_url: String::from("https://api.listenbrainz.org"),
+        }
+    }
+}
+
+impl Default for LastFmSettings {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            api_key: String::default(),
+            api_secret: String::default(),
+            session_key: None,
+            username: None,
+            base_url: String::from("https://ws.audioscrobbler.com/2.0/"),
+            auth_url: String::from("https://www.last.fm/api/auth/"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ScrobbleService {
+    ListenBrainz,
+    LastFm,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Scrobble {
+    pub artist: String,
+    pub title: String,
+    pub album: Option<String>,
+    pub album_artist: Option<String>,
+    pub track: Option<u32>,
+    pub duration: u64,
+    pub timestamp: u64,
+    pub recording_mbid: Option<String>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct QueuedScrobble {
+    pub service: ScrobbleService,
+    pub scrobble: Scrobble,
+    pub attempts: u32,
+    pub retry_at: u64,
+    pub last_error: Option<String>,
+}
+
+// Scrobbles not accepted yet, saved as scrobble_queue.json so they survive restarts
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct ScrobbleQueue {
+    pub pending: Vec<QueuedScrobble>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct LastFmAuth {
+    pub token: String,
+    pub url: String,
+}
+
+// Whether a failed submission is worth sending again later
+enum SubmitError {
+    Retry(String),
+    Rejected(String),
+}
+
+pub struct ScrobbleClient {
+    http: reqwest::Client,
+    settings: ScrobbleSettings,
+}
+
+impl ScrobbleSettings {
+    pub fn enabled_services(&self) -> Vec<ScrobbleService> {
+        let mut services = vec![];
+        if self.listenbrainz.enabled && !self.listenbrainz.token.is_empty() {
+            services.push(ScrobbleService::ListenBrainz);
+        }
+        if self.lastfm.enabled && self.lastfm.session_key.is_some() {
+            services.push(ScrobbleService::LastFm);
+        }
+        services
+    }
+}
+
+impl Scrobble {
+    pub fn from_song(song: &Song, timestamp: u64) -> Option<Self> {
+        Some(Self {
+            artist: song.artist.clone()?,
+            title: song.title.clone()?,
+            album: song.album.clone(),
+            album_artist: song.album_artist.clone(),
+            track: song.track,
+            duration: song.duration.as_secs(),
+            timestamp,
+            recording_mbid: song.musicbrainz.recording.clone(),
+        })
+    }
+
+    fn listenbrainz_payload(&self, listen_type: &str) -> Value {
+        let mut listen = json!({
+            "track_metadata": {
+                "artist_name": self.artist,
+                "track_name": self.title,
+                "release_name": self.album,
+                "additional_info": {
+                    "duration_ms": self.duration * 1000,
+                    "tracknumber": self.track,
+                    "recording_mbid": self.recording_mbid,
+                    "submission_client": "bupl",
+                },
+            },
+        });
+        if listen_type != "playing_now" {
+            listen["listened_at"] = json!(self.timestamp);
+        }
+        json!({ "listen_type": listen_type, "payload": [listen] })
+    }
+
+    fn lastfm_params(&self, with_timestamp: bool) -> Vec<(&'static str, String)> {
+        let mut params = vec![
+            ("artist", self.artist.clone()),
+            ("track", self.title.clone()),
+            ("duration", self.duration.to_string()),
+        ];
+        params.extend(self.album.clone().map(|album| ("album", album)));
+        params.extend(
+            self.album_artist
+                .clone()
+                .map(|artist| ("albumArtist", artist)),
+        );
+        params.extend(self.track.map(|track| ("trackNumber", track.to_string())));
+        params.extend(self.recording_mbid.clone().map(|mbid| ("mbid", mbid)));
+        if with_timestamp {
+            params.push(("timestamp", self.timestamp.to_string()));
+        }
+        params
+    }
+}
+
+impl ScrobbleQueue {
+    pub fn load() -> Result<Self, AppError> {
+        let queue = read_to_string(scrobble_queue_file()?).unwrap_or_default();
+        if queue.is_empty() {
+            return Ok(Self::default());
+        }
+        Ok(serde_json::from_str(&queue)?)
+    }
+
+    pub fn save(&self) -> Result<(), AppError> {
+        fs::create_dir_all(app_config_path()?)?;
+        fs::write(scrobble_queue_file()?, serde_json::to_string_pretty(self)?)?;
+        Ok(())
+    }
+
+    pub fn push(&mut self, service: ScrobbleService, scrobble: Scrobble) {
+        self.pending.push(QueuedScrobble {
+            service,
+            scrobble,
+            attempts: 0,
+            retry_at: 0,
+            last_error: None,
+        });
+    }
+}
+
+impl ScrobbleClient {
+    pub fn new(settings: ScrobbleSettings) -> Self {
+        Self::with_timeout(settings, HTTP_TIMEOUT)
+    }
+
+    fn with_timeout(settings: ScrobbleSettings, timeout: Duration) -> Self {
+        Self {
+            http: reqwest::Client::builder()
+                .timeout(timeout)
+                .build()
+                .unwrap_or_default(),
+            settings,
+        }
+    }
+
+    pub async fn now_playing(
+        &self,
+        service: ScrobbleService,
+        scrobble: &Scrobble,
+    ) -> Result<(), AppError> {
+        let result = match service {
+            ScrobbleService::ListenBrainz => {
+                self.listenbrainz(scrobble.listenbrainz_payload("playing_now"))
+                    .await
+            }
+            ScrobbleService::LastFm => self
+                .lastfm("track.updateNowPlaying", scrobble.lastfm_params(false))
+                .await
+                .map(|_| ()),
+        };
+        result.map_err(AppError::from)
+    }
+
+    async fn scrobble(
+        &self,
+        service: ScrobbleService,
+        scrobble: &Scrobble,
+    ) -> Result<(), SubmitError> {
+        match service {
+            ScrobbleService::ListenBrainz => {
+                self.listenbrainz(scrobble.listenbrainz_payload("single"))
+                    .await
+            }
+            ScrobbleService::LastFm => self
+                .lastfm("track.scrobble", scrobble.lastfm_params(true))
+                .await
+                .map(|_| ()),
+        }
+    }
+
+    async fn listenbrainz(&self, payload: Value) -> Result<(), SubmitError> {
+        let settings = &self.settings.listenbrainz;
+        let url = format!(
+            "{}/1/submit-listens",
+            settings.base_url.trim_end_matches('/')
+        );
+        let response = self
+            .http
+            .post(url)
+            .header("Authorization", format!("Token {}", settings.token))
+            .header("Content-Type", "application/json")
+            .body(payload.to_string())
+            .send()
+            .await
+            .map_err(|e| SubmitError::Retry(e.to_string()))?;
+
+        let status = response.status();
+        if status.is_success() {
+            return Ok(());
+        }
+        let message = format!("{} {}", status, response.text().await.unwrap_or_default());
+        // A bad token is kept for after the user fixes it, like a server outage
+        match status.as_u16() {
+            401 | 429 | 500.. => Err(SubmitError::Retry(message)),
+            _ => Err(SubmitError::Rejected(message)),
+        }
+    }
+
+    // Signed Last.fm API call, see https://www.last.fm/api/authspec
+    async fn lastfm(
+        &self,
+        method: &str,
+        mut params: Vec<(&str, String)>,
+    ) -> Result<Value, SubmitError> {
+        let settings = &self.settings.lastfm;
+        params.push(("method", method.to_string()));
+        params.push(("api_key", settings.api_key.clone()));
+        if let Some(session_key) = settings
+            .session_key
+            .clone()
+            .filter(|_| !method.starts_with("auth."))
+        {
+            params.push(("sk", session_key));
+        }
+        params.sort();
+        let signature: String = params
+            .iter()
+            .map(|(key, value)| format!("{}{}", key, value))
+            .collect();
+        params.push((
+            "api_sig",
+            format!("{:x}", md5::compute(signature + &settings.api_secret)),
+        ));
+        params.push(("format", String::from("json")));
+
+        let response = self
+            .http
+            .post(&settings.base_url)
+            .form(&params)
+            .send()
+            .await
+            .map_err(|e| SubmitError::Retry(e.to_string()))?;
+        let status = response.status();
+        let body: Value = response
+            .text()
+            .await
+            .ok()
+            .and_then(|body| serde_json::from_str(&body).ok())
+            .unwrap_or_default();
+
+        match body["error"].as_u64() {
+            None if status.is_success() => Ok(body),
+            None => Err(SubmitError::Retry(status.to_string())),
+            // Invalid session, service offline, temporarily unavailable and rate limits
+            Some(code @ (9 | 11 | 16 | 29)) => Err(SubmitError::Retry(lastfm_error(code, &body))),
+            Some(code) => Err(SubmitError::Rejected(lastfm_error(code, &body))),
+        }
+    }
+
+    // First step of desktop auth, the user approves the token at the returned url
+    pub async fn lastfm_begin_auth(&self) -> Result<LastFmAuth, AppError> {
+        let response = self.lastfm("auth.getToken", vec![]).await?;
+        let token = response["token"].as_str().unwrap_or_default().to_string();
+        let settings = &self.settings.lastfm;
+        Ok(LastFmAuth {
+            url: format!(
+                "{}?api_key={}&token={}",
+                settings.auth_url, settings.api_key, token
+            ),
+            token,
+        })
+    }
+
+    // Returns the session key and user name once the token was approved
+    pub async fn lastfm_finish_auth(&self, token: String) -> Result<(String, String), AppError> {
+        let response = self
+            .lastfm("auth.getSession", vec![("token", token)])
+            .await?;
+        let session = &response["session"];
+        match (session["key"].as_str(), session["name"].as_str()) {
+            (Some(key), Some(name)) => Ok((key.to_string(), name.to_string())),
+            _ => Err(AppError::Network(String::from(
+                "Last.fm returned no session",
+            ))),
+        }
+    }
+}
+
+impl From<SubmitError> for AppError {
+    fn from(e: SubmitError) -> Self {
+        match e {
+            SubmitError::Retry(e) | SubmitError::Rejected(e) => AppError::Network(e),
+        }
+    }
+}
+
+// Sends now playing and queues scrobbles as songs start and end. Nothing here waits on the
+// services, so no events are missed while one hangs
+pub async fn run_scrobbler() {
+    tokio::spawn(send_queued_scrobbles());
+    let mut events = PLAYER_EVENTS.subscribe();
+    loop {
+        match events.recv().await {
+            Ok(PlayerEvent::TrackStarted(song)) => {
+                tokio::spawn(async move { send_now_playing(&song).await });
+            }
+            Ok(PlayerEvent::TrackEnded(record)) => queue_scrobble(&record).await,
+            Ok(_) => {}
+            Err(RecvError::Lagged(missed)) => {
+                println!("Scrobbler missed {} player events", missed)
+            }
+            Err(RecvError::Closed) => break,
+        }
+    }
+}
+
+// Sends the queue when a play was queued and retries it every minute
+async fn send_queued_scrobbles() {
+    let mut ticks = tokio::time::interval(FLUSH_INTERVAL);
+    loop {
+        tokio::select! {
+            _ = ticks.tick() => {}
+            _ = SCROBBLES_QUEUED.notified() => {}
+        }
+        let client = ScrobbleClient::new(CONFIG.lock().await.scrobbling.clone());
+        if let Err(e) = flush_scrobbles(&client, false).await {
+            println!("Could not save scrobble queue: {}", e);
+        }
+    }
+}
+
+// Sends the scrobbles that are due, or all of them, and returns how many were accepted.
+// The queue is not held while sending, so new plays can be queued meanwhile
+pub async fn flush_scrobbles(client: &ScrobbleClient, retry_all: bool) -> Result<usize, AppError> {
+    let now = unix_time(SystemTime::now());
+    let enabled = client.settings.enabled_services();
+    let due: Vec<QueuedScrobble> = {
+        let mut queue = SCROBBLE_QUEUE.lock().await;
+        let (due, waiting) = std::mem::take(&mut queue.pending)
+            .into_iter()
+            .partition(|queued| {
+                (retry_all || queued.retry_at <= now) && enabled.contains(&queued.service)
+            });
+        queue.pending = waiting;
+        due
+    };
+    if due.is_empty() {
+        return Ok(0);
+    }
+
+    let (sent, failed) = submit(client, due, now).await;
+    let mut queue = SCROBBLE_QUEUE.lock().await;
+    queue.pending.splice(0..0, failed);
+    queue.save()?;
+    Ok(sent)
+}
+
+// Returns how many were sent and the ones to retry, failures wait twice as long each time
+async fn submit(
+    client: &ScrobbleClient,
+    due: Vec<QueuedScrobble>,
+    now: u64,
+) -> (usize, Vec<QueuedScrobble>) {
+    let mut sent = 0;
+    let mut failed = vec![];
+    for mut queued in due {
+        match client.scrobble(queued.service, &queued.scrobble).await {
+            Ok(()) => sent += 1,
+            Err(SubmitError::Rejected(e)) => {
+                println!("Scrobble of {} rejected: {}", queued.scrobble.title, e);
+            }
+            Err(SubmitError::Retry(e)) => {
+                let backoff = 60u64.saturating_mul(1 << queued.attempts.min(20));
+                queued.attempts += 1;
+                queued.retry_at = now + backoff.min(MAX_BACKOFF);
+                queued.last_error = Some(e);
+                failed.push(queued);
+            }
+        }
+    }
+    (sent, failed)
+}
+
+pub async fn send_now_playing(song: &Song) {
+    let settings = CONFIG.lock().await.scrobbling.clone();
+    let client = ScrobbleClient::new(settings.clone());
+    let now = unix_time(SystemTime::now());
+    if let Some(scrobble) = Scrobble::from_song(song, now) {
+        for service in settings.enabled_services() {
+            if let Err(e) = client.now_playing(service, &scrobble).await {
+                println!("Could not send now playing: {}", e);
+            }
+        }
+    }
+}
+
+// Queued scrobbles are sent with the next flush
+pub async fn queue_scrobble(record: &PlayRecord) {
+    if !record.counts_as_scrobble() {
+        return;
+    }
+    let settings = CONFIG.lock().await.scrobbling.clone();
+    if let Some(scrobble) = Scrobble::from_song(&record.song, record.started) {
+        let mut queue = SCROBBLE_QUEUE.lock().await;
+        for service in settings.enabled_services() {
+            queue.push(service, scrobble.clone());
+        }
+        if let Err(e) = queue.save() {
+            println!("Could not save scrobble queue: {}", e);
+        }
+    }
+    SCROBBLES_QUEUED.notify_one();
+}
+
+fn lastfm_error(code: u64, body: &Value) -> String {
+    format!(
+        "Last.fm error {}: {}",
+        code,
+        body["message"].as_str().unwrap_or_default()
+    )
+}
+
+fn scrobble_queue_file() -> Result<String, AppError> {
+    Ok(format!("{}/scrobble_queue.json", app_config_path()?))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader};
+    use tokio::net::TcpListener;
+    use tokio::time::timeout;
+
+    // Answers each connection with the next canned response and hands back what was sent
+    struct MockServer {
+        url: String,
+        requests: Arc<Mutex<Vec<String>>>,
+    }
+
+    impl MockServer {
+        async fn start(responses: Vec<(u16, &'static str)>) -> Self {
+            let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let url = format!("http://{}", listener.local_addr().unwrap());
+            let requests = Arc::new(Mutex::new(vec![]));
+            let seen = requests.clone();
+            tokio::spawn(async move {
+                for (status, body) in responses {
+                    let (stream, _) = listener.accept().await.unwrap();
+                    let mut stream = BufReader::new(stream);
+                    let mut request = String::new();
+                    let mut length = 0;
+                    loop {
+                        let mut line = String::new();
+                        stream.read_line(&mut line).await.unwrap();
+                        if let Some(value) = line.to_lowercase().strip_prefix("content-length:") {
+                            length = value.trim().parse().unwrap();
+                        }
+                        request += &line;
+                        if line.trim().is_empty() {
+                            break;
+                        }
+                    }
+                    let mut body_in = vec![0; length];
+                    stream.read_exact(&mut body_in).await.unwrap();
+                    request += &String::from_utf8_lossy(&body_in);
+                    seen.lock().await.push(request);
+
+                    let response = format!(
+                        "HTTP/1.1 {} X\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+                        status,
+                        body.len(),
+                        body
+                    );
+                    stream.write_all(response.as_bytes()).await.unwrap();
+                }
+            });
+            Self { url, requests }
+        }
+
+        async fn requests(&self) -> Vec<String> {
+            self.requests.lock().await.clone()
+        }
+    }
+
+    fn scrobble() -> Scrobble {
+        Scrobble {
+            artist: String::from("Artist"),
+            title: String::from("Title"),
+            album: Some(String::from("Album")),
+            album_artist: None,
+            track: Some(3),
+            duration: 200,
+            timestamp: 1_700_000_000,
+            recording_mbid: None,
+        }
+    }
+
+    fn listenbrainz(url: &str) -> ScrobbleSettings {
+        ScrobbleSettings {
+            listenbrainz: ListenBrainzSettings {
+                enabled: true,
+                token: String::from("secret"),
+                base_url: url.to_string(),
+            },
+            ..ScrobbleSettings::default()
+        }
+    }
+
+    fn lastfm(url: &str) -> ScrobbleSettings {
+        ScrobbleSettings {
+            lastfm: LastFmSettings {
+                enabled: true,
+                api_key: String::from("key"),
+                api_secret: String::from("shh"),
+                session_key: Some(String::from("session")),
+                base_url: url.to_string(),
+                ..LastFmSettings::default()
+            },
+            ..ScrobbleSettings::default()
+        }
+    }
+
+    fn queued(service: ScrobbleService) -> QueuedScrobble {
+        QueuedScrobble {
+            service,
+            scrobble: scrobble(),
+            attempts: 0,
+            retry_at: 0,
+            last_error: None,
+        }
+    }
+
+    #[tokio::test]
+    async fn listenbrainz_submits_a_listen() {
+        let server = MockServer::start(vec![(200, r#"{"status":"ok"}"#)]).await;
+        let client = ScrobbleClient::new(listenbrainz(&server.url));
+        let result = client
+            .scrobble(ScrobbleService::ListenBrainz, &scrobble())
+            .await;
+        assert!(result.is_ok());
+
+        let requests = server.requests().await;
+        assert!(requests[0].starts_with("POST /1/submit-listens "));
+        assert!(requests[0].contains("authorization: Token secret"));
+        assert!(requests[0].contains(r#""listened_at":1700000000"#));
+        assert!(requests[0].contains(r#""listen_type":"single""#));
+    }
+
+    #[tokio::test]
+    async fn listenbrainz_retries_outages_and_drops_bad_listens() {
+        let server = MockServer::start(vec![(503, ""), (400, "bad listen")]).await;
+        let client = ScrobbleClient::new(listenbrainz(&server.url));
+        let service = ScrobbleService::ListenBrainz;
+        assert!(matches!(
+            client.scrobble(service, &scrobble()).await,
+            Err(SubmitError::Retry(_))
+        ));
+        assert!(matches!(
+            client.scrobble(service, &scrobble()).await,
+            Err(SubmitError::Rejected(_))
+        ));
+    }
+
+    #[tokio::test]
+    async fn lastfm_signs_calls() {
+        let server = MockServer::start(vec![(200, r#"{"scrobbles":{}}"#)]).await;
+        let client = ScrobbleClient::new(lastfm(&server.url));
+        let result = client.scrobble(ScrobbleService::LastFm, &scrobble()).await;
+        assert!(result.is_ok());
+
+        let request = &server.requests().await[0];
+        let form = request.rsplit("\r\n").next().unwrap();
+        let params: Vec<(String, String)> = form
+            .split('&')
+            .filter_map(|pair| pair.split_once('='))
+            .map(|(key, value)| (key.to_string(), value.replace('+', " ")))
+            .collect();
+        let param = |key: &str| {
+            params
+                .iter()
+                .find(|(k, _)| k == key)
+                .map(|(_, v)| v.clone())
+                .unwrap()
+        };
+        assert_eq!(param("method"), "track.scrobble");
+        assert_eq!(param("sk"), "session");
+
+        let mut signed: Vec<_> = params
+            .iter()
+            .filter(|(key, _)| key != "api_sig" && key != "format")
+            .collect();
+        signed.sort();
+        let signature: String = signed.iter().map(|(k, v)| format!("{}{}", k, v)).collect();
+        let expected = format!("{:x}", md5::compute(signature + "shh"));
+        assert_eq!(param("api_sig"), expected);
+    }
+
+    #[tokio::test]
+    async fn lastfm_error_codes_decide_on_retries() {
+        let server = MockServer::start(vec![
+            (200, r#"{"error":16,"message":"Try again"}"#),
+            (200, r#"{"error":6,"message":"Invalid parameters"}"#),
+        ])
+        .await;
+        let client = ScrobbleClient::new(lastfm(&server.url));
+        let service = ScrobbleService::LastFm;
+        assert!(matches!(
+            client.scrobble(service, &scrobble()).await,
+            Err(SubmitError::Retry(_))
+        ));
+        assert!(matches!(
+            client.scrobble(service, &scrobble()).await,
+            Err(SubmitError::Rejected(_))
+        ));
+    }
+
+    #[tokio::test]
+    async fn hung_services_time_out() {
+        // Accepts the connection but never answers
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+        tokio::spawn(async move {
+            let _stream = listener.accept().await;
+            tokio::time::sleep(Duration::from_secs(60)).await;
+        });
+
+        let client = ScrobbleClient::with_timeout(listenbrainz(&url), Duration::from_millis(200));
+        let result = timeout(
+            Duration::from_secs(5),
+            client.scrobble(ScrobbleService::ListenBrainz, &scrobble()),
+        )
+        .await;
+        assert!(matches!(result, Ok(Err(SubmitError::Retry(_)))));
+    }
+
+    #[tokio::test]
+    async fn failed_scrobbles_back_off() {
+        let server = MockServer::start(vec![(200, "{}"), (500, ""), (400, "")]).await;
+        let client = ScrobbleClient::new(listenbrainz(&server.url));
+        let due = vec![
+            queued(ScrobbleService::ListenBrainz),
+            queued(ScrobbleService::ListenBrainz),
+            queued(ScrobbleService::ListenBrainz),
+        ];
+
+        let (sent, failed) = submit(&client, due, 1000).await;
+        assert_eq!(sent, 1);
+        assert_eq!(failed.len(), 1);
+        assert_eq!(failed[0].attempts, 1);
+        assert_eq!(failed[0].retry_at, 1060);
+        assert!(failed[0].last_error.is_some());
+    }
+}
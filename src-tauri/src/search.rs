@@ -0,0 +1,358 @@
+use lazy_static::lazy_static;
+use serde::Serialize;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::path::PathBuf;
+use std::sync::Arc;
+use tokio::sync::Mutex;
+
+use crate::song::Song;
+
+lazy_static! {
+    pub static ref SEARCH_INDEX: Arc<Mutex<SearchIndex>> =
+        Arc::new(Mutex::new(SearchIndex::default()));
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+enum SearchField {
+    Title,
+    Artist,
+    Album,
+    AlbumArtist,
+    Genre,
+}
+
+const SEARCH_FIELDS: [SearchField; 5] = [
+    SearchField::Title,
+    SearchField::Artist,
+    SearchField::Album,
+    SearchField::AlbumArtist,
+    SearchField::Genre,
+];
+
+#[derive(Debug, Default, Serialize)]
+pub struct SearchResults {
+    pub songs: Vec<Song>,
+    pub albums: Vec<AlbumHit>,
+    pub artists: Vec<ArtistHit>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct AlbumHit {
+    pub id: String,
+    pub name: String,
+    pub artist: String,
+    pub year: Option<u32>,
+    pub cover_path: PathBuf,
+    pub song_count: usize,
+}
+
+#[derive(Debug, Serialize)]
+pub struct ArtistHit {
+    pub name: String,
+    pub song_count: usize,
+}
+
+// One song with its searchable fields lowercased and folded ahead of time
+#[derive(Debug)]
+struct IndexEntry {
+    song: Song,
+    fields: Vec<(SearchField, String)>,
+}
+
+#[derive(Debug)]
+enum Term {
+    Text(Option<SearchField>, String),
+    Year(u32, u32),
+}
+
+// Kept in sync with the library cache, only songs whose tags changed are re-indexed
+#[derive(Debug, Default)]
+pub struct SearchIndex {
+    entries: HashMap<PathBuf, IndexEntry>,
+}
+
+impl SearchIndex {
+    pub fn sync(&mut self, songs: &[Song]) {
+        let paths: HashSet<PathBuf> = songs.iter().map(|song| song.get_path()).collect();
+        self.entries.retain(|path, _| paths.contains(path));
+
+        for song in songs {
+            let unchanged = self
+                .entries
+                .get(&song.get_path())
+                .is_some_and(|entry| same_fields(&entry.song, song));
+            if !unchanged {
+                self.insert(song);
+            }
+        }
+    }
+
+    pub fn insert(&mut self, song: &Song) {
+        let fields = SEARCH_FIELDS
+            .iter()
+            .flat_map(|field| {
+                field_values(song, *field)
+                    .into_iter()
+                    .map(|v| (*field, normalize(&v)))
+            })
+            .collect();
+        self.entries.insert(
+            song.get_path(),
+            IndexEntry {
+                song: song.clone(),
+                fields,
+            },
+        );
+    }
+
+    pub fn search(&self, query: &str, limit: usize) -> SearchResults {
+        let terms = parse_query(query);
+        if terms.is_empty() {
+            return SearchResults::default();
+        }
+
+        let mut songs = vec![];
+        let mut albums: BTreeMap<String, (u32, Vec<&Song>)> = BTreeMap::new();
+        let mut artists: BTreeMap<String, (u32, usize)> = BTreeMap::new();
+
+        for entry in self.entries.values() {
+            let Some((score, matched)) = entry.score(&terms) else {
+                continue;
+            };
+            songs.push((score, &entry.song));
+
+            if entry.song.album.is_some()
+                && matched
+                    .iter()
+                    .any(|f| !matches!(f, SearchField::Title | SearchField::Genre))
+            {
+                let album = albums.entry(entry.song.album_key()).or_default();
+                album.0 = album.0.max(score);
+                album.1.push(&entry.song);
+            }
+            if matched.contains(&SearchField::Artist) {
+                for artist in field_values(&entry.song, SearchField::Artist) {
+                    let hit = artists.entry(artist).or_default();
+                    hit.0 = hit.0.max(score);
+                    hit.1 += 1;
+                }
+            }
+        }
+
+        songs.sort_by(|a, b| b.0.cmp(&a.0).then_with(|| a.1.title.cmp(&b.1.title)));
+        let mut albums: Vec<_> = albums.into_iter().collect();
+        albums.sort_by(|a, b| b.1 .0.cmp(&a.1 .0).then_with(|| a.0.cmp(&b.0)));
+        let mut artists: Vec<_> = artists.into_iter().collect();
+        artists.sort_by(|a, b| b.1 .0.cmp(&a.1 .0).then_with(|| b.1 .1.cmp(&a.1 .1)));
+
+        SearchResults {
+            songs: songs
+                .into_iter()
+                .take(limit)
+                .map(|(_, song)| song.clone())
+                .collect(),
+            albums: albums
+                .into_iter()
+                .take(limit)
+                .map(|(id, (_, songs))| {
+                    let first = songs[0];
+                    AlbumHit {
+                        id,
+                        name: first.album.clone().unwrap_or_default(),
+                        artist: first
+                            .album_artist
+                            .clone()
+                            .or(first.artist.clone())
+                            .unwrap_or_default(),
+                        year: first.year,
+                        cover_path: first.get_cover_path(),
+                        song_count: songs.len(),
+                    }
+                })
+                .collect(),
+            artists: artists
+                .into_iter()
+                .take(limit)
+                .map(|(name, (_, song_count))| ArtistHit { name, song_count })
+                .collect(),
+        }
+    }
+}
+
+impl IndexEntry {
+    // Every term has to match, the score is the sum of each term's best field
+    fn score(&self, terms: &[Term]) -> Option<(u32, HashSet<SearchField>)> {
+        let mut total = 0;
+        let mut matched = HashSet::new();
+
+        for term in terms {
+            match term {
+                Term::Year(from, to) => {
+                    let year = self.song.year?;
+                    if year < *from || year > *to {
+                        return None;
+                    }
+                    total += 50;
+                }
+                Term::Text(field, text) => {
+                    let (best, best_field) = self
+                        .fields
+                        .iter()
+                        .filter(|(f, _)| field.is_none_or(|field| field == *f))
+                        .map(|(f, value)| (match_score(value, text) * field_weight(*f) / 10, *f))
+                        .max_by_key(|(score, _)| *score)?;
+                    if best == 0 {
+                        return None;
+                    }
+                    total += best;
+                    matched.insert(best_field);
+                }
+            }
+        }
+        Some((total, matched))
+    }
+}
+
+fn field_weight(field: SearchField) -> u32 {
+    match field {
+        SearchField::Title => 10,
+        SearchField::Artist => 9,
+        SearchField::Album => 8,
+        SearchField::AlbumArtist => 7,
+        SearchField::Genre => 5,
+    }
+}
+
+fn field_values(song: &Song, field: SearchField) -> Vec<String> {
+    match field {
+        SearchField::Title => song.title.iter().cloned().collect(),
+        SearchField::Artist if song.artists.is_empty() => song.artist.iter().cloned().collect(),
+        SearchField::Artist => song.artists.clone(),
+        SearchField::Album => song.album.iter().cloned().collect(),
+        SearchField::AlbumArtist => song.album_artist.iter().cloned().collect(),
+        SearchField::Genre if song.genres.is_empty() => song.genre.iter().cloned().collect(),
+        SearchField::Genre => song.genres.clone(),
+    }
+}
+
+fn same_fields(a: &Song, b: &Song) -> bool {
+    a.year == b.year
+        && a.get_cover_path() == b.get_cover_path()
+        && SEARCH_FIELDS
+            .iter()
+            .all(|field| field_values(a, *field) == field_values(b, *field))
+}
+
+// Exact > word prefix > substring > typo or subsequence, 0 when unrelated
+fn match_score(value: &str, term: &str) -> u32 {
+    if value == term {
+        return 100;
+    }
+    if value.starts_with(term) {
+        return 90;
+    }
+    if value.split_whitespace().any(|word| word.starts_with(term)) {
+        return 80;
+    }
+    if value.contains(term) {
+        return 60;
+    }
+    let (value, term) = (value.as_bytes(), term.as_bytes());
+    if term.len() >= 4
+        && value.split(|c| *c == b' ').any(|word| {
+            within_one_edit(word, term)
+                || within_one_edit(&word[..word.len().min(term.len())], term)
+        })
+    {
+        return 40;
+    }
+    if is_subsequence(value, term) {
+        return 20;
+    }
+    0
+}
+
+// Compares bytes, accents are folded so most text is ASCII by now
+fn within_one_edit(a: &[u8], b: &[u8]) -> bool {
+    if a.len().abs_diff(b.len()) > 1 {
+        return false;
+    }
+
+    let prefix = a.iter().zip(b).take_while(|(x, y)| x == y).count();
+    let (a, b) = (&a[prefix..], &b[prefix..]);
+    a.get(1..) == b.get(1..) || Some(a) == b.get(1..) || a.get(1..) == Some(b)
+}
+
+fn is_subsequence(value: &[u8], term: &[u8]) -> bool {
+    let mut value = value.iter();
+    term.iter()
+        .filter(|c| **c != b' ')
+        .all(|c| value.any(|v| v == c))
+}
+
+// `artist:radiohead year:1997 "ok computer"`, years also accept ranges like 1990-1999
+fn parse_query(query: &str) -> Vec<Term> {
+    let mut terms = vec![];
+    let mut chars = query.chars().peekable();
+
+    while chars.peek().is_some() {
+        let mut word = String::new();
+        let mut quoted = false;
+        while let Some(&c) = chars.peek() {
+            chars.next();
+            match c {
+                '"' => quoted = !quoted,
+                c if c.is_whitespace() && !quoted => break,
+                c => word.push(c),
+            }
+        }
+        if word.is_empty() {
+            continue;
+        }
+
+        let (field, text) = match word.split_once(':') {
+            Some((field, text)) if !text.is_empty() => (Some(field.to_lowercase()), text),
+            _ => (None, word.as_str()),
+        };
+        let field = match field.as_deref() {
+            None => None,
+            Some("year") => {
+                let (from, to) = text.split_once('-').unwrap_or((text, text));
+                if let (Ok(from), Ok(to)) = (from.parse(), to.parse()) {
+                    terms.push(Term::Year(from, to));
+                }
+                continue;
+            }
+            Some("title") => Some(SearchField::Title),
+            Some("artist") => Some(SearchField::Artist),
+            Some("album") => Some(SearchField::Album),
+            Some("albumartist" | "album_artist") => Some(SearchField::AlbumArtist),
+            Some("genre") => Some(SearchField::Genre),
+            // Unknown prefixes are searched as plain text, e.g. `re:member`
+            Some(_) => {
+                terms.push(Term::Text(None, normalize(&word)));
+                continue;
+            }
+        };
+        terms.push(Term::Text(field, normalize(text)));
+    }
+    terms
+}
+
+fn normalize(text: &str) -> String {
+    text.to_lowercase().chars().map(fold_accent).collect()
+}
+
+fn fold_accent(c: char) -> char {
+    match c {
+        'à' | 'á' | 'â' | 'ã' | 'ä' | 'å' => 'a',
+        'ç' => 'c',
+        'è' | 'é' | 'ê' | 'ë' => 'e',
+        'ì' | 'í' | 'î' | 'ï' => 'i',
+        'ñ' => 'n',
+        'ò' | 'ó' | 'ô' | 'õ' | 'ö' | 'ø' => 'o',
+        'ù' | 'ú' | 'û' | 'ü' => 'u',
+        'ý' | 'ÿ' => 'y',
+        c => c,
+    }
+}
@@ -0,0 +1,151 @@
+use lazy_static::lazy_static;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap};
+use std::sync::Arc;
+use tauri::plugin::TauriPlugin;
+use tauri::{AppHandle, Runtime};
+use tauri_plugin_global_shortcut::{GlobalShortcutExt, Shortcut, ShortcutState};
+use tokio::sync::Mutex;
+
+use crate::config::CONFIG;
+use crate::control::{dispatch, ControlRequest};
+
+// Same step as the arrow keys in the window
+const VOLUME_STEP: f32 = 0.05;
+
+lazy_static! {
+    static ref REGISTERED: Arc<Mutex<HashMap<Shortcut, ShortcutAction>>> =
+        Arc::new(Mutex::new(HashMap::new()));
+    static ref CONFLICTS: Arc<Mutex<Vec<ShortcutConflict>>> = Arc::new(Mutex::new(vec![]));
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ShortcutAction {
+    PlayPause,
+    Next,
+    Prev,
+    VolumeUp,
+    VolumeDown,
+}
+
+impl ShortcutAction {
+    fn request(self) -> ControlRequest {
+        match self {
+            ShortcutAction::PlayPause => ControlRequest::PlayPause,
+            ShortcutAction::Next => ControlRequest::Next,
+            ShortcutAction::Prev => ControlRequest::Prev,
+            ShortcutAction::VolumeUp => ControlRequest::AdjustVolume { by: VOLUME_STEP },
+            ShortcutAction::VolumeDown => ControlRequest::AdjustVolume { by: -VOLUME_STEP },
+        }
+    }
+}
+
+// Combinations like `CommandOrControl+Alt+Right`, media keys are `MediaPlayPause` etc.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ShortcutSettings {
+    pub enabled: bool,
+    pub bindings: BTreeMap<ShortcutAction, Vec<String>>,
+}
+
+impl Default for ShortcutSettings {
+    fn default() -> Self {
+        let binding = |keys: &[&str]| keys.iter().map(|key| key.to_string()).collect();
+        Self {
+            enabled: true,
+            bindings: BTreeMap::from([
+                (ShortcutAction::PlayPause, binding(&["MediaPlayPause"])),
+                (ShortcutAction::Next, binding(&["MediaTrackNext"])),
+                (ShortcutAction::Prev, binding(&["MediaTrackPrevious"])),
+                (
+                    ShortcutAction::VolumeUp,
+                    binding(&["CommandOrControl+Alt+Up"]),
+                ),
+                (
+                    ShortcutAction::VolumeDown,
+                    binding(&["CommandOrControl+Alt+Down"]),
+                ),
+            ]),
+        }
+    }
+}
+
+// A binding that is not active, because it is malformed, doubled or taken by another app
+#[derive(Debug, Clone, Serialize)]
+pub struct ShortcutConflict {
+    pub action: ShortcutAction,
+    pub shortcut: String,
+    pub reason: String,
+}
+
+pub fn plugin<R: Runtime>() -> TauriPlugin<R> {
+    tauri_plugin_global_shortcut::Builder::new()
+        .with_handler(|_app, shortcut, event| {
+            if event.state() != ShortcutState::Pressed {
+                return;
+            }
+            let shortcut = *shortcut;
+            tauri::async_runtime::spawn(async move {
+                let Some(action) = REGISTERED.lock().await.get(&shortcut).copied() else {
+                    return;
+                };
+                if let Err(e) = dispatch(action.request()).await {
+                    println!("Shortcut failed: {}", e);
+                }
+            });
+        })
+        .build()
+}
+
+// Replaces every registered shortcut with the ones in the config
+pub async fn register_shortcuts<R: Runtime>(app: &AppHandle<R>) -> Vec<ShortcutConflict> {
+    let settings = CONFIG.lock().await.shortcuts.clone();
+    let global_shortcut = app.global_shortcut();
+    if let Err(e) = global_shortcut.unregister_all() {
+        println!("Could not unregister shortcuts: {}", e);
+    }
+
+    let mut registered: HashMap<Shortcut, ShortcutAction> = HashMap::new();
+    let mut conflicts = vec![];
+    let bindings = settings.bindings.iter().filter(|_| settings.enabled);
+    for (action, keys) in bindings {
+        for keys in keys {
+            let conflict = |reason: String| ShortcutConflict {
+                action: *action,
+                shortcut: keys.clone(),
+                reason,
+            };
+            let shortcut = match keys.parse::<Shortcut>() {
+                Ok(shortcut) => shortcut,
+                Err(e) => {
+                    conflicts.push(conflict(e.to_string()));
+                    continue;
+                }
+            };
+            if let Some(other) = registered.get(&shortcut) {
+                conflicts.push(conflict(format!("Already bound to {:?}", other)));
+                continue;
+            }
+            if let Err(e) = global_shortcut.register(shortcut) {
+                conflicts.push(conflict(format!("Taken by another application: {}", e)));
+                continue;
+            }
+            registered.insert(shortcut, *action);
+        }
+    }
+
+    for conflict in &conflicts {
+        println!(
+            "Shortcut {} for {:?} is not active: {}",
+            conflict.shortcut, conflict.action, conflict.reason
+        );
+    }
+    *REGISTERED.lock().await = registered;
+    *CONFLICTS.lock().await = conflicts.clone();
+    conflicts
+}
+
+pub async fn shortcut_conflicts() -> Vec<ShortcutConflict> {
+    CONFLICTS.lock().await.clone()
+}
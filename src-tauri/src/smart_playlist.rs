@@ -0,0 +1,570 @@
+use rand::seq::SliceRandom;
+use rand::thread_rng;
+use serde::{Deserialize, Serialize};
+use std::cmp::Ordering;
+use std::collections::BTreeMap;
+use std::fs::{self, read_to_string};
+use std::path::PathBuf;
+use std::time::SystemTime;
+
+use crate::config::app_config_path;
+use crate::error::AppError;
+use crate::library::{unix_time, SongStats};
+use crate::song::Song;
+
+// A playlist defined by a query such as
+// `genre = "Jazz" AND year < 1970 AND rating >= 4` or `added in last 30 days`
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SmartPlaylist {
+    pub id: String,
+    pub name: String,
+    pub query: String,
+    pub sort_by: Option<String>,
+    #[serde(default)]
+    pub descending: bool,
+    pub limit: Option<usize>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Field {
+    Title,
+    Artist,
+    Album,
+    AlbumArtist,
+    Composer,
+    Genre,
+    Path,
+    Year,
+    Track,
+    Disc,
+    Duration,
+    Rating,
+    PlayCount,
+    SkipCount,
+    Added,
+    LastPlayed,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Op {
+    Eq,
+    NotEq,
+    Lt,
+    LtEq,
+    Gt,
+    GtEq,
+    Contains,
+    NotContains,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Value {
+    Number(f64),
+    Text(String),
+}
+
+#[derive(Debug, Clone)]
+enum Expr {
+    And(Box<Expr>, Box<Expr>),
+    Or(Box<Expr>, Box<Expr>),
+    Not(Box<Expr>),
+    Compare(Field, Op, Value),
+    // `added in last 30 days`, stored in seconds
+    Within(Field, u64),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Token {
+    Word(String),
+    Text(String),
+    Op(Op),
+    Open,
+    Close,
+}
+
+impl SmartPlaylist {
+    pub fn new(name: String, query: String) -> Self {
+        Self {
+            id: format!(
+                "{:x}{:08x}",
+                unix_time(SystemTime::now()),
+                rand::random::<u32>()
+            ),
+            name,
+            query,
+            sort_by: None,
+            descending: false,
+            limit: None,
+        }
+    }
+
+    pub fn load_all() -> Result<Vec<Self>, AppError> {
+        let playlists = read_to_string(smart_playlists_file()?).unwrap_or_default();
+        if playlists.is_empty() {
+            return Ok(vec![]);
+        }
+        Ok(serde_json::from_str(&playlists)?)
+    }
+
+    pub fn save_all(playlists: &[Self]) -> Result<(), AppError> {
+        fs::create_dir_all(app_config_path()?)?;
+        fs::write(
+            smart_playlists_file()?,
+            serde_json::to_string_pretty(playlists)?,
+        )?;
+        Ok(())
+    }
+
+    pub fn load(id: &str) -> Result<Self, AppError> {
+        Self::load_all()?
+            .into_iter()
+            .find(|playlist| playlist.id == id)
+            .ok_or(AppError::PlaylistNotFound(id.to_string()))
+    }
+
+    pub fn validate(&self) -> Result<(), AppError> {
+        parse_query(&self.query)?;
+        if let Some(sort_by) = &self.sort_by {
+            if sort_by != "random" {
+                parse_field(sort_by)?;
+            }
+        }
+        Ok(())
+    }
+
+    pub fn evaluate(
+        &self,
+        songs: &[Song],
+        stats: &BTreeMap<PathBuf, SongStats>,
+    ) -> Result<Vec<Song>, AppError> {
+        let expr = parse_query(&self.query)?;
+        let default_stats = SongStats::default();
+        let now = unix_time(SystemTime::now());
+
+        let mut matches: Vec<(&Song, &SongStats)> = songs
+            .iter()
+            .map(|song| (song, stats.get(&song.get_path()).unwrap_or(&default_stats)))
+            .filter(|(song, stats)| expr.matches(song, stats, now))
+            .collect();
+
+        match self.sort_by.as_deref() {
+            Some("random") => matches.shuffle(&mut thread_rng()),
+            Some(sort_by) => {
+                let field = parse_field(sort_by)?;
+                matches.sort_by(|a, b| {
+                    compare_values(
+                        &field.value(a.0, a.1),
+                        &field.value(b.0, b.1),
+                        self.descending,
+                    )
+                });
+            }
+            None => {}
+        }
+
+        Ok(matches
+            .into_iter()
+            .take(self.limit.unwrap_or(usize::MAX))
+            .map(|(song, _)| song.clone())
+            .collect())
+    }
+}
+
+impl Field {
+    fn is_text(&self) -> bool {
+        matches!(
+            self,
+            Field::Title
+                | Field::Artist
+                | Field::Album
+                | Field::AlbumArtist
+                | Field::Composer
+                | Field::Genre
+                | Field::Path
+        )
+    }
+
+    fn value(&self, song: &Song, stats: &SongStats) -> Vec<Value> {
+        let text = |value: &Option<String>| value.iter().map(|v| Value::Text(v.clone())).collect();
+        let texts = |values: &Vec<String>| values.iter().map(|v| Value::Text(v.clone())).collect();
+        let number = |value: Option<f64>| value.map(Value::Number).into_iter().collect();
+
+        match self {
+            Field::Title => text(&song.title),
+            Field::Artist if song.artists.is_empty() => text(&song.artist),
+            Field::Artist => texts(&song.artists),
+            Field::Album => text(&song.album),
+            Field::AlbumArtist => text(&song.album_artist),
+            Field::Composer => text(&song.composer),
+            Field::Genre if song.genres.is_empty() => text(&song.genre),
+            Field::Genre => texts(&song.genres),
+            Field::Path => vec![Value::Text(song.get_path().display().to_string())],
+            Field::Year => number(song.year.map(f64::from)),
+            Field::Track => number(song.track.map(f64::from)),
+            Field::Disc => number(song.disc.map(f64::from)),
+            Field::Duration => number(Some(song.duration.as_secs_f64())),
+            Field::Rating => number(stats.rating.map(f64::from)),
+            Field::PlayCount => number(Some(f64::from(stats.play_count))),
+            Field::SkipCount => number(Some(f64::from(stats.skip_count))),
+            Field::Added => number(Some(stats.date_added as f64)),
+            Field::LastPlayed => number(stats.last_played.map(|t| t as f64)),
+        }
+    }
+}
+
+impl Expr {
+    fn matches(&self, song: &Song, stats: &SongStats, now: u64) -> bool {
+        match self {
+            Expr::And(a, b) => a.matches(song, stats, now) && b.matches(song, stats, now),
+            Expr::Or(a, b) => a.matches(song, stats, now) || b.matches(song, stats, now),
+            Expr::Not(a) => !a.matches(song, stats, now),
+            Expr::Within(field, seconds) => field.value(song, stats).iter().any(|value| {
+                matches!(value, Value::Number(t) if *t >= now.saturating_sub(*seconds) as f64)
+            }),
+            Expr::Compare(field, op, expected) => {
+                let values = field.value(song, stats);
+                match op {
+                    // Negated operators must hold for every value, e.g. for each genre
+                    Op::NotEq | Op::NotContains => values.iter().all(|v| compare(v, *op, expected)),
+                    _ => values.iter().any(|v| compare(v, *op, expected)),
+                }
+            }
+        }
+    }
+}
+
+fn compare(value: &Value, op: Op, expected: &Value) -> bool {
+    match (value, expected) {
+        (Value::Number(a), Value::Number(b)) => match op {
+            Op::Eq | Op::Contains => a == b,
+            Op::NotEq | Op::NotContains => a != b,
+            Op::Lt => a < b,
+            Op::LtEq => a <= b,
+            Op::Gt => a > b,
+            Op::GtEq => a >= b,
+        },
+        (Value::Text(a), Value::Text(b)) => {
+            let (a, b) = (a.to_lowercase(), b.to_lowercase());
+            match op {
+                Op::Eq => a == b,
+                Op::NotEq => a != b,
+                Op::Lt => a < b,
+                Op::LtEq => a <= b,
+                Op::Gt => a > b,
+                Op::GtEq => a >= b,
+                Op::Contains => a.contains(&b),
+                Op::NotContains => !a.contains(&b),
+            }
+        }
+        _ => false,
+    }
+}
+
+fn compare_values(a: &[Value], b: &[Value], descending: bool) -> Ordering {
+    let ordering = match (a.first(), b.first()) {
+        (Some(Value::Number(a)), Some(Value::Number(b))) => a.total_cmp(b),
+        (Some(Value::Text(a)), Some(Value::Text(b))) => a.to_lowercase().cmp(&b.to_lowercase()),
+        // Songs without the field go last, whichever the direction
+        (Some(_), None) => return Ordering::Less,
+        (None, Some(_)) => return Ordering::Greater,
+        _ => Ordering::Equal,
+    };
+    match descending {
+        true => ordering.reverse(),
+        false => ordering,
+    }
+}
+
+fn parse_field(name: &str) -> Result<Field, AppError> {
+    match name.to_lowercase().as_str() {
+        "title" => Ok(Field::Title),
+        "artist" => Ok(Field::Artist),
+        "album" => Ok(Field::Album),
+        "album_artist" | "albumartist" => Ok(Field::AlbumArtist),
+        "composer" => Ok(Field::Composer),
+        "genre" => Ok(Field::Genre),
+        "path" => Ok(Field::Path),
+        "year" => Ok(Field::Year),
+        "track" => Ok(Field::Track),
+        "disc" => Ok(Field::Disc),
+        "duration" => Ok(Field::Duration),
+        "rating" => Ok(Field::Rating),
+        "play_count" | "plays" => Ok(Field::PlayCount),
+        "skip_count" | "skips" => Ok(Field::SkipCount),
+        "added" | "date_added" => Ok(Field::Added),
+        "played" | "last_played" => Ok(Field::LastPlayed),
+        _ => Err(AppError::InvalidQuery(format!("unknown field {}", name))),
+    }
+}
+
+fn tokenize(query: &str) -> Result<Vec<Token>, AppError> {
+    let mut tokens = vec![];
+    let mut chars = query.chars().peekable();
+
+    while let Some(&c) = chars.peek() {
+        match c {
+            c if c.is_whitespace() => {
+                chars.next();
+            }
+            '(' => {
+                chars.next();
+                tokens.push(Token::Open);
+            }
+            ')' => {
+                chars.next();
+                tokens.push(Token::Close);
+            }
+            '"' => {
+                chars.next();
+                let mut text = String::new();
+                loop {
+                    match chars.next() {
+                        Some('"') => break,
+                        Some('\\') => text.extend(chars.next()),
+                        Some(c) => text.push(c),
+                        None => return Err(AppError::InvalidQuery(String::from("unclosed quote"))),
+                    }
+                }
+                tokens.push(Token::Text(text));
+            }
+            '=' | '!' | '<' | '>' | '~' => {
+                chars.next();
+                let next = chars.peek().copied();
+                let op = match (c, next) {
+                    ('=', _) => Op::Eq,
+                    ('!', Some('=')) => Op::NotEq,
+                    ('!', Some('~')) => Op::NotContains,
+                    ('<', Some('=')) => Op::LtEq,
+                    ('>', Some('=')) => Op::GtEq,
+                    ('<', _) => Op::Lt,
+                    ('>', _) => Op::Gt,
+                    ('~', _) => Op::Contains,
+                    _ => return Err(AppError::InvalidQuery(format!("unexpected {}", c))),
+                };
+                if matches!(op, Op::NotEq | Op::NotContains | Op::LtEq | Op::GtEq) {
+                    chars.next();
+                }
+                tokens.push(Token::Op(op));
+            }
+            _ => {
+                let mut word = String::new();
+                while let Some(&c) = chars.peek() {
+                    if c.is_whitespace() || "()\"=!<>~".contains(c) {
+                        break;
+                    }
+                    word.push(c);
+                    chars.next();
+                }
+                tokens.push(Token::Word(word));
+            }
+        }
+    }
+    Ok(tokens)
+}
+
+struct Parser {
+    tokens: Vec<Token>,
+    position: usize,
+}
+
+fn parse_query(query: &str) -> Result<Expr, AppError> {
+    let mut parser = Parser {
+        tokens: tokenize(query)?,
+        position: 0,
+    };
+    let expr = parser.or()?;
+    match parser.next() {
+        None => Ok(expr),
+        Some(token) => Err(AppError::InvalidQuery(format!("unexpected {:?}", token))),
+    }
+}
+
+impl Parser {
+    fn next(&mut self) -> Option<Token> {
+        let token = self.tokens.get(self.position).cloned();
+        self.position += 1;
+        token
+    }
+
+    fn peek_keyword(&self, keyword: &str) -> bool {
+        matches!(self.tokens.get(self.position), Some(Token::Word(w)) if w.eq_ignore_ascii_case(keyword))
+    }
+
+    fn expect_keyword(&mut self, keyword: &str) -> Result<(), AppError> {
+        if !self.peek_keyword(keyword) {
+            return Err(AppError::InvalidQuery(format!("expected {}", keyword)));
+        }
+        self.position += 1;
+        Ok(())
+    }
+
+    fn or(&mut self) -> Result<Expr, AppError> {
+        let mut expr = self.and()?;
+        while self.peek_keyword("or") {
+            self.position += 1;
+            expr = Expr::Or(Box::new(expr), Box::new(self.and()?));
+        }
+        Ok(expr)
+    }
+
+    fn and(&mut self) -> Result<Expr, AppError> {
+        let mut expr = self.not()?;
+        while self.peek_keyword("and") {
+            self.position += 1;
+            expr = Expr::And(Box::new(expr), Box::new(self.not()?));
+        }
+        Ok(expr)
+    }
+
+    fn not(&mut self) -> Result<Expr, AppError> {
+        if self.peek_keyword("not") {
+            self.position += 1;
+            return Ok(Expr::Not(Box::new(self.not()?)));
+        }
+        self.primary()
+    }
+
+    fn primary(&mut self) -> Result<Expr, AppError> {
+        match self.next() {
+            Some(Token::Open) => {
+                let expr = self.or()?;
+                match self.next() {
+                    Some(Token::Close) => Ok(expr),
+                    _ => Err(AppError::InvalidQuery(String::from("missing )"))),
+                }
+            }
+            Some(Token::Word(name)) => {
+                let field = parse_field(&name)?;
+                if self.peek_keyword("in") {
+                    self.position += 1;
+                    return self.within(field);
+                }
+                let op = match self.next() {
+                    Some(Token::Op(op)) => op,
+                    _ => {
+                        return Err(AppError::InvalidQuery(format!(
+                            "expected operator after {}",
+                            name
+                        )))
+                    }
+                };
+                // The field decides, `title = 1984` is text and `year = "1984"` a number
+                let value = match self.next() {
+                    Some(Token::Text(text) | Token::Word(text)) if field.is_text() => {
+                        Value::Text(text)
+                    }
+                    Some(Token::Text(text) | Token::Word(text)) => text
+                        .parse::<f64>()
+                        .ok()
+                        .filter(|number| number.is_finite())
+                        .map(Value::Number)
+                        .ok_or(AppError::InvalidQuery(format!(
+                            "{} needs a number, not {}",
+                            name, text
+                        )))?,
+                    _ => {
+                        return Err(AppError::InvalidQuery(format!(
+                            "expected value after {}",
+                            name
+                        )))
+                    }
+                };
+                Ok(Expr::Compare(field, op, value))
+            }
+            token => Err(AppError::InvalidQuery(format!("unexpected {:?}", token))),
+        }
+    }
+
+    // `in last <n> <days|weeks|months|years>`
+    fn within(&mut self, field: Field) -> Result<Expr, AppError> {
+        if !matches!(field, Field::Added | Field::LastPlayed) {
+            return Err(AppError::InvalidQuery(String::from(
+                "only added and played support in last",
+            )));
+        }
+        self.expect_keyword("last")?;
+        let count = match self.next() {
+            Some(Token::Word(count)) => count.parse::<u64>().ok(),
+            _ => None,
+        }
+        .ok_or(AppError::InvalidQuery(String::from(
+            "expected a number after last",
+        )))?;
+
+        let unit = match self.next() {
+            Some(Token::Word(unit)) => unit.to_lowercase(),
+            _ => String::default(),
+        };
+        let seconds = match unit.trim_end_matches('s') {
+            "hour" => 60 * 60,
+            "day" => 24 * 60 * 60,
+            "week" => 7 * 24 * 60 * 60,
+            "month" => 30 * 24 * 60 * 60,
+            "year" => 365 * 24 * 60 * 60,
+            _ => {
+                return Err(AppError::InvalidQuery(format!(
+                    "unknown time unit {}",
+                    unit
+                )))
+            }
+        };
+        let window = count
+            .checked_mul(seconds)
+            .ok_or(AppError::InvalidQuery(format!(
+                "{} {} is too long ago",
+                count, unit
+            )))?;
+        Ok(Expr::Within(field, window))
+    }
+}
+
+fn smart_playlists_file() -> Result<String, AppError> {
+    Ok(format!("{}/smart_playlists.json", app_config_path()?))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn song(title: &str, year: Option<u32>) -> Song {
+        let mut song = Song::new(PathBuf::from(format!("/music/{}.mp3", title)));
+        song.title = Some(title.to_string());
+        song.year = year;
+        song
+    }
+
+    fn titles(playlist: &SmartPlaylist, songs: &[Song]) -> Vec<String> {
+        playlist
+            .evaluate(songs, &BTreeMap::new())
+            .unwrap()
+            .into_iter()
+            .filter_map(|song| song.title)
+            .collect()
+    }
+
+    #[test]
+    fn songs_without_the_sort_field_go_last() {
+        let songs = [
+            song("none", None),
+            song("old", Some(1970)),
+            song("new", Some(2001)),
+        ];
+        let mut playlist = SmartPlaylist::new(String::from("By year"), String::from("plays >= 0"));
+        playlist.sort_by = Some(String::from("year"));
+        assert_eq!(titles(&playlist, &songs), ["old", "new", "none"]);
+        playlist.descending = true;
+        assert_eq!(titles(&playlist, &songs), ["new", "old", "none"]);
+    }
+
+    #[test]
+    fn values_take_the_type_of_their_field() {
+        let songs = [song("1984", Some(1949)), song("Animal Farm", Some(1945))];
+        let query = |query: &str| SmartPlaylist::new(String::new(), query.to_string());
+        assert_eq!(titles(&query("title = 1984"), &songs), ["1984"]);
+        assert_eq!(titles(&query("title != 1984"), &songs), ["Animal Farm"]);
+        assert_eq!(titles(&query("title = nan"), &songs), Vec::<String>::new());
+        assert_eq!(titles(&query("year = \"1945\""), &songs), ["Animal Farm"]);
+        assert!(query("year > inf").validate().is_err());
+        assert!(query("year = nineteen").validate().is_err());
+    }
+}
@@ -1,15 +1,57 @@
-use image::{ImageError, ImageReader};
+use image::ImageReader;
+use lofty::config::WriteOptions;
+use lofty::error::LoftyError;
 use lofty::file::{AudioFile, TaggedFileExt};
 use lofty::probe::Probe;
-use lofty::tag::Accessor;
-use reqwest;
+use lofty::tag::{Accessor, ItemKey, ItemValue, Tag, TagExt, TagItem, TagType};
 use serde::Serialize;
-use std::fs;
 use std::io::Cursor;
 use std::path::{Path, PathBuf};
 use std::time::Duration;
+use std::{fmt, fs};
 use youtube_dl::{SearchOptions, SingleVideo, YoutubeDl, YoutubeDlOutput};
 
+use crate::error::AppError;
+use crate::pattern::PathPattern;
+use crate::APP_NAME;
+
+#[derive(Debug)]
+pub enum SongError {
+    BadPath(PathBuf, LoftyError),
+    Unreadable(PathBuf, LoftyError),
+}
+
+impl fmt::Display for SongError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SongError::BadPath(path, e) => write!(f, "Bad path {}: {}", path.display(), e),
+            SongError::Unreadable(path, e) => {
+                write!(f, "Failed to read {}: {}", path.display(), e)
+            }
+        }
+    }
+}
+
+impl SongError {
+    pub fn path(&self) -> &Path {
+        match self {
+            SongError::BadPath(path, _) | SongError::Unreadable(path, _) => path,
+        }
+    }
+}
+
+impl std::error::Error for SongError {}
+
+#[derive(Default, Debug, Clone, Serialize)]
+pub struct MusicBrainzIds {
+    pub recording: Option<String>,
+    pub release_track: Option<String>,
+    pub release: Option<String>,
+    pub release_group: Option<String>,
+    pub artist: Option<String>,
+    pub album_artist: Option<String>,
+}
+
 #[derive(Default, Debug, Clone, Serialize)]
 pub struct Song {
     path: PathBuf,
@@ -17,10 +59,20 @@ pub struct Song {
     pub cover_data: Option<Vec<u8>>,
     pub title: Option<String>,
     pub artist: Option<String>,
+    pub artists: Vec<String>,
     pub album: Option<String>,
+    pub album_artist: Option<String>,
+    pub composer: Option<String>,
     pub genre: Option<String>,
+    pub genres: Vec<String>,
     pub year: Option<u32>,
+    pub original_release_date: Option<String>,
     pub track: Option<u32>,
+    pub total_tracks: Option<u32>,
+    pub disc: Option<u32>,
+    pub total_discs: Option<u32>,
+    pub musicbrainz: MusicBrainzIds,
+    pub rating: Option<u8>,
 
     pub duration: Duration,
     pub channels: Option<u8>,
@@ -37,10 +89,20 @@ impl Song {
             cover_data: None,
             title: None,
             artist: None,
+            artists: Vec::default(),
             album: None,
+            album_artist: None,
+            composer: None,
             genre: None,
+            genres: Vec::default(),
             year: None,
+            original_release_date: None,
             track: None,
+            total_tracks: None,
+            disc: None,
+            total_discs: None,
+            musicbrainz: MusicBrainzIds::default(),
+            rating: None,
             duration: Duration::default(),
             channels: None,
             sample_rate: None,
@@ -49,14 +111,13 @@ impl Song {
         }
     }
 
-    pub async fn load_metadata(&mut self) {
+    pub async fn load_metadata(&mut self, patterns: &[PathPattern]) -> Result<(), SongError> {
         let tag_file = Probe::open(&self.path)
-            .expect("ERROR: Bad path provided!")
+            .map_err(|e| SongError::BadPath(self.path.clone(), e))?
             .read()
-            .expect("ERROR: Failed to read file!");
+            .map_err(|e| SongError::Unreadable(self.path.clone(), e))?;
 
         let audio_properties = &tag_file.properties();
-        let metadata_tag = &tag_file.primary_tag().expect("Could not get metadata tag");
 
         // Audio Metadata
         self.duration = audio_properties.duration();
@@ -65,38 +126,113 @@ impl Song {
         self.audio_bitrate = audio_properties.audio_bitrate();
         self.bit_depth = audio_properties.bit_depth();
 
-        // Music Metadata
-        self.title = metadata_tag.title().map(|s| s.to_string());
-        self.album = metadata_tag.album().map(|s| s.to_string());
-        self.artist = metadata_tag.artist().map(|s| s.to_string());
-        self.genre = metadata_tag.genre().map(|s| s.to_string());
-        self.track = metadata_tag.track().map(|s| s as u32);
-        self.year = metadata_tag.year().map(|s| s as u32);
-        self.cover_data = metadata_tag.pictures().get(0).map(|p| p.data().to_owned());
-
-        let cache = dirs::cache_dir().unwrap_or_default();
+        // Music Metadata, falling back to any other tag the file has
+        if let Some(metadata_tag) = tag_file.primary_tag().or_else(|| tag_file.first_tag()) {
+            self.title = metadata_tag.title().map(|s| s.to_string());
+            self.album = metadata_tag.album().map(|s| s.to_string());
+            self.artist = metadata_tag.artist().map(|s| s.to_string());
+            self.genre = metadata_tag.genre().map(|s| s.to_string());
+            self.track = metadata_tag.track();
+            self.year = metadata_tag.year();
+            self.cover_data = metadata_tag.pictures().first().map(|p| p.data().to_owned());
+            self.load_extended_metadata(metadata_tag);
+        }
+        self.fill_from_path(patterns);
+        if self.title.is_none() {
+            self.title = self
+                .path
+                .file_stem()
+                .map(|s| s.to_string_lossy().to_string());
+        }
+        // Also covers an artist or genre that only came from the path
+        if self.artists.is_empty() {
+            self.artists.extend(self.artist.clone());
+        }
+        if self.genres.is_empty() {
+            self.genres.extend(self.genre.clone());
+        }
 
-        let format = format!(
-            "{}/{}/{}",
-            cache.display(),
-            "bupl".to_string(),
-            self.album.clone().unwrap_or_default()
-        );
+        // Without an album there is no key to cache the cover under
+        if self.album.is_none() {
+            return Ok(());
+        }
+        let Some(cache) = dirs::cache_dir() else {
+            return Ok(());
+        };
+        let key = self.cover_key();
+        let format = format!("{}/{}/{}", cache.display(), APP_NAME.as_str(), key);
 
+        // Appended rather than set, keys can contain dots
         let extensions = ["webp", "png", "jpg"];
-        let cover_exists = extensions.iter().any(|ext| {
-            let path = Path::new(&format).with_extension(ext);
-            fs::metadata(&path).is_ok()
-        });
+        let cover_exists = extensions
+            .iter()
+            .any(|ext| fs::metadata(format!("{}.{}", format, ext)).is_ok());
 
         if !cover_exists {
             println!("Cover not found, fetching...");
-            let _ = self
+            if let Err(e) = self
                 .process_cover_data(self.cover_data.clone(), format)
-                .await;
+                .await
+            {
+                println!("Could not process cover: {}", e);
+            }
+        }
+
+        self.cover_path = Song::set_cover_path(&key);
+        Ok(())
+    }
+
+    fn load_extended_metadata(&mut self, tag: &Tag) {
+        let string = |key: ItemKey| tag.get_string(&key).map(|s| s.to_string());
+
+        self.album_artist = string(ItemKey::AlbumArtist);
+        self.composer = string(ItemKey::Composer);
+        self.original_release_date = string(ItemKey::OriginalReleaseDate);
+        self.artists = split_values(tag, ItemKey::TrackArtist);
+        self.genres = split_values(tag, ItemKey::Genre);
+        self.total_tracks = tag.track_total();
+        self.disc = tag.disk();
+        self.total_discs = tag.disk_total();
+
+        self.musicbrainz = MusicBrainzIds {
+            recording: string(ItemKey::MusicBrainzRecordingId),
+            release_track: string(ItemKey::MusicBrainzTrackId),
+            release: string(ItemKey::MusicBrainzReleaseId),
+            release_group: string(ItemKey::MusicBrainzReleaseGroupId),
+            artist: string(ItemKey::MusicBrainzArtistId),
+            album_artist: string(ItemKey::MusicBrainzReleaseArtistId),
+        };
+        self.rating = read_rating(tag);
+    }
+
+    // Compilations share an album artist, so group by it before the track artist
+    pub fn album_key(&self) -> String {
+        let album = self.album.clone().unwrap_or_default();
+        match self.album_artist.as_ref().or(self.artist.as_ref()) {
+            Some(artist) if !album.is_empty() => format!("{} - {}", artist, album),
+            _ => album,
+        }
+    }
+
+    // The album key as a file name, so albums of the same name keep their own cover
+    pub fn cover_key(&self) -> String {
+        self.album_key().replace(['/', '\\'], "_")
+    }
+
+    fn fill_from_path(&mut self, patterns: &[PathPattern]) {
+        if self.title.is_some() && self.artist.is_some() && self.album.is_some() {
+            return;
         }
+        let Some(fields) = patterns.iter().find_map(|p| p.parse_path(&self.path)) else {
+            return;
+        };
 
-        self.cover_path = Song::set_cover_path(self.album.clone().unwrap_or_default());
+        self.title = self.title.take().or(fields.title);
+        self.artist = self.artist.take().or(fields.artist);
+        self.album = self.album.take().or(fields.album);
+        self.genre = self.genre.take().or(fields.genre);
+        self.year = self.year.or(fields.year);
+        self.track = self.track.or(fields.track);
     }
 
     pub fn get_path(&self) -> PathBuf {
@@ -111,7 +247,7 @@ impl Song {
         &self,
         data: Option<Vec<u8>>,
         format: String,
-    ) -> Result<(), ImageError> {
+    ) -> Result<(), AppError> {
         println!("Processing, {}", self.album.clone().unwrap_or_default());
 
         match data {
@@ -120,100 +256,154 @@ impl Song {
                 fetch_album_cover(
                     self.title.clone().unwrap_or_default(),
                     self.album.clone().unwrap_or_default(),
+                    self.cover_key(),
                 )
-                .await;
+                .await?;
             }
             Some(data) => {
                 let mut img = ImageReader::new(Cursor::new(data))
                     .with_guessed_format()?
                     .decode()?;
                 img = img.crop_imm(
-                    (img.width() - img.height()) / 2,
+                    img.width().saturating_sub(img.height()) / 2,
                     0,
                     img.height(),
                     img.height(),
                 );
-                img.save(format)?
+                img.save(format!("{}.png", format))?
             }
         }
         Ok(())
     }
 
-    fn set_cover_path(album: String) -> Option<PathBuf> {
-        let cache_path = format!(
-            "{}/{}",
-            dirs::cache_dir().unwrap().display(),
-            "bupl".to_string()
-        );
-        let mut cover_path = None;
-
+    fn set_cover_path(key: &str) -> Option<PathBuf> {
+        let cache_path = dirs::cache_dir()?.join(APP_NAME.as_str());
         let _ = fs::create_dir_all(&cache_path);
 
-        for entry in fs::read_dir(&cache_path).unwrap().filter_map(|e| e.ok()) {
-            if entry.file_type().unwrap().is_file() {
-                let file_name_str = entry.file_name();
-                let file_name = file_name_str.to_str().unwrap().split(".").next().unwrap();
-                if file_name == album {
-                    cover_path = Some(entry.path());
-                    break;
-                }
-            }
-        }
+        // Keys can contain dots, so compare the whole stem
+        let cover_path = fs::read_dir(&cache_path)
+            .into_iter()
+            .flatten()
+            .filter_map(|entry| entry.ok())
+            .filter(|entry| entry.file_type().is_ok_and(|t| t.is_file()))
+            .map(|entry| entry.path())
+            .find(|path| path.file_stem().is_some_and(|stem| stem == key));
         println!("Cover path: {:?}", cover_path);
         cover_path
     }
 }
 
-async fn fetch_video_info(title: String, album: String) -> SingleVideo {
+// Multi-value frames are either separate items or joined with NUL/semicolons
+fn split_values(tag: &Tag, key: ItemKey) -> Vec<String> {
+    tag.get_strings(&key)
+        .flat_map(|value| value.split(['\0', ';']))
+        .map(|value| value.trim().to_string())
+        .filter(|value| !value.is_empty())
+        .collect()
+}
+
+// Ratings are 0 to 5 stars, ID3v2 keeps them in POPM and other tags in FMPS_RATING
+fn read_rating(tag: &Tag) -> Option<u8> {
+    if let Some(ItemValue::Binary(popm)) = tag.get(&ItemKey::Popularimeter).map(|i| i.value()) {
+        // `<email>\0<rating><counter>`, where 1 to 255 maps onto the stars
+        let email_end = popm.iter().position(|b| *b == 0)?;
+        return match *popm.get(email_end + 1)? {
+            0 => None,
+            1..=31 => Some(1),
+            32..=95 => Some(2),
+            96..=159 => Some(3),
+            160..=223 => Some(4),
+            _ => Some(5),
+        };
+    }
+    let fmps = tag.get_string(&ItemKey::Unknown(String::from("FMPS_RATING")))?;
+    let rating = fmps.trim().parse::<f32>().ok()?;
+    Some((rating.clamp(0.0, 1.0) * 5.0).round() as u8)
+}
+
+pub fn write_rating(path: &Path, rating: Option<u8>) -> Result<(), AppError> {
+    let metadata_error = |e: LoftyError| AppError::Metadata(e.to_string());
+    let mut tag_file = Probe::open(path)
+        .and_then(|probe| probe.read())
+        .map_err(metadata_error)?;
+    if tag_file.primary_tag().is_none() {
+        tag_file.insert_tag(Tag::new(tag_file.primary_tag_type()));
+    }
+    let Some(tag) = tag_file.primary_tag_mut() else {
+        return Err(AppError::Metadata(String::from("no writable tag")));
+    };
+
+    let key = match tag.tag_type() {
+        TagType::Id3v2 => ItemKey::Popularimeter,
+        _ => ItemKey::Unknown(String::from("FMPS_RATING")),
+    };
+    tag.remove_key(&key);
+    if let Some(rating) = rating {
+        let value = match key {
+            ItemKey::Popularimeter => {
+                let popm = [0, 1, 64, 128, 196, 255][rating.min(5) as usize];
+                let mut frame = b"bupl\0".to_vec();
+                frame.extend([popm, 0, 0, 0, 0]);
+                ItemValue::Binary(frame)
+            }
+            _ => ItemValue::Text(format!("{:.1}", f32::from(rating) / 5.0)),
+        };
+        tag.insert(TagItem::new(key, value));
+    }
+    tag.save_to_path(path, WriteOptions::default())
+        .map_err(metadata_error)
+}
+
+pub async fn fetch_video_info(title: String, album: String) -> Result<SingleVideo, AppError> {
     let search_query = format!("{} {}", title, album);
     let search = SearchOptions::youtube(&search_query);
     println!("Searching for: {}", search_query);
 
-    let yt_search = YoutubeDl::search_for(&search).run().unwrap();
+    let yt_search = YoutubeDl::search_for(&search).run()?;
     let video = match yt_search {
         YoutubeDlOutput::Playlist(playlist) => {
-            if let Some(video) = playlist.entries.unwrap().first() {
+            if let Some(video) = playlist.entries.unwrap_or_default().first() {
                 video.clone()
             } else {
-                return SingleVideo::default();
+                return Ok(SingleVideo::default());
             }
         }
         YoutubeDlOutput::SingleVideo(video) => *video,
     };
-    video
+    Ok(video)
 }
 
-async fn fetch_album_cover(title: String, album: String) {
+// Saved under `key`, see Song::cover_key
+pub async fn fetch_album_cover(title: String, album: String, key: String) -> Result<(), AppError> {
     println!("Searching for cover art for: {}", album);
-    let cache_path = dirs::cache_dir()
-        .ok_or("Failed to get cache directory")
-        .unwrap();
+    let cache_path = dirs::cache_dir().ok_or(AppError::DirectoryMissing("cache"))?;
 
-    let video = fetch_video_info(title, album.clone()).await;
+    let video = fetch_video_info(title, album.clone()).await?;
 
-    let thumbnail_url = video.thumbnail.ok_or("No thumbnail found").unwrap();
+    let thumbnail_url = video
+        .thumbnail
+        .ok_or(AppError::Cover(String::from("No thumbnail found")))?;
     println!("Thumbnail URL: {}", thumbnail_url);
 
     let extension = if let Some(query_start) = thumbnail_url.find('?') {
         thumbnail_url[..query_start]
             .split('.')
-            .last()
+            .next_back()
             .unwrap_or("jpg")
     } else {
-        thumbnail_url.split('.').last().unwrap_or("jpg")
+        thumbnail_url.split('.').next_back().unwrap_or("jpg")
     };
 
     let thumbnail_path = format!(
         "{}/{}/{}.{}",
         cache_path.display(),
-        "bupl".to_string(),
-        &album,
+        APP_NAME.as_str(),
+        &key,
         extension
     );
-    let response = reqwest::get(&thumbnail_url).await.unwrap();
-    let mut file = tokio::fs::File::create(&thumbnail_path).await.unwrap();
-    let content = response.bytes().await.unwrap();
-    tokio::io::copy(&mut content.as_ref(), &mut file)
-        .await
-        .unwrap();
+    let response = reqwest::get(&thumbnail_url).await?;
+    let mut file = tokio::fs::File::create(&thumbnail_path).await?;
+    let content = response.bytes().await?;
+    tokio::io::copy(&mut content.as_ref(), &mut file).await?;
+    Ok(())
 }
@@ -0,0 +1,236 @@
+use chrono::{DateTime, Datelike, Local, NaiveDate, TimeZone, Timelike};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeSet, HashMap};
+use std::fs::{self, read_to_string, OpenOptions};
+use std::io::Write;
+use std::path::PathBuf;
+
+use crate::config::app_config_path;
+use crate::error::AppError;
+use crate::player::PlayRecord;
+
+// One line of history.jsonl for every song that counted as a play
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct HistoryEntry {
+    pub path: PathBuf,
+    pub title: Option<String>,
+    pub artists: Vec<String>,
+    pub album: Option<String>,
+    pub album_artist: Option<String>,
+    pub genres: Vec<String>,
+    pub started: u64,
+    pub played: u64,
+}
+
+#[derive(Debug, Default, Serialize)]
+pub struct ListeningReport {
+    pub from: u64,
+    pub to: u64,
+    pub plays: usize,
+    pub listening_time: u64,
+    pub top_songs: Vec<RankedItem>,
+    pub top_artists: Vec<RankedItem>,
+    pub top_albums: Vec<RankedItem>,
+    pub top_genres: Vec<RankedItem>,
+    // Plays per weekday (Monday first) and hour in local time
+    pub by_hour: Vec<Vec<u32>>,
+    pub longest_streak: u32,
+    pub current_streak: u32,
+}
+
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct RankedItem {
+    pub name: String,
+    pub artist: Option<String>,
+    pub plays: u32,
+    pub listening_time: u64,
+}
+
+impl HistoryEntry {
+    pub fn from_record(record: &PlayRecord) -> Self {
+        let song = &record.song;
+        Self {
+            path: song.get_path(),
+            title: song.title.clone(),
+            artists: match song.artists.is_empty() {
+                true => song.artist.iter().cloned().collect(),
+                false => song.artists.clone(),
+            },
+            album: song.album.clone(),
+            album_artist: song.album_artist.clone().or(song.artist.clone()),
+            genres: match song.genres.is_empty() {
+                true => song.genre.iter().cloned().collect(),
+                false => song.genres.clone(),
+            },
+            started: record.started,
+            played: record.played.as_secs(),
+        }
+    }
+
+    pub fn append(&self) -> Result<(), AppError> {
+        fs::create_dir_all(app_config_path()?)?;
+        let mut history = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(history_file()?)?;
+        writeln!(history, "{}", serde_json::to_string(self)?)?;
+        Ok(())
+    }
+
+    // Plays started in `from..to`, unreadable lines are skipped
+    pub fn load(from: u64, to: u64) -> Result<Vec<Self>, AppError> {
+        let history = read_to_string(history_file()?).unwrap_or_default();
+        Ok(history
+            .lines()
+            .filter_map(|line| serde_json::from_str::<Self>(line).ok())
+            .filter(|entry| entry.started >= from && entry.started < to)
+            .collect())
+    }
+
+    fn local_time(&self) -> DateTime<Local> {
+        Local
+            .timestamp_opt(self.started as i64, 0)
+            .single()
+            .unwrap_or_default()
+    }
+}
+
+impl ListeningReport {
+    pub fn new(history: &[HistoryEntry], from: u64, to: u64, limit: usize) -> Self {
+        let mut songs = Ranking::default();
+        let mut artists = Ranking::default();
+        let mut albums = Ranking::default();
+        let mut genres = Ranking::default();
+        let mut by_hour = vec![vec![0; 24]; 7];
+        let mut days = BTreeSet::new();
+
+        for entry in history {
+            let title = entry.title.clone().unwrap_or_default();
+            songs.add(title, entry.artists.first().cloned(), entry.played);
+            for artist in &entry.artists {
+                artists.add(artist.clone(), None, entry.played);
+            }
+            if let Some(album) = &entry.album {
+                albums.add(album.clone(), entry.album_artist.clone(), entry.played);
+            }
+            for genre in &entry.genres {
+                genres.add(genre.clone(), None, entry.played);
+            }
+
+            let time = entry.local_time();
+            by_hour[time.weekday().num_days_from_monday() as usize][time.hour() as usize] += 1;
+            days.insert(time.date_naive());
+        }
+
+        let (longest_streak, current_streak) = streaks(&days, Local::now().date_naive());
+        Self {
+            from,
+            to,
+            plays: history.len(),
+            listening_time: history.iter().map(|entry| entry.played).sum(),
+            top_songs: songs.top(limit),
+            top_artists: artists.top(limit),
+            top_albums: albums.top(limit),
+            top_genres: genres.top(limit),
+            by_hour,
+            longest_streak,
+            current_streak,
+        }
+    }
+}
+
+#[derive(Default)]
+struct Ranking(HashMap<(String, Option<String>), RankedItem>);
+
+impl Ranking {
+    fn add(&mut self, name: String, artist: Option<String>, played: u64) {
+        let item = self
+            .0
+            .entry((name.clone(), artist.clone()))
+            .or_insert_with(|| RankedItem {
+                name,
+                artist,
+                ..RankedItem::default()
+            });
+        item.plays += 1;
+        item.listening_time += played;
+    }
+
+    fn top(self, limit: usize) -> Vec<RankedItem> {
+        let mut items: Vec<RankedItem> = self.0.into_values().collect();
+        items.sort_by(|a, b| {
+            b.plays
+                .cmp(&a.plays)
+                .then(b.listening_time.cmp(&a.listening_time))
+                .then_with(|| a.name.cmp(&b.name))
+        });
+        items.truncate(limit);
+        items
+    }
+}
+
+// Longest run of consecutive days with plays, and the run still going today or yesterday
+fn streaks(days: &BTreeSet<NaiveDate>, today: NaiveDate) -> (u32, u32) {
+    let mut longest = 0;
+    let mut run = 0;
+    let mut previous: Option<NaiveDate> = None;
+
+    for day in days {
+        run = match previous {
+            Some(previous) if previous.succ_opt() == Some(*day) => run + 1,
+            _ => 1,
+        };
+        longest = longest.max(run);
+        previous = Some(*day);
+    }
+
+    let current = match previous {
+        Some(last) if last == today || last.succ_opt() == Some(today) => run,
+        _ => 0,
+    };
+    (longest, current)
+}
+
+// Start of `year` and of the year after, as unix timestamps in local time
+pub fn year_range(year: i32) -> Result<(u64, u64), AppError> {
+    let start = |year: i32| {
+        Local
+            .with_ymd_and_hms(year, 1, 1, 0, 0, 0)
+            .earliest()
+            .map(|time| time.timestamp().max(0) as u64)
+            .ok_or(AppError::OutOfRange(year.max(0) as usize))
+    };
+    Ok((start(year)?, start(year + 1)?))
+}
+
+pub fn history_csv(history: &[HistoryEntry]) -> String {
+    let mut csv = String::from("started,played,title,artist,album,album_artist,genre,path\n");
+    for entry in history {
+        let fields = [
+            entry.local_time().to_rfc3339(),
+            entry.played.to_string(),
+            entry.title.clone().unwrap_or_default(),
+            entry.artists.join("; "),
+            entry.album.clone().unwrap_or_default(),
+            entry.album_artist.clone().unwrap_or_default(),
+            entry.genres.join("; "),
+            entry.path.display().to_string(),
+        ];
+        let row: Vec<String> = fields.iter().map(|field| csv_field(field)).collect();
+        csv.push_str(&row.join(","));
+        csv.push('\n');
+    }
+    csv
+}
+
+fn csv_field(field: &str) -> String {
+    if field.contains([',', '"', '\n']) {
+        format!("\"{}\"", field.replace('"', "\"\""))
+    } else {
+        field.to_string()
+    }
+}
+
+fn history_file() -> Result<String, AppError> {
+    Ok(format!("{}/history.jsonl", app_config_path()?))
+}
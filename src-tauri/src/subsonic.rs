@@ -0,0 +1,1019 @@
+use lazy_static::lazy_static;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Map, Value};
+use std::collections::{BTreeMap, HashMap};
+use std::io::Cursor;
+use std::path::{Path, PathBuf};
+use std::process::Stdio;
+use std::sync::Arc;
+use std::time::{Duration, SystemTime};
+use tokio::fs::File;
+use tokio::io::{self, AsyncReadExt, AsyncSeekExt, AsyncWriteExt, BufReader};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::process::Command;
+use tokio::sync::{Mutex, MutexGuard};
+
+use crate::config::CONFIG;
+use crate::error::AppError;
+use crate::http::{read_body, read_head, same_secret};
+use crate::library::{unix_time, LIBRARY_SONGS};
+use crate::mpd::song_uri;
+use crate::player::PlayRecord;
+use crate::playlist::Playlist;
+use crate::playlist_format::percent_decode;
+use crate::scrobbler::{queue_scrobble, send_now_playing};
+use crate::search::SEARCH_INDEX;
+use crate::song::Song;
+use crate::{get_library_songs, save_play};
+
+const API_VERSION: &str = "1.16.1";
+
+const IGNORED_ARTICLES: &[&str] = &["The", "El", "La", "Los", "Las", "Le", "Les"];
+
+lazy_static! {
+    static ref LIBRARY_IDS: Arc<Mutex<LibraryIds>> = Arc::new(Mutex::new(LibraryIds::default()));
+}
+
+// What the ids of the cached library songs stand for, rebuilt along with the cache
+#[derive(Default)]
+struct LibraryIds {
+    songs: HashMap<String, PathBuf>,
+    albums: HashMap<String, String>,
+    artists: HashMap<String, String>,
+}
+
+// Off by default, set `address` to e.g. 0.0.0.0:4040 so phones on the network can connect
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct SubsonicSettings {
+    pub enabled: bool,
+    pub address: String,
+    pub username: String,
+    pub password: Option<String>,
+    // Used when a client asks for another format or a lower bitrate
+    pub ffmpeg: String,
+}
+
+impl Default for SubsonicSettings {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            address: String::from("127.0.0.1:4040"),
+            username: String::from("bupl"),
+            password: None,
+            ffmpeg: String::from("ffmpeg"),
+        }
+    }
+}
+
+// Error codes from the Subsonic API, sent with HTTP 200 inside the usual envelope
+#[derive(Debug, Clone, Copy)]
+enum Code {
+    Generic = 0,
+    MissingParameter = 10,
+    WrongCredentials = 40,
+    NotFound = 70,
+}
+
+#[derive(Debug)]
+struct ApiError {
+    code: Code,
+    message: String,
+}
+
+impl ApiError {
+    fn new(code: Code, message: impl Into<String>) -> Self {
+        Self {
+            code,
+            message: message.into(),
+        }
+    }
+}
+
+impl From<AppError> for ApiError {
+    fn from(e: AppError) -> Self {
+        let code = match e {
+            AppError::FileMissing(_) | AppError::OutsideLibrary(_) => Code::NotFound,
+            _ => Code::Generic,
+        };
+        Self::new(code, e.to_string())
+    }
+}
+
+impl From<io::Error> for ApiError {
+    fn from(e: io::Error) -> Self {
+        AppError::from(e).into()
+    }
+}
+
+struct Request {
+    method: String,
+    endpoint: String,
+    params: Vec<(String, String)>,
+    range: Option<String>,
+}
+
+impl Request {
+    fn param(&self, name: &str) -> Option<&str> {
+        self.params
+            .iter()
+            .find(|(key, _)| key == name)
+            .map(|(_, value)| value.as_str())
+    }
+
+    // Some parameters, like the ids to scrobble, can be repeated
+    fn params(&self, name: &str) -> Vec<&str> {
+        self.params
+            .iter()
+            .filter(|(key, _)| key == name)
+            .map(|(_, value)| value.as_str())
+            .collect()
+    }
+
+    fn required(&self, name: &str) -> Result<&str, ApiError> {
+        self.param(name).ok_or(ApiError::new(
+            Code::MissingParameter,
+            format!("Required parameter '{}' is missing", name),
+        ))
+    }
+
+    fn number<T: std::str::FromStr>(&self, name: &str, default: T) -> T {
+        self.param(name)
+            .and_then(|value| value.parse().ok())
+            .unwrap_or(default)
+    }
+
+    fn json(&self) -> bool {
+        self.param("f") == Some("json")
+    }
+}
+
+// Either an API response for the envelope or raw data, like a song or cover
+enum Reply {
+    Body(Value),
+    File(PathBuf),
+    Image(Vec<u8>),
+    Transcode(tokio::process::Child, &'static str),
+}
+
+pub async fn run_subsonic_server() {
+    let settings = CONFIG.lock().await.subsonic.clone();
+    if !settings.enabled {
+        return;
+    }
+    if settings.password.is_none() {
+        println!("Subsonic server needs a password");
+        return;
+    }
+    if let Err(e) = serve(settings).await {
+        println!("Subsonic server unavailable: {}", e);
+    }
+}
+
+async fn serve(settings: SubsonicSettings) -> Result<(), AppError> {
+    let listener = TcpListener::bind(&settings.address).await?;
+    loop {
+        let (stream, _) = listener.accept().await?;
+        tokio::spawn(handle_client(stream, settings.clone()));
+    }
+}
+
+// One request per connection, every response closes it
+async fn handle_client(stream: TcpStream, settings: SubsonicSettings) -> Result<(), AppError> {
+    let mut stream = BufReader::new(stream);
+    let Some(request) = read_request(&mut stream).await? else {
+        return write_head(&mut stream, "400 Bad Request", &[], Some(0)).await;
+    };
+    let json = request.json();
+    if !request.endpoint.starts_with("/rest/") {
+        return write_head(&mut stream, "404 Not Found", &[], Some(0)).await;
+    }
+
+    let reply = match authenticate(&request, &settings) {
+        Ok(()) => handle_request(&request, &settings).await,
+        Err(e) => Err(e),
+    };
+    let head_only = request.method == "HEAD";
+    match reply {
+        Ok(Reply::Body(body)) => write_body(&mut stream, envelope(Ok(body)), json).await,
+        Ok(Reply::File(path)) => send_file(&mut stream, &path, &request, head_only).await,
+        Ok(Reply::Image(data)) => {
+            let headers = [("Content-Type", "image/jpeg")];
+            write_head(&mut stream, "200 OK", &headers, Some(data.len() as u64)).await?;
+            if !head_only {
+                stream.write_all(&data).await?;
+            }
+            Ok(())
+        }
+        Ok(Reply::Transcode(mut child, mime)) => {
+            write_head(&mut stream, "200 OK", &[("Content-Type", mime)], None).await?;
+            if let Some(mut output) = child.stdout.take().filter(|_| !head_only) {
+                io::copy(&mut output, &mut stream).await?;
+            }
+            Ok(())
+        }
+        Err(e) => write_body(&mut stream, envelope(Err(e)), json).await,
+    }
+}
+
+async fn read_request(stream: &mut BufReader<TcpStream>) -> Result<Option<Request>, AppError> {
+    let Some(head) = read_head(stream).await? else {
+        return Ok(None);
+    };
+    let (path, query) = head.target.split_once('?').unwrap_or((&head.target, ""));
+    let mut params = parse_query(query);
+    // Clients may post the parameters as a form instead of the query string
+    let form = head
+        .header("content-type")
+        .is_some_and(|value| value.starts_with("application/x-www-form-urlencoded"));
+    let content_length = head
+        .header("content-length")
+        .and_then(|value| value.parse().ok())
+        .unwrap_or(0);
+    if head.method == "POST" && form && content_length <= 64 * 1024 {
+        let body = read_body(stream, content_length).await?;
+        params.extend(parse_query(&String::from_utf8_lossy(&body)));
+    }
+    Ok(Some(Request {
+        endpoint: path.trim_end_matches(".view").to_string(),
+        params,
+        range: head.header("range").map(str::to_string),
+        method: head.method,
+    }))
+}
+
+fn parse_query(query: &str) -> Vec<(String, String)> {
+    query
+        .split('&')
+        .filter(|pair| !pair.is_empty())
+        .map(|pair| {
+            let (key, value) = pair.split_once('=').unwrap_or((pair, ""));
+            let decode = |text: &str| percent_decode(&text.replace('+', " "));
+            (decode(key), decode(value))
+        })
+        .collect()
+}
+
+// Accepts the salted token (`t` and `s`) or the password itself, plain or as `enc:` hex
+fn authenticate(request: &Request, settings: &SubsonicSettings) -> Result<(), ApiError> {
+    let password = settings.password.clone().unwrap_or_default();
+    let username = request.required("u")?;
+    let valid = match (request.param("t"), request.param("s"), request.param("p")) {
+        (Some(token), Some(salt), _) => {
+            let expected = format!("{:x}", md5::compute(format!("{}{}", password, salt)));
+            same_secret(token.to_ascii_lowercase().as_bytes(), expected.as_bytes())
+        }
+        (_, _, Some(given)) => match given.strip_prefix("enc:") {
+            Some(hex) => {
+                decode_hex(hex).is_some_and(|given| same_secret(&given, password.as_bytes()))
+            }
+            None => same_secret(given.as_bytes(), password.as_bytes()),
+        },
+        _ => {
+            return Err(ApiError::new(
+                Code::MissingParameter,
+                "Required parameter 't' or 'p' is missing",
+            ))
+        }
+    };
+    match valid && username == settings.username {
+        true => Ok(()),
+        false => Err(ApiError::new(
+            Code::WrongCredentials,
+            "Wrong username or password",
+        )),
+    }
+}
+
+fn decode_hex(hex: &str) -> Option<Vec<u8>> {
+    // from_str_radix would also take a sign, as in `+1`
+    if !hex.bytes().all(|b| b.is_ascii_hexdigit()) {
+        return None;
+    }
+    (0..hex.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(hex.get(i..i + 2)?, 16).ok())
+        .collect()
+}
+
+async fn handle_request(request: &Request, settings: &SubsonicSettings) -> Result<Reply, ApiError> {
+    let body = match request.endpoint.trim_start_matches("/rest/") {
+        "ping" => json!({}),
+        "getLicense" => json!({ "license": { "valid": true } }),
+        "getArtists" => get_artists().await?,
+        "getArtist" => get_artist(request.required("id")?).await?,
+        "getAlbum" => get_album(request.required("id")?).await?,
+        "getSong" => {
+            let song = find_song(request.required("id")?).await?;
+            json!({ "song": child(&song).await })
+        }
+        "search3" => search(request).await?,
+        "stream" | "download" => return stream(request, settings).await,
+        "getCoverArt" => return cover_art(request).await,
+        "scrobble" => scrobble(request).await?,
+        endpoint => {
+            return Err(ApiError::new(
+                Code::NotFound,
+                format!("Unknown endpoint '{}'", endpoint),
+            ))
+        }
+    };
+    Ok(Reply::Body(body))
+}
+
+// Artists are the album artists, so every artist leads to albums
+async fn get_artists() -> Result<Value, ApiError> {
+    let mut index: BTreeMap<String, Vec<Value>> = BTreeMap::new();
+    for (name, albums) in artist_albums().await? {
+        let sort_name = IGNORED_ARTICLES
+            .iter()
+            .find_map(|article| name.strip_prefix(&format!("{} ", article)))
+            .unwrap_or(&name);
+        let letter = match sort_name.chars().next() {
+            Some(c) if c.is_alphabetic() => c.to_uppercase().to_string(),
+            _ => String::from("#"),
+        };
+        index
+            .entry(letter)
+            .or_default()
+            .push(artist_info(&name, &albums));
+    }
+    let index: Vec<Value> = index
+        .into_iter()
+        .map(|(name, artists)| json!({ "name": name, "artist": artists }))
+        .collect();
+    Ok(json!({
+        "artists": { "ignoredArticles": IGNORED_ARTICLES.join(" "), "index": index }
+    }))
+}
+
+async fn get_artist(id: &str) -> Result<Value, ApiError> {
+    let (name, albums) = find_artist(id).await?;
+    let mut artist = artist_info(&name, &albums);
+    artist["album"] = albums.iter().map(album_info).collect();
+    Ok(json!({ "artist": artist }))
+}
+
+async fn get_album(id: &str) -> Result<Value, ApiError> {
+    let album = find_album(id).await?;
+    let mut info = album_info(&album);
+    let mut songs = vec![];
+    for song in &album.song_list {
+        songs.push(child(song).await);
+    }
+    info["song"] = Value::Array(songs);
+    Ok(json!({ "album": info }))
+}
+
+// An empty query lists the whole library, clients use it to sync page by page
+async fn search(request: &Request) -> Result<Value, ApiError> {
+    let query = request.param("query").unwrap_or_default().trim_matches('"');
+    let page = |kind: &str| {
+        let count: usize = request.number(&format!("{}Count", kind), 20);
+        let offset: usize = request.number(&format!("{}Offset", kind), 0);
+        (offset, count)
+    };
+    let (artist_page, album_page, song_page) = (page("artist"), page("album"), page("song"));
+
+    let songs = get_library_songs().await?;
+    let (artists, albums, songs) = match query.is_empty() {
+        true => {
+            let albums = Playlist::albums_from(songs.clone());
+            let artists: Vec<String> = artist_albums().await?.into_keys().collect();
+            let albums: Vec<String> = albums.iter().map(|album| album.id.clone()).collect();
+            (artists, albums, songs)
+        }
+        false => {
+            let limit = [artist_page, album_page, song_page]
+                .iter()
+                .map(|(offset, count)| offset + count)
+                .max()
+                .unwrap_or_default();
+            let results = SEARCH_INDEX.lock().await.search(query, limit);
+            let artists = results.artists.into_iter().map(|hit| hit.name).collect();
+            let albums = results.albums.into_iter().map(|hit| hit.id).collect();
+            (artists, albums, results.songs)
+        }
+    };
+
+    let all_albums = library_albums().await?;
+    let all_artists = artist_albums().await?;
+    let artists: Vec<Value> = paged(artists, artist_page)
+        .filter_map(|name| {
+            let albums = all_artists.get(&name)?;
+            Some(artist_info(&name, albums))
+        })
+        .collect();
+    let albums: Vec<Value> = paged(albums, album_page)
+        .filter_map(|id| all_albums.iter().find(|album| album.id == id))
+        .map(album_info)
+        .collect();
+    let mut song_list = vec![];
+    for song in paged(songs, song_page) {
+        song_list.push(child(&song).await);
+    }
+    Ok(json!({
+        "searchResult3": { "artist": artists, "album": albums, "song": song_list }
+    }))
+}
+
+fn paged<T>(items: Vec<T>, (offset, count): (usize, usize)) -> impl Iterator<Item = T> {
+    items.into_iter().skip(offset).take(count)
+}
+
+// Original files are served as they are, with ranges for seeking
+async fn stream(request: &Request, settings: &SubsonicSettings) -> Result<Reply, ApiError> {
+    let song = find_song(request.required("id")?).await?;
+    let path = song.get_path();
+    let suffix = suffix(&path);
+    let format = request.param("format").unwrap_or("raw");
+    let max_bitrate: u32 = request.number("maxBitRate", 0);
+    let offset: u64 = request.number("timeOffset", 0);
+
+    let lower_bitrate = max_bitrate > 0 && song.audio_bitrate.is_some_and(|b| b > max_bitrate);
+    let other_format = format != "raw" && format != suffix;
+    if request.endpoint.ends_with("download") || !(lower_bitrate || other_format || offset > 0) {
+        return Ok(Reply::File(path));
+    }
+
+    let (muxer, codec, mime) = match format {
+        "opus" | "ogg" => ("ogg", "libopus", "audio/ogg"),
+        _ => ("mp3", "libmp3lame", "audio/mpeg"),
+    };
+    let bitrate = match max_bitrate {
+        0 => 192,
+        rate => rate,
+    };
+    let child = Command::new(&settings.ffmpeg)
+        .args(["-v", "error", "-ss", &offset.to_string(), "-i"])
+        .arg(&path)
+        .args(["-map", "0:a:0", "-vn", "-c:a", codec])
+        .args(["-b:a", &format!("{}k", bitrate), "-f", muxer, "pipe:1"])
+        .stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::null())
+        .kill_on_drop(true)
+        .spawn();
+    match child {
+        Ok(child) => Ok(Reply::Transcode(child, mime)),
+        // Without ffmpeg the client still gets the song, just not converted
+        Err(_) if offset == 0 => Ok(Reply::File(path)),
+        Err(e) => Err(ApiError::new(
+            Code::Generic,
+            format!("Could not transcode: {}", e),
+        )),
+    }
+}
+
+// Covers come from the cover cache, songs, albums and artists all have one
+async fn cover_art(request: &Request) -> Result<Reply, ApiError> {
+    let id = request.required("id")?;
+    let cover = match id.split_once('-').map(|(kind, _)| kind) {
+        Some("tr") => find_song(id).await?.get_cover_path(),
+        Some("al") => find_album(id).await?.cover_path,
+        Some("ar") => find_artist(id)
+            .await?
+            .1
+            .last()
+            .map(|album| album.cover_path.clone())
+            .unwrap_or_default(),
+        _ => PathBuf::new(),
+    };
+    if !cover.is_file() {
+        return Err(not_found("Cover art"));
+    }
+
+    let size: u32 = request.number("size", 0);
+    if size == 0 {
+        return Ok(Reply::File(cover));
+    }
+    let data = tokio::task::spawn_blocking(move || -> Result<Vec<u8>, AppError> {
+        let mut data = Cursor::new(vec![]);
+        image::open(&cover)?
+            .thumbnail(size, size)
+            .to_rgb8()
+            .write_to(&mut data, image::ImageFormat::Jpeg)?;
+        Ok(data.into_inner())
+    })
+    .await
+    .map_err(|e| ApiError::new(Code::Generic, e.to_string()))??;
+    Ok(Reply::Image(data))
+}
+
+// Finished plays count like local ones, otherwise the song is sent as now playing
+async fn scrobble(request: &Request) -> Result<Value, ApiError> {
+    let submission = request.param("submission") != Some("false");
+    let times = request.params("time");
+    let now = unix_time(SystemTime::now());
+
+    for (i, id) in request.params("id").into_iter().enumerate() {
+        let song = find_song(id).await?;
+        if !submission {
+            send_now_playing(&song).await;
+            continue;
+        }
+        let started = times
+            .get(i)
+            .and_then(|time| time.parse::<u64>().ok())
+            .map(|millis| millis / 1000)
+            .unwrap_or(now.saturating_sub(song.duration.as_secs()));
+        let record = PlayRecord {
+            played: song.duration,
+            song,
+            started,
+        };
+        save_play(&record).await;
+        queue_scrobble(&record).await;
+    }
+    Ok(json!({}))
+}
+
+async fn library_albums() -> Result<Vec<Playlist>, AppError> {
+    Ok(Playlist::albums_from(get_library_songs().await?))
+}
+
+async fn artist_albums() -> Result<BTreeMap<String, Vec<Playlist>>, AppError> {
+    let mut artists: BTreeMap<String, Vec<Playlist>> = BTreeMap::new();
+    for album in library_albums().await? {
+        if let Some(artist) = album.song_list.first().and_then(album_artist) {
+            artists.entry(artist).or_default().push(album);
+        }
+    }
+    Ok(artists)
+}
+
+pub async fn cache_library_ids(songs: &[Song]) {
+    let mut ids = LibraryIds::default();
+    for song in songs {
+        let path = song.get_path();
+        ids.songs
+            .insert(subsonic_id("tr", &path.display().to_string()), path);
+        ids.albums
+            .insert(subsonic_id("al", &song.album_key()), song.album_key());
+        if let Some(artist) = album_artist(song) {
+            ids.artists.insert(subsonic_id("ar", &artist), artist);
+        }
+    }
+    *LIBRARY_IDS.lock().await = ids;
+}
+
+// Scans the library first if it is not cached yet
+async fn library_ids() -> Result<MutexGuard<'static, LibraryIds>, AppError> {
+    if LIBRARY_SONGS.lock().await.is_none() {
+        get_library_songs().await?;
+    }
+    Ok(LIBRARY_IDS.lock().await)
+}
+
+// Copies only the matching songs out of the cache
+async fn library_songs_where(matches: impl Fn(&Song) -> bool) -> Vec<Song> {
+    let songs = LIBRARY_SONGS.lock().await;
+    songs
+        .iter()
+        .flatten()
+        .filter(|song| matches(song))
+        .cloned()
+        .collect()
+}
+
+async fn find_song(id: &str) -> Result<Song, ApiError> {
+    let path = library_ids().await?.songs.get(id).cloned();
+    let path = path.ok_or(not_found("Song"))?;
+    library_songs_where(|song| song.get_path() == path)
+        .await
+        .pop()
+        .ok_or(not_found("Song"))
+}
+
+async fn find_album(id: &str) -> Result<Playlist, ApiError> {
+    let key = library_ids().await?.albums.get(id).cloned();
+    let key = key.ok_or(not_found("Album"))?;
+    let songs = library_songs_where(|song| song.album_key() == key).await;
+    Playlist::albums_from(songs).pop().ok_or(not_found("Album"))
+}
+
+async fn find_artist(id: &str) -> Result<(String, Vec<Playlist>), ApiError> {
+    let name = library_ids().await?.artists.get(id).cloned();
+    let name = name.ok_or(not_found("Artist"))?;
+    let songs = library_songs_where(|song| album_artist(song).as_ref() == Some(&name)).await;
+    let albums = Playlist::albums_from(songs)
+        .into_iter()
+        .filter(|album| album.song_list.first().and_then(album_artist).as_ref() == Some(&name))
+        .collect();
+    Ok((name, albums))
+}
+
+fn not_found(kind: &str) -> ApiError {
+    ApiError::new(Code::NotFound, format!("{} not found", kind))
+}
+
+// Ids are hashes of the artist name, album key or path, so they survive rescans
+fn subsonic_id(kind: &str, key: &str) -> String {
+    format!("{}-{:x}", kind, md5::compute(key))
+}
+
+fn album_artist(song: &Song) -> Option<String> {
+    song.album_artist.clone().or(song.artist.clone())
+}
+
+fn artist_info(name: &str, albums: &[Playlist]) -> Value {
+    json!({
+        "id": subsonic_id("ar", name),
+        "name": name,
+        "albumCount": albums.len(),
+        "coverArt": subsonic_id("ar", name),
+    })
+}
+
+fn album_info(album: &Playlist) -> Value {
+    let first = album.song_list.first().cloned().unwrap_or_default();
+    let artist = album_artist(&first);
+    let duration: Duration = album.song_list.iter().map(|song| song.duration).sum();
+    json!({
+        "id": subsonic_id("al", &album.id),
+        "name": album.name,
+        "artist": artist,
+        "artistId": artist.as_ref().map(|artist| subsonic_id("ar", artist)),
+        "coverArt": subsonic_id("al", &album.id),
+        "songCount": album.song_list.len(),
+        "duration": duration.as_secs(),
+        "year": album.year(),
+        "genre": first.genre,
+    })
+}
+
+// Songs are called children in the Subsonic API
+async fn child(song: &Song) -> Value {
+    let path = song.get_path();
+    let album_id = subsonic_id("al", &song.album_key());
+    let size = tokio::fs::metadata(&path)
+        .await
+        .map(|metadata| metadata.len())
+        .ok();
+    let title = song.title.clone().unwrap_or_else(|| {
+        path.file_stem()
+            .map(|stem| stem.to_string_lossy().to_string())
+            .unwrap_or_default()
+    });
+    json!({
+        "id": subsonic_id("tr", &path.display().to_string()),
+        "parent": album_id,
+        "isDir": false,
+        "title": title,
+        "album": song.album,
+        "artist": song.artist,
+        "track": song.track,
+        "discNumber": song.disc,
+        "year": song.year,
+        "genre": song.genre,
+        "coverArt": album_id,
+        "size": size,
+        "contentType": content_type(&path),
+        "suffix": suffix(&path),
+        "duration": song.duration.as_secs(),
+        "bitRate": song.audio_bitrate,
+        "path": song_uri(&path).await,
+        "albumId": album_id,
+        "artistId": album_artist(song).map(|artist| subsonic_id("ar", &artist)),
+        "type": "music",
+    })
+}
+
+fn suffix(path: &Path) -> String {
+    path.extension()
+        .map(|ext| ext.to_string_lossy().to_lowercase())
+        .unwrap_or_default()
+}
+
+fn content_type(path: &Path) -> &'static str {
+    match suffix(path).as_str() {
+        "mp3" => "audio/mpeg",
+        "flac" => "audio/flac",
+        "ogg" | "oga" | "opus" => "audio/ogg",
+        "m4a" | "mp4" => "audio/mp4",
+        "aac" => "audio/aac",
+        "wav" => "audio/wav",
+        "jpg" | "jpeg" => "image/jpeg",
+        "png" => "image/png",
+        "webp" => "image/webp",
+        _ => "application/octet-stream",
+    }
+}
+
+fn envelope(body: Result<Value, ApiError>) -> Value {
+    let mut response = json!({
+        "status": "ok",
+        "version": API_VERSION,
+        "type": "bupl",
+        "serverVersion": env!("CARGO_PKG_VERSION"),
+        "openSubsonic": true,
+    });
+    match body {
+        Ok(Value::Object(fields)) => response.as_object_mut().unwrap().extend(fields),
+        Ok(_) => {}
+        Err(e) => {
+            response["status"] = json!("failed");
+            response["error"] = json!({ "code": e.code as u32, "message": e.message });
+        }
+    }
+    response
+}
+
+async fn write_body(
+    stream: &mut BufReader<TcpStream>,
+    response: Value,
+    json: bool,
+) -> Result<(), AppError> {
+    let response = without_nulls(response);
+    let (body, mime) = match json {
+        true => (
+            json!({ "subsonic-response": response }).to_string(),
+            "application/json",
+        ),
+        false => {
+            let mut xml = String::from(r#"<?xml version="1.0" encoding="UTF-8"?>"#);
+            let mut root = response;
+            root["xmlns"] = json!("http://subsonic.org/restapi");
+            write_xml(&mut xml, "subsonic-response", &root);
+            (xml, "text/xml; charset=utf-8")
+        }
+    };
+    let headers = [("Content-Type", mime)];
+    write_head(stream, "200 OK", &headers, Some(body.len() as u64)).await?;
+    stream.write_all(body.as_bytes()).await?;
+    Ok(())
+}
+
+fn without_nulls(value: Value) -> Value {
+    match value {
+        Value::Object(fields) => Value::Object(
+            fields
+                .into_iter()
+                .filter(|(_, value)| !value.is_null())
+                .map(|(key, value)| (key, without_nulls(value)))
+                .collect::<Map<String, Value>>(),
+        ),
+        Value::Array(items) => Value::Array(items.into_iter().map(without_nulls).collect()),
+        value => value,
+    }
+}
+
+// Plain values become attributes, objects and arrays of objects become child elements
+fn write_xml(xml: &mut String, name: &str, value: &Value) {
+    let Value::Object(fields) = value else {
+        return;
+    };
+    xml.push_str(&format!("<{}", name));
+    for (key, value) in fields {
+        let text = match value {
+            Value::String(text) => text.clone(),
+            Value::Bool(_) | Value::Number(_) => value.to_string(),
+            _ => continue,
+        };
+        xml.push_str(&format!(r#" {}="{}""#, key, escape_xml(&text)));
+    }
+    xml.push('>');
+    for (key, value) in fields {
+        match value {
+            Value::Object(_) => write_xml(xml, key, value),
+            Value::Array(items) => items.iter().for_each(|item| write_xml(xml, key, item)),
+            _ => {}
+        }
+    }
+    xml.push_str(&format!("</{}>", name));
+}
+
+fn escape_xml(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+        .replace('\'', "&apos;")
+}
+
+async fn write_head(
+    stream: &mut BufReader<TcpStream>,
+    status: &str,
+    headers: &[(&str, &str)],
+    length: Option<u64>,
+) -> Result<(), AppError> {
+    let mut head = format!("HTTP/1.1 {}\r\nConnection: close\r\n", status);
+    for (name, value) in headers {
+        head.push_str(&format!("{}: {}\r\n", name, value));
+    }
+    if let Some(length) = length {
+        head.push_str(&format!("Content-Length: {}\r\n", length));
+    }
+    head.push_str("\r\n");
+    stream.write_all(head.as_bytes()).await?;
+    Ok(())
+}
+
+async fn send_file(
+    stream: &mut BufReader<TcpStream>,
+    path: &Path,
+    request: &Request,
+    head_only: bool,
+) -> Result<(), AppError> {
+    let mut file = File::open(path).await?;
+    let size = file.metadata().await?.len();
+    let mime = content_type(path);
+
+    let (status, start, end) = match request.range.as_deref().map(|r| byte_range(r, size)) {
+        None => ("200 OK", 0, size.saturating_sub(1)),
+        Some(Some((start, end))) => ("206 Partial Content", start, end),
+        Some(None) => {
+            let content_range = format!("bytes */{}", size);
+            let headers = [("Content-Range", content_range.as_str())];
+            return write_head(stream, "416 Range Not Satisfiable", &headers, Some(0)).await;
+        }
+    };
+    let length = match size {
+        0 => 0,
+        _ => end - start + 1,
+    };
+    let content_range = format!("bytes {}-{}/{}", start, end, size);
+    let mut headers = vec![("Content-Type", mime), ("Accept-Ranges", "bytes")];
+    if status.starts_with("206") {
+        headers.push(("Content-Range", content_range.as_str()));
+    }
+    write_head(stream, status, &headers, Some(length)).await?;
+    if head_only {
+        return Ok(());
+    }
+    file.seek(std::io::SeekFrom::Start(start)).await?;
+    io::copy(&mut file.take(length), stream).await?;
+    Ok(())
+}
+
+// `bytes=start-end`, `bytes=start-` or `bytes=-suffix`, only the first range is served
+fn byte_range(header: &str, size: u64) -> Option<(u64, u64)> {
+    let range = header.strip_prefix("bytes=")?.split(',').next()?.trim();
+    let (start, end) = range.split_once('-')?;
+    let last = size.checked_sub(1)?;
+    let (start, end) = match (start.parse::<u64>().ok(), end.parse::<u64>().ok()) {
+        (Some(start), Some(end)) => (start, end.min(last)),
+        (Some(start), None) => (start, last),
+        (None, Some(suffix)) if suffix > 0 => (size.saturating_sub(suffix), last),
+        _ => return None,
+    };
+    (start <= end).then_some((start, end))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn settings() -> SubsonicSettings {
+        SubsonicSettings {
+            username: String::from("me"),
+            password: Some(String::from("sesame")),
+            ..SubsonicSettings::default()
+        }
+    }
+
+    fn request(query: &str) -> Request {
+        Request {
+            method: String::from("GET"),
+            endpoint: String::from("/rest/ping"),
+            params: parse_query(query),
+            range: None,
+        }
+    }
+
+    fn code(query: &str) -> Option<u32> {
+        authenticate(&request(query), &settings())
+            .err()
+            .map(|e| e.code as u32)
+    }
+
+    #[test]
+    fn accepts_the_password_plain_or_hex() {
+        assert_eq!(code("u=me&p=sesame"), None);
+        assert_eq!(code("u=me&p=enc:736573616d65"), None);
+        assert_eq!(code("u=me&p=enc:736573616D65"), None);
+        assert_eq!(code("u=me&p=sesam"), Some(40));
+        assert_eq!(code("u=me&p=sesame2"), Some(40));
+        assert_eq!(code("u=me&p=enc:736573616d"), Some(40));
+        assert_eq!(code("u=me&p=enc:zz"), Some(40));
+        assert_eq!(code("u=you&p=sesame"), Some(40));
+        assert_eq!(code("p=sesame"), Some(10));
+        assert_eq!(code("u=me"), Some(10));
+    }
+
+    #[test]
+    fn accepts_the_salted_token() {
+        let token = format!("{:x}", md5::compute("sesamec19b2d"));
+        assert_eq!(code(&format!("u=me&t={}&s=c19b2d", token)), None);
+        let upper = token.to_uppercase();
+        assert_eq!(code(&format!("u=me&t={}&s=c19b2d", upper)), None);
+        // The token only holds for the salt it was made with
+        assert_eq!(code(&format!("u=me&t={}&s=c19b2e", token)), Some(40));
+        assert_eq!(code(&format!("u=me&t={}&s=", token)), Some(40));
+        assert_eq!(code(&format!("u=me&t={}&s=c19b2d", &token[1..])), Some(40));
+        assert_eq!(code(&format!("u=you&t={}&s=c19b2d", token)), Some(40));
+        // Without a salt the token is ignored and the password is needed
+        assert_eq!(code(&format!("u=me&t={}", token)), Some(10));
+        assert_eq!(code(&format!("u=me&t={}&p=sesame", token)), None);
+    }
+
+    #[test]
+    fn decodes_hex() {
+        assert_eq!(decode_hex("736573616d65").unwrap(), b"sesame");
+        assert_eq!(decode_hex("00FFa0").unwrap(), [0, 255, 160]);
+        assert_eq!(decode_hex("").unwrap(), b"");
+        assert_eq!(decode_hex("abc"), None);
+        assert_eq!(decode_hex("zz"), None);
+        assert_eq!(decode_hex("+1"), None);
+        assert_eq!(decode_hex("é0"), None);
+    }
+
+    #[test]
+    fn serves_the_first_satisfiable_range() {
+        assert_eq!(byte_range("bytes=0-49", 100), Some((0, 49)));
+        assert_eq!(byte_range("bytes=0-0", 100), Some((0, 0)));
+        assert_eq!(byte_range("bytes=50-", 100), Some((50, 99)));
+        assert_eq!(byte_range("bytes=90-200", 100), Some((90, 99)));
+        assert_eq!(byte_range("bytes=99-", 100), Some((99, 99)));
+        assert_eq!(byte_range("bytes=-10", 100), Some((90, 99)));
+        assert_eq!(byte_range("bytes=-200", 100), Some((0, 99)));
+        assert_eq!(byte_range("bytes=0-9, 20-29", 100), Some((0, 9)));
+    }
+
+    // None is answered with 416 Range Not Satisfiable
+    #[test]
+    fn refuses_unsatisfiable_ranges() {
+        for header in [
+            "bytes=100-",
+            "bytes=100-150",
+            "bytes=50-10",
+            "bytes=-0",
+            "bytes=-",
+            "bytes=a-b",
+            "bytes=10",
+            "items=0-9",
+            "",
+        ] {
+            assert_eq!(byte_range(header, 100), None, "{}", header);
+        }
+        assert_eq!(byte_range("bytes=0-", 0), None);
+        assert_eq!(byte_range("bytes=-10", 0), None);
+    }
+
+    #[test]
+    fn parses_query_strings() {
+        assert_eq!(
+            parse_query("u=me&p=a%20b+c&&flag&s=&k%3Dy=1%2B1"),
+            [
+                ("u", "me"),
+                ("p", "a b c"),
+                ("flag", ""),
+                ("s", ""),
+                ("k=y", "1+1"),
+            ]
+            .map(|(key, value)| (key.to_string(), value.to_string()))
+        );
+        assert!(parse_query("").is_empty());
+        let ids = request("id=1&id=2&f=json");
+        assert_eq!(ids.params("id"), ["1", "2"]);
+        assert!(ids.json());
+    }
+
+    #[test]
+    fn escapes_xml_text() {
+        assert_eq!(
+            escape_xml(r#"<a & "b" 'c'>"#),
+            "&lt;a &amp; &quot;b&quot; &apos;c&apos;&gt;"
+        );
+        assert_eq!(escape_xml("&amp;"), "&amp;amp;");
+        assert_eq!(escape_xml("plain"), "plain");
+    }
+
+    #[test]
+    fn writes_values_as_attributes_and_children() {
+        let value = json!({
+            "status": "ok",
+            "count": 2,
+            "starred": true,
+            "empty": null,
+            "album": { "name": "Rock & \"Roll\"" },
+            "song": [{ "id": "1" }, { "id": "2" }],
+            "tags": ["ignored"],
+        });
+        let mut xml = String::new();
+        write_xml(&mut xml, "response", &value);
+        assert_eq!(
+            xml,
+            concat!(
+                r#"<response count="2" starred="true" status="ok">"#,
+                r#"<album name="Rock &amp; &quot;Roll&quot;"></album>"#,
+                r#"<song id="1"></song><song id="2"></song>"#,
+                "</response>"
+            )
+        );
+
+        let mut xml = String::new();
+        write_xml(&mut xml, "response", &json!("text"));
+        assert!(xml.is_empty());
+    }
+}
@@ -0,0 +1,235 @@
+use serde::{Deserialize, Serialize};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::Duration;
+use tauri::menu::{CheckMenuItemBuilder, Menu, MenuBuilder, MenuEvent, SubmenuBuilder};
+use tauri::tray::{MouseButton, MouseButtonState, TrayIcon, TrayIconBuilder, TrayIconEvent};
+use tauri::{AppHandle, Manager, Runtime, Window, WindowEvent};
+use tokio::sync::broadcast::error::RecvError;
+
+use crate::config::CONFIG;
+use crate::control::{dispatch, player_status, ControlRequest, PlayerStatus};
+use crate::player::PLAYER_EVENTS;
+use crate::stats::HistoryEntry;
+use crate::APP_NAME;
+
+const RECENT_ALBUMS: usize = 10;
+const ALBUM_PREFIX: &str = "album:";
+
+// Events only come from this process' player, a daemon is polled
+const REFRESH_INTERVAL: Duration = Duration::from_secs(2);
+
+// Read on the main thread when the window closes, so it is kept outside CONFIG
+static CLOSE_TO_TRAY: AtomicBool = AtomicBool::new(false);
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct TraySettings {
+    // Closing the window hides it and keeps playing, Quit in the tray menu exits
+    pub close_to_tray: bool,
+}
+
+// What the menu and tooltip show, they are only rebuilt when it changes
+#[derive(Debug, Default, PartialEq)]
+struct TrayState {
+    tooltip: String,
+    playing: bool,
+    repeat: bool,
+    shuffle: bool,
+    // Album keys and labels
+    albums: Vec<(String, String)>,
+}
+
+pub fn apply_tray_settings(settings: &TraySettings) {
+    CLOSE_TO_TRAY.store(settings.close_to_tray, Ordering::Relaxed);
+}
+
+pub fn create_tray<R: Runtime>(app: &AppHandle<R>) -> tauri::Result<()> {
+    apply_tray_settings(&tauri::async_runtime::block_on(async {
+        CONFIG.lock().await.tray.clone()
+    }));
+    let mut tray = TrayIconBuilder::with_id("main")
+        .tooltip(APP_NAME.as_str())
+        .menu(&build_menu(app, &TrayState::default())?)
+        .menu_on_left_click(false)
+        .on_menu_event(handle_menu_event)
+        .on_tray_icon_event(|tray, event| {
+            if let TrayIconEvent::Click {
+                button: MouseButton::Left,
+                button_state: MouseButtonState::Up,
+                ..
+            } = event
+            {
+                show_window(tray.app_handle());
+            }
+        });
+    if let Some(icon) = app.default_window_icon() {
+        tray = tray.icon(icon.clone());
+    }
+    tauri::async_runtime::spawn(follow_player(tray.build(app)?));
+    Ok(())
+}
+
+// Hides the window instead of closing it when set to keep playing in the tray
+pub fn handle_window_event<R: Runtime>(window: &Window<R>, event: &WindowEvent) {
+    let WindowEvent::CloseRequested { api, .. } = event else {
+        return;
+    };
+    if CLOSE_TO_TRAY.load(Ordering::Relaxed) {
+        api.prevent_close();
+        let _ = window.hide();
+    }
+}
+
+async fn follow_player<R: Runtime>(tray: TrayIcon<R>) {
+    let mut events = PLAYER_EVENTS.subscribe();
+    let mut ticks = tokio::time::interval(REFRESH_INTERVAL);
+    let mut shown = TrayState::default();
+    let mut albums_for = None;
+    let mut albums = vec![];
+    loop {
+        tokio::select! {
+            event = events.recv() => if let Err(RecvError::Closed) = event {
+                return;
+            },
+            _ = ticks.tick() => {}
+        }
+
+        let status = player_status().await;
+        // The history only grows when a song ends
+        if albums_for != Some(status.path.clone()) {
+            albums = recent_albums(&status).await;
+            albums_for = Some(status.path.clone());
+        }
+        let state = TrayState {
+            tooltip: tooltip(&status),
+            playing: status.state == "playing",
+            repeat: status.repeat,
+            shuffle: status.shuffle,
+            albums: albums.clone(),
+        };
+        if state == shown {
+            continue;
+        }
+
+        let menu = match build_menu(tray.app_handle(), &state) {
+            Ok(menu) => menu,
+            Err(e) => {
+                println!("Could not build the tray menu: {}", e);
+                continue;
+            }
+        };
+        let _ = tray.set_menu(Some(menu));
+        let _ = tray.set_tooltip(Some(&state.tooltip));
+        shown = state;
+    }
+}
+
+fn build_menu<R: Runtime>(app: &AppHandle<R>, state: &TrayState) -> tauri::Result<Menu<R>> {
+    let repeat = CheckMenuItemBuilder::with_id("repeat", "Repeat")
+        .checked(state.repeat)
+        .build(app)?;
+    let shuffle = CheckMenuItemBuilder::with_id("shuffle", "Shuffle")
+        .checked(state.shuffle)
+        .build(app)?;
+    let mut albums = SubmenuBuilder::new(app, "Recent albums").enabled(!state.albums.is_empty());
+    for (key, label) in &state.albums {
+        // A single & marks a mnemonic on Windows
+        albums = albums.text(format!("{}{}", ALBUM_PREFIX, key), label.replace('&', "&&"));
+    }
+
+    MenuBuilder::new(app)
+        .text("play_pause", if state.playing { "Pause" } else { "Play" })
+        .text("next", "Next")
+        .text("prev", "Previous")
+        .separator()
+        .item(&repeat)
+        .item(&shuffle)
+        .separator()
+        .item(&albums.build()?)
+        .separator()
+        .text("show", format!("Show {}", APP_NAME.as_str()))
+        .text("quit", "Quit")
+        .build()
+}
+
+fn handle_menu_event<R: Runtime>(app: &AppHandle<R>, event: MenuEvent) {
+    let id = event.id().as_ref();
+    let request = match id {
+        "play_pause" => ControlRequest::PlayPause,
+        "next" => ControlRequest::Next,
+        "prev" => ControlRequest::Prev,
+        "repeat" => ControlRequest::ToggleRepeat,
+        "shuffle" => ControlRequest::ToggleShuffle,
+        "show" => return show_window(app),
+        "quit" => return app.exit(0),
+        _ => match id.strip_prefix(ALBUM_PREFIX) {
+            Some(key) => ControlRequest::PlayAlbum {
+                name: key.to_string(),
+            },
+            None => return,
+        },
+    };
+    tauri::async_runtime::spawn(async move {
+        if let Err(e) = dispatch(request).await {
+            println!("Tray action failed: {}", e);
+        }
+    });
+}
+
+fn show_window<R: Runtime>(app: &AppHandle<R>) {
+    if let Some(window) = app.get_webview_window("main") {
+        let _ = window.unminimize();
+        let _ = window.show();
+        let _ = window.set_focus();
+    }
+}
+
+fn tooltip(status: &PlayerStatus) -> String {
+    match (&status.title, &status.artist) {
+        _ if status.state == "stopped" => APP_NAME.to_string(),
+        (Some(title), Some(artist)) => format!("{} - {}", title, artist),
+        (Some(title), None) => title.clone(),
+        (None, _) => APP_NAME.to_string(),
+    }
+}
+
+// The playing album first, then the albums of the latest plays
+async fn recent_albums(status: &PlayerStatus) -> Vec<(String, String)> {
+    let playing = status.album.clone().map(|album| {
+        let artist = status.album_artist.clone().or(status.artist.clone());
+        (album, artist)
+    });
+    // The whole history file is read, so not on the runtime's threads
+    let history = tokio::task::spawn_blocking(|| HistoryEntry::load(0, u64::MAX))
+        .await
+        .ok()
+        .and_then(Result::ok)
+        .unwrap_or_default();
+    let played = history
+        .into_iter()
+        .rev()
+        .filter_map(|entry| Some((entry.album?, entry.album_artist)));
+
+    let mut albums: Vec<(String, String)> = vec![];
+    for (album, artist) in playing.into_iter().chain(played) {
+        if album.is_empty() {
+            continue;
+        }
+        // Same as Song::album_key, which PlayAlbum looks up
+        let (key, label) = match artist {
+            Some(artist) => (
+                format!("{} - {}", artist, album),
+                format!("{} - {}", album, artist),
+            ),
+            None => (album.clone(), album),
+        };
+        if albums.iter().any(|(other, _)| *other == key) {
+            continue;
+        }
+        albums.push((key, label));
+        if albums.len() == RECENT_ALBUMS {
+            break;
+        }
+    }
+    albums
+}
@@ -0,0 +1,207 @@
+use serde::{Deserialize, Serialize};
+use std::fs::{self, read_to_string};
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+
+use crate::config::app_config_path;
+use crate::error::AppError;
+use crate::library::unix_time;
+use crate::playlist::Playlist;
+use crate::song::Song;
+
+// Enough of a song to find it again after the file is moved or renamed
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SongRef {
+    pub path: PathBuf,
+    pub title: Option<String>,
+    pub artist: Option<String>,
+    pub album: Option<String>,
+    pub duration: u64,
+    pub musicbrainz_recording: Option<String>,
+    #[serde(default)]
+    pub missing: bool,
+}
+
+impl SongRef {
+    pub fn from_song(song: &Song) -> Self {
+        Self {
+            path: song.get_path(),
+            title: song.title.clone(),
+            artist: song.artist.clone(),
+            album: song.album.clone(),
+            duration: song.duration.as_secs(),
+            musicbrainz_recording: song.musicbrainz.recording.clone(),
+            missing: false,
+        }
+    }
+
+    fn matches(&self, song: &Song) -> bool {
+        if self.musicbrainz_recording.is_some() {
+            return self.musicbrainz_recording == song.musicbrainz.recording;
+        }
+        let same = |a: &Option<String>, b: &Option<String>| match (a, b) {
+            (Some(a), Some(b)) => a.to_lowercase() == b.to_lowercase(),
+            _ => false,
+        };
+        same(&self.title, &song.title)
+            && same(&self.artist, &song.artist)
+            && (same(&self.album, &song.album)
+                || self.duration.abs_diff(song.duration.as_secs()) <= 2)
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct UserPlaylist {
+    pub id: String,
+    pub name: String,
+    pub cover_path: Option<PathBuf>,
+    pub created: u64,
+    pub modified: u64,
+    pub songs: Vec<SongRef>,
+}
+
+impl UserPlaylist {
+    pub fn new(name: String) -> Self {
+        let now = unix_time(SystemTime::now());
+        Self {
+            id: format!("{:x}{:08x}", now, rand::random::<u32>()),
+            name,
+            cover_path: None,
+            created: now,
+            modified: now,
+            songs: vec![],
+        }
+    }
+
+    pub fn load(id: &str) -> Result<Self, AppError> {
+        let playlist = read_to_string(playlist_file(id)?)
+            .map_err(|_| AppError::PlaylistNotFound(id.to_string()))?;
+        Ok(serde_json::from_str(&playlist)?)
+    }
+
+    pub fn load_all() -> Result<Vec<Self>, AppError> {
+        let mut playlists = vec![];
+        let Ok(entries) = fs::read_dir(playlists_path()?) else {
+            return Ok(playlists);
+        };
+
+        for entry in entries.filter_map(|e| e.ok()) {
+            let path = entry.path();
+            if path.extension().is_some_and(|ext| ext == "json") {
+                match read_to_string(&path).map(|p| serde_json::from_str::<Self>(&p)) {
+                    Ok(Ok(playlist)) => playlists.push(playlist),
+                    _ => println!("Could not read playlist {}", path.display()),
+                }
+            }
+        }
+        playlists.sort_by_key(|playlist| playlist.created);
+        Ok(playlists)
+    }
+
+    pub fn save(&mut self) -> Result<(), AppError> {
+        self.modified = unix_time(SystemTime::now());
+        fs::create_dir_all(playlists_path()?)?;
+        fs::write(
+            playlist_file(&self.id)?,
+            serde_json::to_string_pretty(self)?,
+        )?;
+        Ok(())
+    }
+
+    pub fn delete(&self) -> Result<(), AppError> {
+        fs::remove_file(playlist_file(&self.id)?)?;
+        if let Some(cover_path) = &self.cover_path {
+            let _ = fs::remove_file(cover_path);
+        }
+        Ok(())
+    }
+
+    pub fn duplicate(&self) -> Result<Self, AppError> {
+        let mut copy = Self::new(format!("{} (copy)", self.name));
+        copy.songs = self.songs.clone();
+        if let Some(cover_path) = &self.cover_path {
+            copy.set_cover(Some(cover_path))?;
+        }
+        Ok(copy)
+    }
+
+    // Copies the image next to the playlist so it stays readable by the webview
+    pub fn set_cover(&mut self, image: Option<&Path>) -> Result<(), AppError> {
+        if let Some(old_cover) = self.cover_path.take() {
+            if Some(old_cover.as_path()) != image {
+                let _ = fs::remove_file(old_cover);
+            }
+        }
+        let Some(image) = image else {
+            return Ok(());
+        };
+
+        let extension = image.extension().unwrap_or_default().to_string_lossy();
+        let cover_path = playlists_path()?.join(format!("{}.{}", self.id, extension));
+        if image != cover_path {
+            fs::create_dir_all(playlists_path()?)?;
+            fs::copy(image, &cover_path)?;
+        }
+        self.cover_path = Some(cover_path);
+        Ok(())
+    }
+
+    pub fn move_song(&mut self, from: usize, to: usize) -> Result<(), AppError> {
+        if from >= self.songs.len() || to >= self.songs.len() {
+            return Err(AppError::OutOfRange(from.max(to)));
+        }
+        let song = self.songs.remove(from);
+        self.songs.insert(to, song);
+        Ok(())
+    }
+
+    // Songs flagged as missing count too, their files may be back
+    pub fn has_moved_songs(&self) -> bool {
+        self.songs
+            .iter()
+            .any(|song| song.missing || !song.path.exists())
+    }
+
+    // Points references at moved files and flags the ones that can't be found
+    pub fn resolve(&mut self, library: &[Song]) {
+        for song_ref in self.songs.iter_mut() {
+            if song_ref.path.exists() {
+                song_ref.missing = false;
+                continue;
+            }
+            match library.iter().find(|song| song_ref.matches(song)) {
+                Some(song) => {
+                    println!(
+                        "Playlist song moved: {} -> {}",
+                        song_ref.path.display(),
+                        song.get_path().display()
+                    );
+                    song_ref.path = song.get_path();
+                    song_ref.missing = false;
+                }
+                None => song_ref.missing = true,
+            }
+        }
+    }
+
+    pub fn to_playlist(&self, song_list: Vec<Song>) -> Playlist {
+        let mut playlist = Playlist::new_playlist_from(&self.id, song_list);
+        playlist.name = self.name.clone();
+        if let Some(cover_path) = &self.cover_path {
+            playlist.cover_path = cover_path.clone();
+        }
+        playlist
+    }
+}
+
+fn playlists_path() -> Result<PathBuf, AppError> {
+    Ok(PathBuf::from(app_config_path()?).join("playlists"))
+}
+
+fn playlist_file(id: &str) -> Result<PathBuf, AppError> {
+    // IDs come from the frontend, so never let them escape the playlists folder
+    if id.is_empty() || !id.chars().all(|c| c.is_ascii_alphanumeric()) {
+        return Err(AppError::PlaylistNotFound(id.to_string()));
+    }
+    Ok(playlists_path()?.join(format!("{}.json", id)))
+}
@@ -1,6 +1,11 @@
 import { invoke } from "@tauri-apps/api/core";
 import { appConfigDir } from "@tauri-apps/api/path";
 
+// Shape of the errors rejected by fallible commands
+export type AppError = {
+    code: string;
+    message: string;
+};
 
 cachePlaylistTypes();
 
@@ -40,6 +45,10 @@ export async function playerSongFinished(): Promise<boolean> {
     return await invoke("player_song_finished");
 }
 
+export async function playerQueueIndex(): Promise<number> {
+    return await invoke("player_queue_index");
+}
+
 export async function playerRepeat(): Promise<boolean> {
     return await invoke("player_repeat");
 }
@@ -82,6 +91,389 @@ async function cachePlaylistTypes() {
     await invoke("create_playlist_types");
 }
 
-export async function fetchAlbumCover(title:string, album: string) {
-    await invoke("fetch_album_cover", {title, album });
-}
\ No newline at end of file
+export async function getScanReport(): Promise<any> {
+    return await invoke("get_scan_report");
+}
+
+export async function fetchAlbumCover(title:string, album: string, artist?: string) {
+    await invoke("fetch_album_cover", {title, album, artist });
+}
+export async function getPathPatterns(): Promise<string[]> {
+    return await invoke("get_path_patterns");
+}
+
+export async function setPathPatterns(patterns: string[]) {
+    await invoke("set_path_patterns", { patterns });
+}
+
+export async function previewPathPattern(pattern: string, limit?: number): Promise<any> {
+    return await invoke("preview_path_pattern", { pattern, limit });
+}
+
+export async function getAlbumTrackIssues(): Promise<any> {
+    return await invoke("get_album_track_issues");
+}
+
+export async function getArtists(): Promise<any> {
+    return await invoke("get_artists");
+}
+
+export async function getArtistAlbums(artist: string): Promise<any> {
+    return await invoke("get_artist_albums", { artist });
+}
+
+export async function getArtistPlaylist(artist: string): Promise<any> {
+    return await invoke("get_artist_playlist", { artist });
+}
+
+export async function playArtistPlaylist(artist: string) {
+    await invoke("play_artist_playlist", { artist });
+}
+
+export async function getGenres(): Promise<any> {
+    return await invoke("get_genres");
+}
+
+export async function getGenrePlaylist(genre: string): Promise<any> {
+    return await invoke("get_genre_playlist", { genre });
+}
+
+export async function playGenrePlaylist(genre: string) {
+    await invoke("play_genre_playlist", { genre });
+}
+
+export async function getLibraryRoots(): Promise<string[]> {
+    return await invoke("get_library_roots");
+}
+
+export async function setLibraryRoots(roots: string[]) {
+    await invoke("set_library_roots", { roots });
+}
+
+export async function getFolder(path?: string, depth?: number): Promise<any> {
+    return await invoke("get_folder", { path, depth });
+}
+
+export async function getFolderPlaylist(path: string, recursive?: boolean): Promise<any> {
+    return await invoke("get_folder_playlist", { path, recursive });
+}
+
+export async function playFolder(path: string, recursive?: boolean) {
+    await invoke("play_folder", { path, recursive });
+}
+
+export async function enqueueFolder(path: string, recursive?: boolean) {
+    await invoke("enqueue_folder", { path, recursive });
+}
+
+export async function getUserPlaylists(): Promise<any> {
+    return await invoke("get_user_playlists");
+}
+
+export async function getUserPlaylist(id: string): Promise<any> {
+    return await invoke("get_user_playlist", { id });
+}
+
+export async function createUserPlaylist(name: string): Promise<any> {
+    return await invoke("create_user_playlist", { name });
+}
+
+export async function renameUserPlaylist(id: string, name: string): Promise<any> {
+    return await invoke("rename_user_playlist", { id, name });
+}
+
+export async function deleteUserPlaylist(id: string) {
+    await invoke("delete_user_playlist", { id });
+}
+
+export async function duplicateUserPlaylist(id: string): Promise<any> {
+    return await invoke("duplicate_user_playlist", { id });
+}
+
+export async function addToUserPlaylist(id: string, paths: string[]): Promise<any> {
+    return await invoke("add_to_user_playlist", { id, paths });
+}
+
+export async function removeFromUserPlaylist(id: string, index: number): Promise<any> {
+    return await invoke("remove_from_user_playlist", { id, index });
+}
+
+export async function moveInUserPlaylist(id: string, from: number, to: number): Promise<any> {
+    return await invoke("move_in_user_playlist", { id, from, to });
+}
+
+export async function setUserPlaylistCover(id: string, coverPath?: string): Promise<any> {
+    return await invoke("set_user_playlist_cover", { id, coverPath });
+}
+
+export async function playUserPlaylist(id: string) {
+    await invoke("play_user_playlist", { id });
+}
+
+// A playlist the backend can build, e.g. { kind: "album", id: "Artist - Album" } or { kind: "queue" }
+export type PlaylistSource = {
+    kind: "album" | "artist" | "genre" | "folder" | "user" | "smart" | "queue";
+    id?: string;
+};
+
+export async function importPlaylistFile(path: string): Promise<any> {
+    return await invoke("import_playlist_file", { path });
+}
+
+// Returns where it was written, .m3u is exported as .m3u8
+export async function exportPlaylistFile(source: PlaylistSource, path: string, relative?: boolean): Promise<string> {
+    return await invoke("export_playlist_file", { source, path, relative });
+}
+
+// Rules such as `genre = "Jazz" AND year < 1970` or `added in last 30 days`
+export type SmartPlaylist = {
+    id: string;
+    name: string;
+    query: string;
+    sort_by?: string;
+    descending: boolean;
+    limit?: number;
+};
+
+export async function getSmartPlaylists(): Promise<SmartPlaylist[]> {
+    return await invoke("get_smart_playlists");
+}
+
+export async function saveSmartPlaylist(
+    name: string,
+    query: string,
+    options: { id?: string; sortBy?: string; descending?: boolean; limit?: number } = {}
+): Promise<SmartPlaylist> {
+    return await invoke("save_smart_playlist", { name, query, ...options });
+}
+
+export async function deleteSmartPlaylist(id: string) {
+    await invoke("delete_smart_playlist", { id });
+}
+
+export async function previewSmartPlaylist(
+    query: string,
+    options: { sortBy?: string; descending?: boolean; limit?: number } = {}
+): Promise<any> {
+    return await invoke("preview_smart_playlist", { query, ...options });
+}
+
+export async function getSmartPlaylist(id: string): Promise<any> {
+    return await invoke("get_smart_playlist", { id });
+}
+
+export async function playSmartPlaylist(id: string) {
+    await invoke("play_smart_playlist", { id });
+}
+
+// Fuzzy search over the library, e.g. `artist:radiohead year:1997`
+export type SearchResults = {
+    songs: any[];
+    albums: { id: string; name: string; artist: string; year?: number; cover_path: string; song_count: number }[];
+    artists: { name: string; song_count: number }[];
+};
+
+export async function librarySearch(query: string, limit?: number): Promise<SearchResults> {
+    return await invoke("library_search", { query, limit });
+}
+
+export type SongStats = {
+    date_added: number;
+    play_count: number;
+    skip_count: number;
+    last_played?: number;
+    rating?: number;
+};
+
+export async function getSongStats(path: string): Promise<SongStats> {
+    return await invoke("get_song_stats", { path });
+}
+
+// 0 to 5 stars, leave out the rating to clear it
+export async function setSongRating(path: string, rating?: number): Promise<SongStats> {
+    return await invoke("set_song_rating", { path, rating });
+}
+
+export type RankedItem = { name: string; artist?: string; plays: number; listening_time: number };
+
+export type ListeningReport = {
+    from: number;
+    to: number;
+    plays: number;
+    listening_time: number;
+    top_songs: RankedItem[];
+    top_artists: RankedItem[];
+    top_albums: RankedItem[];
+    top_genres: RankedItem[];
+    by_hour: number[][];
+    longest_streak: number;
+    current_streak: number;
+};
+
+// Timestamps are unix seconds, leave them out for all of the history
+export async function getListeningReport(from?: number, to?: number, limit?: number): Promise<ListeningReport> {
+    return await invoke("get_listening_report", { from, to, limit });
+}
+
+export async function getYearInReview(year: number, limit?: number): Promise<ListeningReport> {
+    return await invoke("get_year_in_review", { year, limit });
+}
+
+// Writes the report to a .json file, or every play to a .csv file
+export async function exportListeningReport(path: string, from?: number, to?: number) {
+    await invoke("export_listening_report", { path, from, to });
+}
+
+export async function getScrobblerLogPath(): Promise<string> {
+    return await invoke("get_scrobbler_log_path");
+}
+
+export async function importScrobblerLog(path: string): Promise<{ imported: number; matched: number; skipped: number }> {
+    return await invoke("import_scrobbler_log", { path });
+}
+
+export type ScrobbleSettings = {
+    listenbrainz: { enabled: boolean; token: string; base_url: string };
+    lastfm: {
+        enabled: boolean;
+        api_key: string;
+        api_secret: string;
+        session_key?: string;
+        username?: string;
+        base_url: string;
+        auth_url: string;
+    };
+};
+
+export async function getScrobbleSettings(): Promise<ScrobbleSettings> {
+    return await invoke("get_scrobble_settings");
+}
+
+export async function setScrobbleSettings(settings: ScrobbleSettings) {
+    await invoke("set_scrobble_settings", { settings });
+}
+
+export async function getScrobbleQueue(): Promise<any[]> {
+    return await invoke("get_scrobble_queue");
+}
+
+export async function flushScrobbleQueue(): Promise<number> {
+    return await invoke("flush_scrobble_queue");
+}
+
+// Open the returned url so the user can approve bupl, then finish with the token
+export async function lastfmBeginAuth(): Promise<{ token: string; url: string }> {
+    return await invoke("lastfm_begin_auth");
+}
+
+export async function lastfmFinishAuth(token: string): Promise<string> {
+    return await invoke("lastfm_finish_auth", { token });
+}
+
+export type MpdSettings = {
+    enabled: boolean;
+    address: string;
+    password?: string;
+};
+
+export async function getMpdSettings(): Promise<MpdSettings> {
+    return await invoke("get_mpd_settings");
+}
+
+// Takes effect the next time bupl starts
+export async function setMpdSettings(settings: MpdSettings) {
+    await invoke("set_mpd_settings", { settings });
+}
+
+export type SubsonicSettings = {
+    enabled: boolean;
+    address: string;
+    username: string;
+    password?: string;
+    ffmpeg: string;
+};
+
+export async function getSubsonicSettings(): Promise<SubsonicSettings> {
+    return await invoke("get_subsonic_settings");
+}
+
+// Takes effect the next time bupl starts
+export async function setSubsonicSettings(settings: SubsonicSettings) {
+    await invoke("set_subsonic_settings", { settings });
+}
+
+export type RemoteSettings = {
+    enabled: boolean;
+    address: string;
+    token: string;
+};
+
+export async function getRemoteSettings(): Promise<RemoteSettings> {
+    return await invoke("get_remote_settings");
+}
+
+// Takes effect the next time bupl starts, except for the token
+export async function setRemoteSettings(settings: RemoteSettings) {
+    await invoke("set_remote_settings", { settings });
+}
+
+// Unpairs every remote and returns the new token
+export async function resetRemoteToken(): Promise<string> {
+    return await invoke("reset_remote_token");
+}
+
+export type ShortcutAction = "play_pause" | "next" | "prev" | "volume_up" | "volume_down";
+
+// Keys like "CommandOrControl+Alt+Up" or "MediaPlayPause"
+export type ShortcutSettings = {
+    enabled: boolean;
+    bindings: Partial<Record<ShortcutAction, string[]>>;
+};
+
+export type ShortcutConflict = {
+    action: ShortcutAction;
+    shortcut: string;
+    reason: string;
+};
+
+export async function getShortcutSettings(): Promise<ShortcutSettings> {
+    return await invoke("get_shortcut_settings");
+}
+
+// Returns the bindings that could not be registered
+export async function setShortcutSettings(settings: ShortcutSettings): Promise<ShortcutConflict[]> {
+    return await invoke("set_shortcut_settings", { settings });
+}
+
+export async function getShortcutConflicts(): Promise<ShortcutConflict[]> {
+    return await invoke("get_shortcut_conflicts");
+}
+
+export type NotificationSettings = {
+    enabled: boolean;
+    // Seconds, 0 leaves it to the notification server
+    timeout: number;
+    suppress_when_focused: boolean;
+    actions: boolean;
+};
+
+export async function getNotificationSettings(): Promise<NotificationSettings> {
+    return await invoke("get_notification_settings");
+}
+
+export async function setNotificationSettings(settings: NotificationSettings): Promise<void> {
+    return await invoke("set_notification_settings", { settings });
+}
+
+export type TraySettings = {
+    // Closing the window keeps playing in the tray
+    close_to_tray: boolean;
+};
+
+export async function getTraySettings(): Promise<TraySettings> {
+    return await invoke("get_tray_settings");
+}
+
+export async function setTraySettings(settings: TraySettings): Promise<void> {
+    return await invoke("set_tray_settings", { settings });
+}